@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use pem::Pem;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::keys::{load_private_key, write_secret};
+
+const KEY_FILE: &str = "account-key.pem";
+
+/// The ES256 key the ACME CA knows us by. It is kept, so restarts reuse the account
+/// instead of registering a new one each time.
+pub struct AccountKey {
+    key_pair: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl AccountKey {
+    /// Loads the key from `dir`, creating it on first use.
+    pub fn load_or_create(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = dir.join(KEY_FILE);
+        let rng = SystemRandom::new();
+
+        let private_key = if path.exists() {
+            load_private_key(&path, None)?
+        } else {
+            let private_key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| "failed to generate the ACME account key")?
+                .as_ref()
+                .to_vec();
+
+            fs::create_dir_all(dir)?;
+            write_secret(&path, pem::encode(&Pem::new("PRIVATE KEY", private_key.clone())).as_bytes())?;
+
+            private_key
+        };
+
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &private_key)
+            .map_err(|e| format!("{}: not a P-256 account key: {}", path.display(), e))?;
+
+        Ok(Self { key_pair, rng })
+    }
+
+    /// The public key as a JWK, members in the order RFC 7638 hashes them.
+    fn jwk(&self) -> Value {
+        // An uncompressed point: 0x04, then x and y.
+        let point = self.key_pair.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+
+    /// What a challenge response must contain to prove control of this account.
+    pub fn key_authorization(&self, token: &str) -> String {
+        let thumbprint = Sha256::digest(self.jwk().to_string().as_bytes());
+        format!("{}.{}", token, URL_SAFE_NO_PAD.encode(thumbprint))
+    }
+
+    /// A flattened JWS request body for `url`. Without `kid` the key itself is sent, as
+    /// registering an account requires; without `payload` it is a POST-as-GET.
+    pub fn sign(&self, url: &str, nonce: &str, kid: Option<&str>, payload: Option<&Value>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload.map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+        let signature = self
+            .key_pair
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| "failed to sign the ACME request")?;
+
+        Ok(serde_json::to_vec(&json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))?)
+    }
+}
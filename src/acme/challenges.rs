@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use rcgen::{Certificate, CertificateParams, CustomExtension, KeyPair, SanType, PKCS_ECDSA_P256_SHA256};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+use crate::crypto::sni::certified_key;
+
+/// The ALPN protocol an ACME server offers when validating TLS-ALPN-01.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Where an ACME server fetches HTTP-01 key authorizations from.
+pub const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeType {
+    /// Answered on a plain HTTP listener, which the CA reaches on port 80.
+    Http01,
+    /// Answered during the TLS handshake, which the CA reaches on port 443.
+    TlsAlpn01,
+}
+
+impl ChallengeType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+impl FromStr for ChallengeType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "http-01" => Ok(ChallengeType::Http01),
+            "tls-alpn-01" => Ok(ChallengeType::TlsAlpn01),
+            _ => Err(format!("unknown challenge {}, expected http-01 or tls-alpn-01", value)),
+        }
+    }
+}
+
+impl fmt::Display for ChallengeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Proofs published while an order is pending, for the listeners to hand the CA.
+#[derive(Default)]
+pub struct Challenges {
+    /// Key authorizations by HTTP-01 token.
+    http: Mutex<HashMap<String, String>>,
+    /// Validation certificates by TLS-ALPN-01 domain.
+    tls_alpn: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl Challenges {
+    /// The key authorization to answer a request for `path` with, if it is a pending HTTP-01 token.
+    pub fn http_response(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(HTTP_CHALLENGE_PATH)?;
+        lock(&self.http).get(token).cloned()
+    }
+
+    pub fn publish(&self, challenge: ChallengeType, domain: &str, token: &str, key_authorization: &str) -> Result<(), Box<dyn Error>> {
+        match challenge {
+            ChallengeType::Http01 => {
+                lock(&self.http).insert(token.to_string(), key_authorization.to_string());
+            }
+            ChallengeType::TlsAlpn01 => {
+                let certified = validation_certificate(domain, key_authorization)?;
+                lock(&self.tls_alpn).insert(domain.to_ascii_lowercase(), certified);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn withdraw(&self, domain: &str, token: &str) {
+        lock(&self.http).remove(token);
+        lock(&self.tls_alpn).remove(&domain.to_ascii_lowercase());
+    }
+}
+
+/// The self-signed certificate RFC 8737 has the CA look for: the domain, and a critical
+/// acmeIdentifier extension holding the digest of the key authorization.
+fn validation_certificate(domain: &str, key_authorization: &str) -> Result<Arc<CertifiedKey>, Box<dyn Error>> {
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = vec![SanType::DnsName(domain.to_string())];
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(&Sha256::digest(key_authorization.as_bytes()))];
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?);
+
+    let certificate = Certificate::from_params(params)?;
+    certified_key(vec![certificate.serialize_der()?], certificate.serialize_private_key_der())
+}
+
+/// Serves the current certificate, which renewals swap without touching the listeners, and
+/// the validation certificate to CAs that handshake with `acme-tls/1`.
+pub struct AcmeResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+    challenges: Arc<Challenges>,
+}
+
+impl AcmeResolver {
+    pub fn new(current: Arc<CertifiedKey>, challenges: Arc<Challenges>) -> Self {
+        Self { current: RwLock::new(current), challenges }
+    }
+
+    pub fn replace(&self, certified: Arc<CertifiedKey>) {
+        *self.current.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = certified;
+    }
+}
+
+impl ResolvesServerCert for AcmeResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let validating = client_hello.alpn().is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if validating {
+            // No pending challenge for the name means no certificate, which fails the handshake.
+            let name = client_hello.server_name()?.to_ascii_lowercase();
+            return lock(&self.challenges.tls_alpn).get(&name).cloned();
+        }
+
+        Some(self.current.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::certificate::X509Certificate;
+    use x509_parser::prelude::FromDer;
+    use crate::crypto::certs::{certificate_subject_alt_names, SubjectAltName};
+
+    /// id-pe-acmeIdentifier from RFC 8737.
+    const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+    #[test]
+    fn http_response_answers_pending_tokens_only() {
+        let challenges = Challenges::default();
+        challenges.publish(ChallengeType::Http01, "example.test", "token1", "token1.thumbprint").unwrap();
+
+        assert_eq!(challenges.http_response("/.well-known/acme-challenge/token1").as_deref(), Some("token1.thumbprint"));
+        assert_eq!(challenges.http_response("/.well-known/acme-challenge/token2"), None);
+        assert_eq!(challenges.http_response("/token1"), None);
+
+        challenges.withdraw("example.test", "token1");
+        assert_eq!(challenges.http_response("/.well-known/acme-challenge/token1"), None);
+    }
+
+    #[test]
+    fn validation_certificate_carries_the_key_authorization_digest() {
+        let certified = validation_certificate("example.test", "token1.thumbprint").unwrap();
+        let der = &certified.cert[0].0;
+
+        assert!(certificate_subject_alt_names(der).unwrap() == vec![SubjectAltName::Dns("example.test".to_string())]);
+
+        let (_, cert) = X509Certificate::from_der(der).unwrap();
+        let extension = cert.extensions().iter().find(|extension| extension.oid.to_id_string() == ACME_IDENTIFIER_OID).unwrap();
+        assert!(extension.critical);
+        // The extension value is a DER OCTET STRING of the SHA-256 digest.
+        let mut expected = vec![0x04, 0x20];
+        expected.extend_from_slice(&Sha256::digest(b"token1.thumbprint"));
+        assert_eq!(extension.value, expected.as_slice());
+    }
+
+    #[test]
+    fn tls_alpn_certificates_are_kept_by_lowercase_domain() {
+        let challenges = Challenges::default();
+        challenges.publish(ChallengeType::TlsAlpn01, "Example.TEST", "token1", "token1.thumbprint").unwrap();
+        assert!(lock(&challenges.tls_alpn).contains_key("example.test"));
+
+        challenges.withdraw("EXAMPLE.test", "token1");
+        assert!(lock(&challenges.tls_alpn).is_empty());
+    }
+}
@@ -0,0 +1,375 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::client::conn::http1;
+use hyper::header::{HeaderName, ACCEPT, CONTENT_TYPE, HOST, LOCATION, RETRY_AFTER, USER_AGENT};
+use hyper::{Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, SanType};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info};
+use url::{Host, Url};
+
+use crate::acme::account::AccountKey;
+use crate::acme::challenges::{ChallengeType, Challenges};
+use crate::crypto::certs::KeyType;
+use crate::crypto::keys::CertificateChain;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for the CA to validate a challenge or sign an order.
+const POLL_TIMEOUT: Duration = Duration::from_secs(180);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The CA may reject any nonce, Pebble does so on purpose, so a few retries are normal.
+const BAD_NONCE_RETRIES: usize = 5;
+
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+/// An RFC 7807 error document, as ACME servers report failures.
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    detail: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = self.kind.strip_prefix("urn:ietf:params:acme:error:").unwrap_or(&self.kind);
+        match &self.detail {
+            Some(detail) => write!(f, "{}: {}", kind, detail),
+            None => f.write_str(kind),
+        }
+    }
+}
+
+struct Reply {
+    status: StatusCode,
+    location: Option<String>,
+    nonce: Option<String>,
+    retry_after: Option<Duration>,
+    body: Bytes,
+}
+
+impl Reply {
+    fn json<T: for<'de> Deserialize<'de>>(&self) -> Result<T, Box<dyn Error>> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Speaks RFC 8555 to one ACME directory on behalf of one account.
+pub struct AcmeClient {
+    tls: Arc<ClientConfig>,
+    directory: Directory,
+    account: AccountKey,
+    contact: Option<String>,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    pub async fn new(directory_url: &str, tls: ClientConfig, account: AccountKey, contact: Option<String>) -> Result<Self, Box<dyn Error>> {
+        let tls = Arc::new(tls);
+        let directory = fetch_directory(&tls, directory_url).await?;
+        Ok(Self { tls, directory, account, contact, kid: None, nonce: None })
+    }
+
+    /// Orders a certificate for `domains`, proving control of each through `challenges`,
+    /// and returns the chain with its new PKCS#8 DER key.
+    pub async fn issue(
+        &mut self,
+        domains: &[String],
+        challenge: ChallengeType,
+        challenges: &Challenges,
+        key_type: KeyType,
+    ) -> Result<(CertificateChain, Vec<u8>), Box<dyn Error>> {
+        self.register().await?;
+
+        let identifiers: Vec<Value> = domains.iter().map(|domain| json!({ "type": "dns", "value": domain })).collect();
+        let new_order = self.directory.new_order.clone();
+        let reply = self.post(&new_order, Some(&json!({ "identifiers": identifiers }))).await?;
+        let order_url = reply.location.clone().ok_or("the CA did not say where the order is")?;
+        let order: Order = reply.json()?;
+
+        for authorization in &order.authorizations {
+            self.authorize(authorization, challenge, challenges).await?;
+        }
+
+        let (private_key, csr) = certificate_request(domains, key_type)?;
+        self.post(&order.finalize, Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) }))).await?;
+
+        let started = Instant::now();
+        let certificate = loop {
+            let reply = self.post(&order_url, None).await?;
+            let order: Order = reply.json()?;
+            match (order.status.as_str(), order.certificate) {
+                ("valid", Some(certificate)) => break certificate,
+                ("invalid", _) => return Err(problem_or(order.error, "the CA rejected the order").into()),
+                _ => wait(started, reply.retry_after, "the CA to sign the certificate").await?,
+            }
+        };
+
+        let reply = self.post(&certificate, None).await?;
+        let chain: CertificateChain = pem::parse_many(&reply.body)?
+            .into_iter()
+            .filter(|block| block.tag() == "CERTIFICATE")
+            .map(pem::Pem::into_contents)
+            .collect();
+        if chain.is_empty() {
+            return Err("the CA sent no certificate".into());
+        }
+
+        Ok((chain, private_key))
+    }
+
+    /// Registers the account, or finds the existing one for this key, which is the same request.
+    async fn register(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.kid.is_some() {
+            return Ok(());
+        }
+
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = &self.contact {
+            payload["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+
+        let new_account = self.directory.new_account.clone();
+        let reply = self.post(&new_account, Some(&payload)).await?;
+        let kid = reply.location.ok_or("the CA did not return an account URL")?;
+        debug!(account = %kid, "ACME account ready");
+        self.kid = Some(kid);
+        Ok(())
+    }
+
+    async fn authorize(&mut self, url: &str, challenge: ChallengeType, challenges: &Challenges) -> Result<(), Box<dyn Error>> {
+        let authorization: Authorization = self.post(url, None).await?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization.identifier.value;
+        let offered = authorization
+            .challenges
+            .into_iter()
+            .find(|offered| offered.kind == challenge.name())
+            .ok_or_else(|| format!("the CA does not offer {} for {}", challenge, domain))?;
+
+        let key_authorization = self.account.key_authorization(&offered.token);
+        challenges.publish(challenge, &domain, &offered.token, &key_authorization)?;
+        info!(domain, challenge = challenge.name(), "answering ACME challenge");
+
+        let result = self.validate(url, &offered.url, &domain).await;
+        challenges.withdraw(&domain, &offered.token);
+        result
+    }
+
+    /// Tells the CA the challenge is ready, then waits for its verdict on the authorization.
+    async fn validate(&mut self, authorization_url: &str, challenge_url: &str, domain: &str) -> Result<(), Box<dyn Error>> {
+        self.post(challenge_url, Some(&json!({}))).await?;
+
+        let started = Instant::now();
+        loop {
+            let reply = self.post(authorization_url, None).await?;
+            let authorization: Authorization = reply.json()?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => wait(started, reply.retry_after, "the CA to validate the challenge").await?,
+                status => {
+                    let error = authorization.challenges.into_iter().find_map(|challenge| challenge.error);
+                    return Err(format!("{}: {}", domain, problem_or(error, &format!("authorization {}", status))).into());
+                }
+            }
+        }
+    }
+
+    /// A JWS POST, or POST-as-GET without `payload`, retried on rejected nonces.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Reply, Box<dyn Error>> {
+        let mut attempt = 0;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+
+            let body = self.account.sign(url, &nonce, self.kid.as_deref(), payload)?;
+            let reply = request(&self.tls, Method::POST, url, Some(body)).await?;
+            self.nonce = reply.nonce.clone();
+
+            if reply.status.is_success() {
+                return Ok(reply);
+            }
+
+            let problem: Option<Problem> = reply.json().ok();
+            if problem.as_ref().is_some_and(|problem| problem.kind == BAD_NONCE) && attempt < BAD_NONCE_RETRIES {
+                attempt += 1;
+                continue;
+            }
+            return Err(format!("{} answered {}: {}", url, reply.status, problem_or(problem, "no details")).into());
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, Box<dyn Error>> {
+        let reply = request(&self.tls, Method::HEAD, &self.directory.new_nonce, None).await?;
+        reply.nonce.ok_or_else(|| "the CA sent no Replay-Nonce".into())
+    }
+}
+
+/// Whether `directory_url` is reachable, trusted and an ACME directory.
+pub async fn check_directory(directory_url: &str, tls: ClientConfig) -> Result<(), Box<dyn Error>> {
+    fetch_directory(&Arc::new(tls), directory_url).await.map(|_| ())
+}
+
+async fn fetch_directory(tls: &Arc<ClientConfig>, directory_url: &str) -> Result<Directory, Box<dyn Error>> {
+    let reply = request(tls, Method::GET, directory_url, None).await?;
+    if !reply.status.is_success() {
+        return Err(format!("{} answered {}", directory_url, reply.status).into());
+    }
+    reply.json().map_err(|e| format!("{} is not an ACME directory: {}", directory_url, e).into())
+}
+
+/// A fresh key and the CSR for it, naming every domain.
+fn certificate_request(domains: &[String], key_type: KeyType) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let mut params = CertificateParams::new(Vec::new());
+    params.distinguished_name = DistinguishedName::new();
+    params.subject_alt_names = domains.iter().map(|domain| SanType::DnsName(domain.clone())).collect();
+    params.alg = key_type.algorithm();
+    params.key_pair = Some(key_type.generate()?);
+
+    let request = Certificate::from_params(params)?;
+    Ok((request.serialize_private_key_der(), request.serialize_request_der()?))
+}
+
+fn problem_or(problem: Option<Problem>, fallback: &str) -> String {
+    problem.map(|problem| problem.to_string()).unwrap_or_else(|| fallback.to_string())
+}
+
+/// Sleeps before the next poll, as long as the CA asks for up to a minute, or gives up.
+async fn wait(started: Instant, retry_after: Option<Duration>, what: &str) -> Result<(), Box<dyn Error>> {
+    if started.elapsed() > POLL_TIMEOUT {
+        return Err(format!("gave up waiting for {}", what).into());
+    }
+    tokio::time::sleep(retry_after.unwrap_or(POLL_INTERVAL).min(Duration::from_secs(60))).await;
+    Ok(())
+}
+
+/// One request on its own connection; an order takes a handful, so pooling is not worth it.
+async fn request(tls: &Arc<ClientConfig>, method: Method, url: &str, body: Option<Vec<u8>>) -> Result<Reply, Box<dyn Error>> {
+    let send = async {
+        let parsed = Url::parse(url)?;
+        if parsed.scheme() != "https" {
+            return Err("ACME needs https".into());
+        }
+        let host = match parsed.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => return Err("no host".into()),
+        };
+        let port = parsed.port_or_known_default().unwrap_or(443);
+
+        let server_name = ServerName::try_from(host.as_str()).map_err(|_| "invalid host")?;
+        let stream = TcpStream::connect((host.as_str(), port)).await?;
+        let stream = TlsConnector::from(tls.clone()).connect(server_name, stream).await?;
+
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!(error = %e, "ACME connection ended");
+            }
+        });
+
+        let path = match parsed.query() {
+            Some(query) => format!("{}?{}", parsed.path(), query),
+            None => parsed.path().to_string(),
+        };
+        // host_str keeps the brackets around IPv6 addresses, as the Host header wants them.
+        let authority = match parsed.port() {
+            Some(port) => format!("{}:{}", parsed.host_str().unwrap_or_default(), port),
+            None => parsed.host_str().unwrap_or_default().to_string(),
+        };
+
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(HOST, authority)
+            .header(USER_AGENT, concat!("droppa/", env!("CARGO_PKG_VERSION")))
+            .header(CONTENT_TYPE, "application/jose+json")
+            .header(ACCEPT, "application/json, application/pem-certificate-chain")
+            .body(Full::new(Bytes::from(body.unwrap_or_default())))?;
+
+        let response = sender.send_request(request).await?;
+        let header = |name: HeaderName| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let location = header(LOCATION);
+        let nonce = header(HeaderName::from_static("replay-nonce"));
+        let retry_after = header(RETRY_AFTER).and_then(|value| value.parse().ok()).map(Duration::from_secs);
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+
+        Ok::<_, Box<dyn Error>>(Reply { status, location, nonce, retry_after, body })
+    };
+
+    tokio::time::timeout(REQUEST_TIMEOUT, send)
+        .await
+        .map_err(|_| format!("{}: no answer within {} seconds", url, REQUEST_TIMEOUT.as_secs()))?
+        .map_err(|e| format!("{}: {}", url, with_causes(&*e)).into())
+}
+
+/// hyper keeps the useful part of its errors, such as the I/O failure, in the source chain.
+fn with_causes(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
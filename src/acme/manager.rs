@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::acme::account::AccountKey;
+use crate::acme::challenges::{AcmeResolver, ChallengeType, Challenges};
+use crate::acme::client::{check_directory, AcmeClient};
+use crate::crypto::certs::{
+    certificate_not_after, certificate_not_before, certificate_subject_alt_names, generate_self_signed_certificate,
+    sha256_fingerprint, CertificateSpec, ExtendedKeyUsage, KeyType, KeyUsage, SubjectAltName,
+};
+use crate::crypto::keys::{save_key_pair, CertificateChain};
+use crate::crypto::sni::certified_key;
+use crate::crypto::tls::{load_tls_files, verifying_tls_connector};
+use crate::lifecycle::shutdown::Shutdown;
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// The longest to sleep between looks at the certificate, so a changed clock or a
+/// suspended host never puts off a renewal for long.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Failed orders are retried after this long, doubling up to `RETRY_MAX`, which keeps
+/// well inside the CA's rate limits on failed validations.
+const RETRY_MIN: Duration = Duration::from_secs(60);
+const RETRY_MAX: Duration = Duration::from_secs(6 * 3600);
+
+/// The stand-in served until the first certificate arrives should never be around this long.
+const STAND_IN_VALIDITY: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// What to order and from where, resolved from the `--acme*` settings.
+#[derive(Clone)]
+pub struct AcmeConfig {
+    pub directory: String,
+    pub domains: Vec<String>,
+    pub contact: Option<String>,
+    pub store: PathBuf,
+    pub challenge: ChallengeType,
+    /// Trusted for the directory's HTTPS besides the system roots, such as Pebble's root.
+    pub roots: Option<PathBuf>,
+    pub key_type: KeyType,
+}
+
+impl AcmeConfig {
+    /// The store keeps an account and a certificate per CA, so switching between a test CA
+    /// and a real one never serves the other's certificate.
+    fn dir(&self) -> PathBuf {
+        let name = Url::parse(&self.directory)
+            .ok()
+            .and_then(|url| {
+                let host = url.host_str()?.replace([':', '[', ']'], "_");
+                Some(match url.port() {
+                    Some(port) => format!("{}_{}", host, port),
+                    None => host,
+                })
+            })
+            .unwrap_or_else(|| "default".to_string());
+        self.store.join(name)
+    }
+
+    /// Whether the directory can be reached and trusted, without touching the account.
+    pub async fn check(&self) -> Result<(), Box<dyn Error>> {
+        check_directory(&self.directory, verifying_tls_connector(self.roots.as_deref())?).await
+    }
+}
+
+/// The certificate being served and whether the CA issued it, or it is the stand-in.
+struct Served {
+    leaf: Vec<u8>,
+    issued: bool,
+}
+
+/// Keeps a certificate from the ACME CA in the resolver: the stored one at startup, then
+/// orders whenever there is none yet or it nears expiry.
+pub struct AcmeManager {
+    config: AcmeConfig,
+    challenges: Arc<Challenges>,
+    resolver: Arc<AcmeResolver>,
+    served: Mutex<Served>,
+}
+
+impl AcmeManager {
+    /// Serves the stored certificate while it still names every domain, a short-lived
+    /// self-signed stand-in otherwise.
+    pub fn new(config: AcmeConfig) -> Result<Self, Box<dyn Error>> {
+        let (chain, private_key, issued) = match stored(&config) {
+            Some((chain, private_key)) => (chain, private_key, true),
+            None => {
+                let (cert, private_key) = generate_self_signed_certificate(&stand_in(&config))?;
+                (vec![cert], private_key, false)
+            }
+        };
+
+        let challenges = Arc::new(Challenges::default());
+        let served = Served { leaf: chain[0].clone(), issued };
+        let resolver = Arc::new(AcmeResolver::new(certified_key(chain, private_key)?, challenges.clone()));
+
+        Ok(Self { config, challenges, resolver, served: Mutex::new(served) })
+    }
+
+    pub fn resolver(&self) -> Arc<AcmeResolver> {
+        self.resolver.clone()
+    }
+
+    pub fn challenges(&self) -> Arc<Challenges> {
+        self.challenges.clone()
+    }
+
+    /// The DER leaf served right now.
+    pub fn leaf(&self) -> Vec<u8> {
+        self.lock().leaf.clone()
+    }
+
+    /// Orders certificates until shutdown: right away while the stand-in is served, then
+    /// once the current one is into the last third of its lifetime.
+    pub async fn run(self: Arc<Self>, shutdown: Shutdown) {
+        let mut retry = RETRY_MIN;
+
+        loop {
+            let due_in = self.renewal_due_in().unwrap_or_else(|err| {
+                warn!(error = %err, "cannot read the served certificate, ordering a new one");
+                Duration::ZERO
+            });
+
+            let delay = if !due_in.is_zero() {
+                due_in.min(CHECK_INTERVAL)
+            } else {
+                match self.renew().await {
+                    // Not straight back to ordering, should the CA hand out certificates already due.
+                    Ok(()) => {
+                        retry = RETRY_MIN;
+                        RETRY_MIN
+                    }
+                    Err(err) => {
+                        warn!(error = %err, retry_in = %humantime::format_duration(retry), "ACME order failed");
+                        let delay = retry;
+                        retry = (retry * 2).min(RETRY_MAX);
+                        delay
+                    }
+                }
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.triggered() => return,
+            }
+        }
+    }
+
+    /// How long until the served certificate is into the last third of its lifetime.
+    fn renewal_due_in(&self) -> Result<Duration, Box<dyn Error>> {
+        let served = self.lock();
+        if !served.issued {
+            return Ok(Duration::ZERO);
+        }
+
+        let not_before = certificate_not_before(&served.leaf)?;
+        let not_after = certificate_not_after(&served.leaf)?;
+        let lifetime = not_after.duration_since(not_before).unwrap_or_default();
+        Ok((not_after - lifetime / 3).duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    async fn renew(&self) -> Result<(), Box<dyn Error>> {
+        let dir = self.config.dir();
+        let account = AccountKey::load_or_create(&dir)?;
+        let tls = verifying_tls_connector(self.config.roots.as_deref())?;
+
+        info!(directory = self.config.directory, domains = ?self.config.domains, "ordering ACME certificate");
+        let mut client = AcmeClient::new(&self.config.directory, tls, account, self.config.contact.clone()).await?;
+        let (chain, private_key) = client
+            .issue(&self.config.domains, self.config.challenge, &self.challenges, self.config.key_type)
+            .await?;
+
+        save(&dir, &chain, &private_key)?;
+        let leaf = chain[0].clone();
+        self.resolver.replace(certified_key(chain, private_key)?);
+
+        info!(
+            sha256 = sha256_fingerprint(&leaf),
+            expires = %humantime::format_rfc3339_seconds(certificate_not_after(&leaf)?),
+            "installed ACME certificate"
+        );
+        *self.lock() = Served { leaf, issued: true };
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Served> {
+        self.served.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The stored chain and key, unless the domains or key type changed since it was issued.
+/// A damaged store is not fatal, since the next order rewrites it.
+fn stored(config: &AcmeConfig) -> Option<(CertificateChain, Vec<u8>)> {
+    let dir = config.dir();
+    let (cert_path, key_path) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+    if !cert_path.exists() {
+        return None;
+    }
+
+    let loaded = load_tls_files(&key_path, &cert_path, None)
+        .and_then(|(chain, private_key)| Ok((certificate_subject_alt_names(&chain[0])?, chain, private_key)));
+    let (names, chain, private_key) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            warn!(store = %dir.display(), error = %err, "cannot load the stored ACME certificate, ordering a new one");
+            return None;
+        }
+    };
+
+    let same_names = names.len() == config.domains.len()
+        && config.domains.iter().all(|domain| names.contains(&SubjectAltName::Dns(domain.clone())));
+    if !same_names || KeyType::of(&private_key) != Some(config.key_type) {
+        info!(store = %dir.display(), "stored ACME certificate no longer fits, ordering a new one");
+        return None;
+    }
+    Some((chain, private_key))
+}
+
+fn save(dir: &Path, chain: &CertificateChain, private_key: &[u8]) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    save_key_pair(&dir.join(KEY_FILE), &dir.join(CERT_FILE), private_key, chain)?;
+    Ok(())
+}
+
+fn stand_in(config: &AcmeConfig) -> CertificateSpec {
+    CertificateSpec {
+        key_type: config.key_type,
+        common_name: config.domains[0].clone(),
+        organization: None,
+        organizational_unit: None,
+        country: None,
+        subject_alt_names: config.domains.iter().map(|domain| SubjectAltName::Dns(domain.clone())).collect(),
+        not_before: SystemTime::now() - Duration::from_secs(3600),
+        validity: STAND_IN_VALIDITY,
+        serial: None,
+        key_usages: vec![KeyUsage::DigitalSignature, KeyUsage::KeyEncipherment],
+        extended_key_usages: vec![ExtendedKeyUsage::ServerAuth],
+    }
+}
@@ -0,0 +1,4 @@
+pub mod account;
+pub mod challenges;
+pub mod client;
+pub mod manager;
@@ -0,0 +1,364 @@
+use clap::{Arg, ArgMatches, Command};
+
+use crate::transport::listener::ListenerSpec;
+
+/// Arguments that select where settings come from rather than being settings themselves.
+pub const META_ARGS: &[&str] = &["config", "profile", "print-config", "check"];
+
+pub fn parse() -> ArgMatches {
+    command().get_matches()
+}
+
+pub fn command() -> Command {
+    Command::new("DROPPA")
+        .version("1.0")
+        .author("Krystian Bajno")
+        .about("A simple file server server with optional TLS")
+        .arg(Arg::new("config")
+            .long("config")
+            .value_name("file")
+            .help("Load settings from a TOML file [env: DROPPA_CONFIG]")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("profile")
+            .long("profile")
+            .value_name("name")
+            .help("Apply [profile.<name>] from the config file [env: DROPPA_PROFILE]")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("print-config")
+            .long("print-config")
+            .help("Print the effective merged configuration as TOML and exit")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("check")
+            .long("check")
+            .help("Validate the configuration, TLS files, upstream and bind addresses, print the run plan and exit")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("listen")
+            .long("listen")
+            .alias("host")
+            .value_name("listen")
+            .help("Set a listening address, repeatable: IPv4, [IPv6], or unix:/path.sock, optionally with :port and ,tls or ,plain [default: 0.0.0.0]")
+            .value_parser(clap::value_parser!(ListenerSpec))
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("dual-stack")
+            .long("dual-stack")
+            .help("Let IPv6 wildcard listeners accept IPv4 too, and listen on [::] by default")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("interface")
+            .long("interface")
+            .value_name("name")
+            .help("Only accept and advertise on this network interface, e.g. tun0 (SO_BINDTODEVICE on Linux)")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("port")
+            .long("port")
+            .value_name("port")
+            .help("Set the listening port, 0 picks a free one")
+            .default_value("8000")
+            .value_parser(clap::value_parser!(u16))
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("directory")
+            .long("directory")
+            .short('d')
+            .value_name("directory")
+            .help("Set the directory to serve files from")
+            .default_value(".")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("tls")
+            .long("tls")
+            .alias("ssl")
+            .help("Enable TLS")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("key-type")
+            .long("key-type")
+            .value_name("type")
+            .help("Key of the generated certificate: rsa2048, rsa3072, rsa4096, ecdsa-p256, ecdsa-p384 or ed25519")
+            .default_value("rsa2048")
+            .global(true)
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("common-name")
+            .long("common-name")
+            .alias("issuer")
+            .value_name("name")
+            .help("Subject common name of the generated certificate")
+            .default_value("getrekt.com")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("san")
+            .long("san")
+            .value_name("name")
+            .help("Subject alternative name of the generated certificate, a DNS name or IP, repeatable [default: common name, hostname and listener addresses]")
+            .value_delimiter(',')
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("cert-org")
+            .long("cert-org")
+            .value_name("organization")
+            .help("Subject organization (O) of the generated certificate")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("cert-org-unit")
+            .long("cert-org-unit")
+            .value_name("unit")
+            .help("Subject organizational unit (OU) of the generated certificate")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("cert-country")
+            .long("cert-country")
+            .value_name("code")
+            .help("Subject country (C) of the generated certificate, two letters")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("cert-not-before")
+            .long("cert-not-before")
+            .value_name("time")
+            .help("Start of the generated certificate's validity, RFC 3339 such as 2024-01-01T00:00:00Z [default: an hour ago]")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("cert-valid-days")
+            .long("cert-valid-days")
+            .value_name("days")
+            .help("How long the generated certificate is valid from --cert-not-before")
+            .default_value("365")
+            .value_parser(clap::value_parser!(u64))
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("cert-serial")
+            .long("cert-serial")
+            .value_name("hex")
+            .help("Serial number of the generated certificate in hex [default: random]")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("key-usage")
+            .long("key-usage")
+            .value_name("usage")
+            .help("Key usage of the generated certificate, repeatable: digital-signature, content-commitment, key-encipherment, data-encipherment, key-agreement, key-cert-sign, crl-sign [default: digital-signature,key-encipherment]")
+            .value_delimiter(',')
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("ext-key-usage")
+            .long("ext-key-usage")
+            .value_name("usage")
+            .help("Extended key usage of the generated certificate, repeatable: server-auth, client-auth, code-signing, email-protection, time-stamping, ocsp-signing [default: server-auth]")
+            .value_delimiter(',')
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("cert-store")
+            .long("cert-store")
+            .value_name("dir")
+            .help("Keep the generated certificate and key in this directory and reuse them on later runs")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("ca")
+            .long("ca")
+            .value_name("dir")
+            .help("Serve leaf certificates minted per SNI name and signed by the CA in this directory, see `ca init`")
+            .global(true)
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("proxy")
+            .long("proxy")
+            .help("Setup as reverse proxy")
+            .value_name("proxy")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("priv")
+            .long("priv")
+            .value_name("private_key")
+            .help("Path to the private key file")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("cert")
+            .long("cert")
+            .value_name("certificate")
+            .help("Path to the certificate file for --priv, or name=cert.pem,key.pem to serve a certificate to clients asking for that name via SNI, repeatable")
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("key-passphrase")
+            .long("key-passphrase")
+            .value_name("passphrase")
+            .help("Passphrase of encrypted PKCS#8 keys given with --priv, --cert or --cert-dir; prefer DROPPA_KEY_PASSPHRASE to keep it out of the process list")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("pkcs12")
+            .long("pkcs12")
+            .value_name("bundle")
+            .help("Serve the certificate chain and key from this PKCS#12 (.p12 / .pfx) bundle instead of --cert/--priv")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("pkcs12-pass")
+            .long("pkcs12-pass")
+            .value_name("password")
+            .help("Password of the --pkcs12 bundle, and of the one written by `cert export`; prefer DROPPA_PKCS12_PASS to keep it out of the process list")
+            .global(true)
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("cert-dir")
+            .long("cert-dir")
+            .value_name("dir")
+            .help("Serve every <name>.pem or <name>.crt with its <name>.key in this directory, selected by SNI against the certificate's DNS names")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("default-cert")
+            .long("default-cert")
+            .value_name("name")
+            .help("Certificate for clients without SNI or asking for an unknown name: a --cert name, a --cert-dir file name, or 'default' for --cert/--priv [default: the first]")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("acme")
+            .long("acme")
+            .value_name("domain")
+            .help("Obtain and renew a certificate for this domain from an ACME CA such as Let's Encrypt, repeatable; the CA must reach droppa on port 443 or, with http-01, port 80")
+            .value_delimiter(',')
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("acme-directory")
+            .long("acme-directory")
+            .value_name("url")
+            .help("Directory URL of the ACME CA, such as https://localhost:14000/dir for a local Pebble")
+            .default_value("https://acme-v02.api.letsencrypt.org/directory")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("acme-email")
+            .long("acme-email")
+            .value_name("address")
+            .help("Contact address registered with the ACME account, for expiry notices")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("acme-store")
+            .long("acme-store")
+            .value_name("dir")
+            .help("Keep the ACME account key and issued certificates in this directory, required with --acme")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("acme-challenge")
+            .long("acme-challenge")
+            .value_name("type")
+            .help("How to prove control of the domains: tls-alpn-01 on a TLS listener, or http-01 on a plain one")
+            .default_value("tls-alpn-01")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("acme-root")
+            .long("acme-root")
+            .value_name("file")
+            .help("Also trust the certificates in this file for the ACME directory's HTTPS, such as Pebble's pebble.minica.pem")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("client-ca")
+            .long("client-ca")
+            .value_name("file")
+            .help("Ask TLS clients for a certificate and verify it against the CA certificates in this PEM or DER file")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("client-auth")
+            .long("client-auth")
+            .value_name("mode")
+            .help("With --client-ca: required turns away clients without a certificate, optional lets them in as anonymous, subject to --auth")
+            .default_value("required")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("client-user")
+            .long("client-user")
+            .value_name("selector=user[:role]")
+            .help("Map client certificates to a user and a file server role (read, write, read-write), repeatable, first match wins; selector is cn:<name>, o:<name>, ou:<name>, sha256:<fingerprint> or *. Certificates matching none are refused [default: any certificate from --client-ca as its common name, read-write]")
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("upstream-verify")
+            .long("upstream-verify")
+            .value_name("mode")
+            .help("How --proxy checks an https:// upstream's certificate: none, system (the OS trust store), ca:<file> (only CAs in this PEM or DER file) or pin:<sha256> (the leaf's public key, as printed by droppa or curl, comma-separated for several)")
+            .default_value("none")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("upstream-name")
+            .long("upstream-name")
+            .value_name("name")
+            .help("Send this name as SNI to the upstream and check the certificate against it [default: the host in --proxy]")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("upstream-no-hostname-check")
+            .long("upstream-no-hostname-check")
+            .help("With --upstream-verify system or ca:, accept upstream certificates whose chain verifies whatever names they carry")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("redirect-https")
+            .long("redirect-https")
+            .help("With TLS enabled, answer plaintext HTTP requests with a redirect to HTTPS")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("auth")
+            .long("auth")
+            .help("Require a generated token for every file server request (Bearer, or Basic with the token as password)")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("auth-token")
+            .long("auth-token")
+            .value_name("token")
+            .help("Require this token instead of a generated one")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("ready-file")
+            .long("ready-file")
+            .value_name("file")
+            .help("Write the JSON startup announcement to this file instead of stdout")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("audit-log")
+            .long("audit-log")
+            .value_name("file")
+            .help("Append a JSON Lines record of every download and upload to this file")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("admin-listen")
+            .long("admin-listen")
+            .value_name("address")
+            .help("Serve /metrics, /healthz and /readyz on this address and port, e.g. 127.0.0.1:9100, or a unix: socket")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("shutdown-timeout")
+            .long("shutdown-timeout")
+            .value_name("seconds")
+            .help("Seconds to wait for active transfers after SIGINT/SIGTERM")
+            .default_value("30")
+            .value_parser(clap::value_parser!(u64))
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-level")
+            .long("log-level")
+            .value_name("level")
+            .help("Minimum level to log: error, warn, info, debug or trace")
+            .default_value("info")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-filter")
+            .long("log-filter")
+            .value_name("directives")
+            .help("Per-module levels on top of --log-level, e.g. droppa::proxy=trace,hyper=warn")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-format")
+            .long("log-format")
+            .value_name("format")
+            .help("Console log format: human or json")
+            .default_value("human")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-file")
+            .long("log-file")
+            .value_name("file")
+            .help("Also write logs to this file, rotated per --log-rotation")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-file-format")
+            .long("log-file-format")
+            .value_name("format")
+            .help("Log file format: human or json")
+            .default_value("json")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-rotation")
+            .long("log-rotation")
+            .value_name("period")
+            .help("Start a new log file: never, minutely, hourly or daily")
+            .default_value("daily")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-max-files")
+            .long("log-max-files")
+            .value_name("count")
+            .help("Rotated log files to keep, 0 keeps all")
+            .default_value("7")
+            .value_parser(clap::value_parser!(usize))
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("quiet")
+            .long("quiet")
+            .short('q')
+            .help("Only print warnings and errors to the console, and no startup banner")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .help("Log more, repeat for more detail (-v debug, -vv trace)")
+            .action(clap::ArgAction::Count))
+        .arg(Arg::new("tui")
+            .long("tui")
+            .help("Show a live dashboard of connections, transfers and requests instead of console logs")
+            .action(clap::ArgAction::SetTrue))
+        .subcommand(Command::new("ca")
+            .about("Manage the local certificate authority used by --ca")
+            .subcommand_required(true)
+            .subcommand(Command::new("init")
+                .about("Create the root certificate and key in the --ca directory, using --key-type")
+                .arg(Arg::new("force")
+                    .long("force")
+                    .help("Replace an existing root; clients that trusted the old one stop trusting droppa")
+                    .action(clap::ArgAction::SetTrue))))
+        .subcommand(Command::new("cert")
+            .about("Work with the certificate droppa serves")
+            .subcommand_required(true)
+            .subcommand(Command::new("export")
+                .about("Write the certificate and key the other flags select as a bundle for browsers and certificate stores, encrypted with --pkcs12-pass")
+                .arg(Arg::new("pkcs12")
+                    .long("pkcs12")
+                    .value_name("file")
+                    .help("Write a PKCS#12 (.p12 / .pfx) bundle to this file")
+                    .required(true)
+                    .action(clap::ArgAction::Set))
+                .arg(Arg::new("legacy")
+                    .long("legacy")
+                    .help("Encrypt with 3DES and SHA-1 for older Windows and macOS keychains instead of AES-256")
+                    .action(clap::ArgAction::SetTrue))))
+}
@@ -0,0 +1,1146 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::net::IpAddr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::server::ClientCertVerifier;
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsAcceptor;
+use tracing_subscriber::EnvFilter;
+use url::{Host, Url};
+
+use crate::acme::challenges::ChallengeType;
+use crate::acme::manager::{AcmeConfig, AcmeManager};
+use crate::config::settings::Settings;
+use crate::crypto::client_auth::{client_verifier, ClientAuth, ClientAuthMode, ClientMapping};
+use crate::crypto::certs::{
+    certificate_subject_alt_names, generate_self_signed_certificate, parse_serial, sha256_fingerprint, spki_fingerprint,
+    CertificateSpec, KeyType, SubjectAltName,
+};
+use crate::crypto::keys::CertificateChain;
+use crate::crypto::pkcs12::load_pkcs12;
+use crate::crypto::sni::{certified_key, SniResolver};
+use crate::crypto::store::CertificateStore;
+use crate::crypto::ca::{CertificateAuthority, MintingResolver};
+use crate::crypto::tls::{acme_tls_acceptor, generate_tls_acceptor, load_tls_files, resolving_tls_acceptor};
+use crate::crypto::upstream::{UpstreamTls, UpstreamVerify};
+use crate::http::auth::generate_token;
+use crate::lifecycle::logging::{verbose_level, LogFile, LogFormat, LogOptions, LogRotation, LEVELS};
+use crate::transport::interfaces::{bound_addresses, interface_addresses};
+use crate::transport::listener::{BindOptions, ListenAddr, Listener};
+
+pub enum Mode {
+    FileServer { directory: PathBuf },
+    ReverseProxy { target: String },
+}
+
+pub enum TlsMaterial {
+    /// Stored in and reused from `store` when given, fresh on every run otherwise.
+    Generated { spec: Box<CertificateSpec>, store: Option<PathBuf> },
+    Files { private_key: PathBuf, cert: PathBuf, passphrase: Option<String> },
+    Pkcs12 { bundle: PathBuf, password: Option<String> },
+    /// Leaves minted per SNI and signed by the root in `dir`; `spec` shapes every leaf.
+    Authority { dir: PathBuf, spec: Box<CertificateSpec> },
+    /// Several key pairs chosen by SNI, falling back to `certificates[default]`.
+    Selected { certificates: Vec<NamedCertificate>, default: usize, passphrase: Option<String> },
+    /// Issued and renewed by an ACME CA, with a stand-in until the first order completes.
+    Acme { config: Box<AcmeConfig> },
+}
+
+pub struct NamedCertificate {
+    /// What `--default-cert` refers to it by.
+    pub label: String,
+    /// The SNI name it answers; the certificate's own DNS names when not given.
+    pub name: Option<String>,
+    pub cert: PathBuf,
+    pub private_key: PathBuf,
+}
+
+pub struct LoadedTls {
+    pub acceptor: TlsAcceptor,
+    /// Of the root rather than the leaf when the certificates come from a local CA.
+    pub fingerprint_sha256: String,
+    pub spki_sha256: String,
+    pub from_authority: bool,
+    /// The certificate every client gets; `None` when it varies by SNI or renewal.
+    pub leaf: Option<Vec<u8>>,
+    /// Keeps the certificate renewed once started; `None` unless it comes from ACME.
+    pub acme: Option<Arc<AcmeManager>>,
+}
+
+impl TlsMaterial {
+    /// Loads the certificates and builds the acceptor, asking for client certificates
+    /// when `client_auth` is given.
+    pub fn load(&self, client_auth: Option<&ClientAuth>) -> Result<LoadedTls, Box<dyn Error>> {
+        let clients = client_verifier(client_auth)?;
+        let (chain, private_key) = match self {
+            TlsMaterial::Generated { spec, store: None } => {
+                let (cert, private_key) = generate_self_signed_certificate(spec)?;
+                (vec![cert], private_key)
+            }
+            TlsMaterial::Selected { certificates, default, passphrase } => {
+                return load_selected(certificates, *default, passphrase.as_deref(), clients);
+            }
+            TlsMaterial::Authority { dir, spec } => {
+                let authority = CertificateAuthority::load(dir)?;
+                let fingerprint_sha256 = sha256_fingerprint(authority.cert());
+                let spki_sha256 = spki_fingerprint(authority.cert())?;
+                let resolver = MintingResolver::new(authority, (**spec).clone())?;
+
+                return Ok(LoadedTls {
+                    acceptor: resolving_tls_acceptor(Arc::new(resolver), clients),
+                    fingerprint_sha256,
+                    spki_sha256,
+                    from_authority: true,
+                    leaf: None,
+                    acme: None,
+                });
+            }
+            TlsMaterial::Acme { config } => {
+                let manager = Arc::new(AcmeManager::new((**config).clone())?);
+                let leaf = manager.leaf();
+
+                return Ok(LoadedTls {
+                    acceptor: acme_tls_acceptor(manager.resolver(), config.challenge, clients),
+                    fingerprint_sha256: sha256_fingerprint(&leaf),
+                    spki_sha256: spki_fingerprint(&leaf)?,
+                    from_authority: false,
+                    leaf: None,
+                    acme: Some(manager),
+                });
+            }
+            material => material.key_pair()?,
+        };
+
+        let fingerprint_sha256 = sha256_fingerprint(&chain[0]);
+        let spki_sha256 = spki_fingerprint(&chain[0])?;
+        let leaf = Some(chain[0].clone());
+        let acceptor = generate_tls_acceptor(chain, private_key, clients)?;
+
+        Ok(LoadedTls { acceptor, fingerprint_sha256, spki_sha256, from_authority: false, leaf, acme: None })
+    }
+
+    /// The one chain and key this material stands for: the stored, loaded or bundled
+    /// certificate, or the default of several selected by SNI.
+    pub fn key_pair(&self) -> Result<(CertificateChain, Vec<u8>), Box<dyn Error>> {
+        match self {
+            TlsMaterial::Generated { spec, store: Some(store) } => {
+                let (cert, private_key) = CertificateStore::new(store).load_or_generate(spec)?;
+                Ok((vec![cert], private_key))
+            }
+            TlsMaterial::Generated { store: None, .. } => {
+                Err("the generated certificate is new on every run, pass --cert-store <dir> to keep one".into())
+            }
+            TlsMaterial::Files { private_key, cert, passphrase } => load_tls_files(private_key, cert, passphrase.as_deref()),
+            TlsMaterial::Pkcs12 { bundle, password } => load_pkcs12(bundle, password.as_deref()),
+            TlsMaterial::Selected { certificates, default, passphrase } => {
+                let certificate = &certificates[*default];
+                load_tls_files(&certificate.private_key, &certificate.cert, passphrase.as_deref())
+            }
+            TlsMaterial::Authority { dir, .. } => Err(format!(
+                "--ca mints a leaf per name, so there is no single key; clients import {} instead",
+                CertificateAuthority::cert_path(dir).display(),
+            )
+            .into()),
+            TlsMaterial::Acme { .. } => Err("--acme certificates are renewed in place; the issued chain and key are in --acme-store".into()),
+        }
+    }
+}
+
+pub struct PlannedListener {
+    pub address: ListenAddr,
+    pub tls: bool,
+}
+
+/// What droppa is going to do, resolved from the merged settings before anything binds.
+pub struct RunPlan {
+    pub mode: Mode,
+    pub listeners: Vec<PlannedListener>,
+    /// Plain HTTP listener for metrics and health checks, never one of the public ports.
+    pub admin: Option<ListenAddr>,
+    pub tls: Option<TlsMaterial>,
+    pub client_auth: Option<Arc<ClientAuth>>,
+    /// How the proxy checks an `https://` upstream; `None` for plain upstreams and the file server.
+    pub upstream: Option<UpstreamTls>,
+    pub bind: BindOptions,
+    pub redirect_https: bool,
+    pub auth_token: Option<String>,
+    pub ready_file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub shutdown_timeout: Duration,
+    pub log: LogOptions,
+    pub tui: bool,
+}
+
+pub struct PlanErrors(pub Vec<String>);
+
+impl fmt::Display for PlanErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl RunPlan {
+    pub fn resolve(settings: &Settings) -> Result<Self, PlanErrors> {
+        let mut errors = Vec::new();
+
+        let mode = match settings.proxy.as_deref().map(str::trim) {
+            Some(target) if !target.is_empty() => match normalize_target(target) {
+                Ok(target) => Mode::ReverseProxy { target },
+                Err(err) => {
+                    errors.push(err);
+                    Mode::ReverseProxy { target: target.to_string() }
+                }
+            },
+            _ => {
+                if !settings.directory.is_dir() {
+                    errors.push(format!("directory {} does not exist or is not a directory", settings.directory.display()));
+                }
+                Mode::FileServer { directory: settings.directory.clone() }
+            }
+        };
+
+        let mut named = Vec::new();
+        let mut unnamed = Vec::new();
+        for entry in &settings.cert {
+            match entry.split_once('=') {
+                Some((name, files)) => match named_certificate(name, files) {
+                    Ok(certificate) => named.push(certificate),
+                    Err(err) => errors.push(format!("--cert {}: {}", entry, err)),
+                },
+                None => unnamed.push(PathBuf::from(entry)),
+            }
+        }
+        if unnamed.len() > 1 {
+            errors.push("--cert without a name= prefix pairs with --priv and can only be given once".to_string());
+        }
+        if let Some(dir) = &settings.cert_dir {
+            match certificate_dir(dir) {
+                Ok(found) => named.extend(found),
+                Err(err) => errors.push(format!("--cert-dir {}: {}", dir.display(), err)),
+            }
+        }
+
+        let key_pair = match (&settings.private_key, unnamed.first()) {
+            (Some(private_key), Some(cert)) => Some((private_key.clone(), cert.clone())),
+            (Some(_), None) => {
+                errors.push("--priv needs a matching --cert".to_string());
+                None
+            }
+            (None, Some(_)) => {
+                errors.push("--cert needs a matching --priv".to_string());
+                None
+            }
+            (None, None) => None,
+        };
+
+        let custom_tls = if let Some(bundle) = &settings.pkcs12 {
+            if key_pair.is_some() || !named.is_empty() {
+                errors.push("--pkcs12 replaces --cert/--priv and cannot be combined with them or --cert-dir".to_string());
+            }
+            if settings.default_cert.is_some() {
+                errors.push("--default-cert needs --cert name=... or --cert-dir".to_string());
+            }
+            Some(TlsMaterial::Pkcs12 { bundle: bundle.clone(), password: settings.pkcs12_pass.clone() })
+        } else if named.is_empty() {
+            if settings.default_cert.is_some() {
+                errors.push("--default-cert needs --cert name=... or --cert-dir".to_string());
+            }
+            key_pair.map(|(private_key, cert)| TlsMaterial::Files { private_key, cert, passphrase: settings.key_passphrase.clone() })
+        } else {
+            let certificates: Vec<NamedCertificate> = key_pair
+                .map(|(private_key, cert)| NamedCertificate { label: "default".to_string(), name: None, cert, private_key })
+                .into_iter()
+                .chain(named)
+                .collect();
+            let default = match &settings.default_cert {
+                Some(label) => certificates.iter().position(|certificate| certificate.label == *label).unwrap_or_else(|| {
+                    errors.push(format!("--default-cert {}: no certificate by that name", label));
+                    0
+                }),
+                None => 0,
+            };
+            Some(TlsMaterial::Selected { certificates, default, passphrase: settings.key_passphrase.clone() })
+        };
+
+        if settings.key_passphrase.is_some() && matches!(custom_tls, None | Some(TlsMaterial::Pkcs12 { .. })) {
+            errors.push("--key-passphrase only applies to keys loaded with --priv, --cert name=... or --cert-dir".to_string());
+        }
+
+        let acme = (!settings.acme.is_empty()).then(|| resolve_acme(settings, &mut errors));
+        if acme.is_some() && custom_tls.is_some() {
+            errors.push("--acme cannot be combined with --priv/--cert/--pkcs12".to_string());
+        }
+
+        let acme_options = [
+            ("--acme-directory", settings.acme_directory != Settings::default().acme_directory),
+            ("--acme-email", settings.acme_email.is_some()),
+            ("--acme-store", settings.acme_store.is_some()),
+            ("--acme-challenge", settings.acme_challenge != Settings::default().acme_challenge),
+            ("--acme-root", settings.acme_root.is_some()),
+        ];
+        for (flag, _) in acme_options.iter().filter(|(_, given)| *given && acme.is_none()) {
+            errors.push(format!("{} needs --acme", flag));
+        }
+
+        // The reverse proxy always speaks TLS unless a listener opts out; the file server
+        // only does when asked to or when handed a key pair.
+        let default_tls = matches!(mode, Mode::ReverseProxy { .. }) || settings.tls || custom_tls.is_some() || acme.is_some();
+
+        let mut listeners: Vec<PlannedListener> = Vec::new();
+        for spec in &settings.listen {
+            if listeners.iter().any(|listener| listener.address == spec.address) {
+                errors.push(format!("listener {} is given more than once", spec.address));
+                continue;
+            }
+
+            if cfg!(not(unix)) && matches!(spec.address, ListenAddr::Unix(_)) {
+                errors.push(format!("{}: Unix domain sockets are not supported on this platform", spec.address));
+            }
+
+            listeners.push(PlannedListener {
+                address: spec.address.clone(),
+                tls: spec.tls.unwrap_or(default_tls),
+            });
+        }
+
+        let admin = match settings.admin_listen.as_deref().map(str::parse::<ListenAddr>) {
+            None => None,
+            Some(Err(err)) => {
+                errors.push(format!("--admin-listen: {}", err));
+                None
+            }
+            Some(Ok(address)) => {
+                if matches!(address, ListenAddr::Tcp { port: None, .. }) {
+                    errors.push(format!("--admin-listen {} needs an explicit port", address));
+                }
+                if let Some(listener) = listeners.iter().find(|listener| shares_port(&listener.address, &address, settings.port)) {
+                    errors.push(format!("--admin-listen {} must stay off the public listener {}", address, listener.address));
+                }
+                Some(address)
+            }
+        };
+
+        if let Some(interface) = &settings.interface {
+            if let Err(err) = interface_addresses(interface) {
+                errors.push(format!("--interface: {}", err));
+            }
+        }
+
+        let any_tls = listeners.iter().any(|listener| listener.tls);
+
+        if settings.redirect_https && !any_tls {
+            errors.push("--redirect-https needs at least one TLS listener".to_string());
+        }
+
+        if settings.redirect_https && matches!(mode, Mode::ReverseProxy { .. }) {
+            errors.push("--redirect-https only applies to the file server, not --proxy".to_string());
+        }
+
+        let auth_token = match (&settings.auth_token, settings.auth) {
+            (Some(token), _) if token.is_empty() => {
+                errors.push("--auth-token must not be empty".to_string());
+                None
+            }
+            (Some(token), _) => Some(token.clone()),
+            (None, true) => Some(generate_token()),
+            (None, false) => None,
+        };
+
+        if auth_token.is_some() && matches!(mode, Mode::ReverseProxy { .. }) {
+            errors.push("--auth/--auth-token only apply to the file server, not --proxy".to_string());
+        }
+
+        if settings.audit_log.is_some() && matches!(mode, Mode::ReverseProxy { .. }) {
+            errors.push("--audit-log only applies to the file server, not --proxy".to_string());
+        }
+
+        if custom_tls.is_some() && !any_tls {
+            errors.push("--priv/--cert/--pkcs12 given but every listener is plain".to_string());
+        }
+
+        if let Some(config) = &acme {
+            if !any_tls {
+                errors.push("--acme given but every listener is plain".to_string());
+            }
+            // File server TLS listeners answer plain HTTP too, proxy ones do not.
+            let proxy = matches!(mode, Mode::ReverseProxy { .. });
+            if config.challenge == ChallengeType::Http01 && proxy && listeners.iter().all(|listener| listener.tls) {
+                errors.push("--acme-challenge http-01 with --proxy needs a plain listener, which the CA reaches on port 80".to_string());
+            }
+        }
+
+        let certificate_options = [
+            ("--key-type", settings.key_type != Settings::default().key_type),
+            ("--san", !settings.san.is_empty()),
+            ("--cert-org", settings.cert_org.is_some()),
+            ("--cert-org-unit", settings.cert_org_unit.is_some()),
+            ("--cert-country", settings.cert_country.is_some()),
+            ("--cert-not-before", settings.cert_not_before.is_some()),
+            ("--cert-serial", settings.cert_serial.is_some()),
+            ("--cert-store", settings.cert_store.is_some()),
+            ("--ca", settings.ca.is_some()),
+        ];
+        for (flag, _) in certificate_options.iter().filter(|(_, given)| *given) {
+            if custom_tls.is_some() {
+                errors.push(format!("{} only applies to generated certificates, not --priv/--cert/--pkcs12", flag));
+            } else if acme.is_some() && *flag != "--key-type" {
+                errors.push(format!("{} only applies to generated certificates, not --acme", flag));
+            } else if !any_tls {
+                errors.push(format!("{} given but every listener is plain", flag));
+            }
+        }
+
+        if settings.ca.is_some() {
+            for (flag, given) in [("--cert-store", settings.cert_store.is_some()), ("--cert-serial", settings.cert_serial.is_some())] {
+                if given {
+                    errors.push(format!("{} cannot be combined with --ca, which mints a leaf per name", flag));
+                }
+            }
+        }
+
+        let tls = match (custom_tls, acme) {
+            (Some(material), _) => Some(material),
+            (None, Some(config)) => Some(TlsMaterial::Acme { config: Box::new(config) }),
+            (None, None) if any_tls => {
+                let spec = Box::new(resolve_certificate(settings, &listeners, &mut errors));
+                Some(match &settings.ca {
+                    Some(dir) => TlsMaterial::Authority { dir: dir.clone(), spec },
+                    None => TlsMaterial::Generated { spec, store: settings.cert_store.clone() },
+                })
+            }
+            (None, None) => None,
+        };
+
+        let client_auth = resolve_client_auth(settings, &mode, &listeners, tls.as_ref(), &mut errors);
+        let upstream = resolve_upstream(settings, &mode, &mut errors);
+
+        if settings.tui && !io::stdout().is_terminal() {
+            errors.push("--tui needs a terminal on stdout".to_string());
+        }
+
+        let log = resolve_log(settings, &mut errors);
+
+        if !errors.is_empty() {
+            return Err(PlanErrors(errors));
+        }
+
+        Ok(RunPlan {
+            mode,
+            listeners,
+            admin,
+            tls,
+            client_auth,
+            upstream,
+            bind: BindOptions {
+                default_port: settings.port,
+                dual_stack: settings.dual_stack,
+                interface: settings.interface.clone(),
+            },
+            redirect_https: settings.redirect_https,
+            auth_token,
+            ready_file: settings.ready_file.clone(),
+            audit_log: settings.audit_log.clone(),
+            shutdown_timeout: Duration::from_secs(settings.shutdown_timeout),
+            log,
+            tui: settings.tui,
+        })
+    }
+
+    /// The admin listener ignores `--interface`, which usually names the public side.
+    pub fn admin_bind(&self) -> BindOptions {
+        BindOptions { default_port: 0, dual_stack: self.bind.dual_stack, interface: None }
+    }
+
+    /// Loads TLS material, resolves the upstream and test-binds every TCP listener without
+    /// serving anything.
+    pub async fn check(&self) -> Result<(), PlanErrors> {
+        let mut errors = Vec::new();
+
+        match &self.tls {
+            Some(material @ TlsMaterial::Files { private_key, cert, .. }) => {
+                if let Err(err) = material.load(self.client_auth.as_deref()) {
+                    errors.push(format!("TLS material {} / {}: {}", cert.display(), private_key.display(), err));
+                }
+            }
+            Some(material @ (TlsMaterial::Pkcs12 { .. } | TlsMaterial::Selected { .. })) => {
+                if let Err(err) = material.load(self.client_auth.as_deref()) {
+                    errors.push(format!("TLS material: {}", err));
+                }
+            }
+            Some(TlsMaterial::Authority { dir, .. }) => {
+                if let Err(err) = CertificateAuthority::load(dir) {
+                    errors.push(format!("CA {}: {}", dir.display(), err));
+                }
+            }
+            Some(TlsMaterial::Acme { config }) => {
+                if let Err(err) = config.check().await {
+                    errors.push(format!("ACME directory: {}", err));
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(client_auth) = &self.client_auth {
+            if let Err(err) = client_auth.verifier() {
+                errors.push(format!("--client-ca: {}", err));
+            }
+        }
+
+        if let Some(upstream) = &self.upstream {
+            if let Err(err) = upstream.connector() {
+                errors.push(format!("--upstream-verify {}: {}", upstream.verify, err));
+            }
+        }
+
+        if let Mode::ReverseProxy { target } = &self.mode {
+            let host_port = target.split_once("://").map(|(_, rest)| rest).unwrap_or(target);
+            match tokio::net::lookup_host(host_port).await {
+                Ok(mut addrs) => {
+                    if addrs.next().is_none() {
+                        errors.push(format!("upstream {} did not resolve", target));
+                    }
+                }
+                Err(err) => errors.push(format!("upstream {}: {}", target, err)),
+            }
+        }
+
+        if let Some(audit_log) = &self.audit_log {
+            let parent = audit_log.parent().filter(|parent| !parent.as_os_str().is_empty());
+            if parent.is_some_and(|parent| !parent.is_dir()) {
+                errors.push(format!("--audit-log {}: parent directory does not exist", audit_log.display()));
+            }
+        }
+
+        let admin_bind = self.admin_bind();
+        let public = self.listeners.iter().map(|listener| (&listener.address, &self.bind));
+        let admin = self.admin.iter().map(|address| (address, &admin_bind));
+
+        for (address, bind) in public.chain(admin) {
+            match address {
+                ListenAddr::Unix(path) => {
+                    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+                    if parent.is_some_and(|parent| !parent.is_dir()) {
+                        errors.push(format!("{}: parent directory does not exist", address));
+                    }
+                }
+                address => {
+                    if let Err(err) = Listener::bind(address, bind).await {
+                        errors.push(format!("cannot listen on {}: {}", address, err));
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PlanErrors(errors))
+        }
+    }
+}
+
+impl fmt::Display for RunPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.mode {
+            Mode::FileServer { directory } => writeln!(f, "  mode:     file server for {}", directory.display())?,
+            Mode::ReverseProxy { target } => writeln!(f, "  mode:     reverse proxy to {}", target)?,
+        }
+
+        for listener in &self.listeners {
+            let address = listener.address.with_default_port(self.bind.default_port);
+            let tls = if listener.tls { "tls" } else { "plain" };
+            writeln!(f, "  listen:   {} ({})", address, tls)?;
+        }
+
+        if let Some(interface) = &self.bind.interface {
+            writeln!(f, "  iface:    {}", interface)?;
+        }
+
+        if let Some(admin) = &self.admin {
+            writeln!(f, "  admin:    {} (metrics, health)", admin)?;
+        }
+
+        match &self.tls {
+            Some(TlsMaterial::Generated { spec, store }) => {
+                let mut subject = format!("CN={}", spec.common_name);
+                for (key, value) in [("O", &spec.organization), ("OU", &spec.organizational_unit), ("C", &spec.country)] {
+                    if let Some(value) = value {
+                        subject.push_str(&format!(", {}={}", key, value));
+                    }
+                }
+                let sans: Vec<String> = spec.subject_alt_names.iter().map(ToString::to_string).collect();
+
+                writeln!(f, "  tls:      self-signed {} certificate for {}", spec.key_type, subject)?;
+                writeln!(f, "  san:      {}", sans.join(", "))?;
+                writeln!(
+                    f,
+                    "  validity: {} days from {}",
+                    spec.validity.as_secs() / (24 * 3600),
+                    humantime::format_rfc3339_seconds(spec.not_before),
+                )?;
+                if let Some(store) = store {
+                    writeln!(f, "  store:    {} (reused until close to expiry or the names change)", store.display())?;
+                }
+            }
+            Some(TlsMaterial::Files { private_key, cert, .. }) => writeln!(f, "  tls:      {} / {}", cert.display(), private_key.display())?,
+            Some(TlsMaterial::Pkcs12 { bundle, .. }) => writeln!(f, "  tls:      PKCS#12 bundle {}", bundle.display())?,
+            Some(TlsMaterial::Selected { certificates, default, .. }) => {
+                writeln!(f, "  tls:      {} certificates selected by SNI", certificates.len())?;
+                for (index, certificate) in certificates.iter().enumerate() {
+                    let name = certificate.name.as_deref().unwrap_or("names from the certificate");
+                    let default = if index == *default { ", default" } else { "" };
+                    writeln!(
+                        f,
+                        "  sni:      {} ({}{}): {} / {}",
+                        certificate.label,
+                        name,
+                        default,
+                        certificate.cert.display(),
+                        certificate.private_key.display(),
+                    )?;
+                }
+            }
+            Some(TlsMaterial::Authority { dir, spec }) => {
+                let sans: Vec<String> = spec.subject_alt_names.iter().map(ToString::to_string).collect();
+
+                writeln!(f, "  tls:      {} leaf per SNI name, signed by the CA in {}", spec.key_type, dir.display())?;
+                writeln!(f, "  san:      {} (clients without SNI)", sans.join(", "))?;
+            }
+            Some(TlsMaterial::Acme { config }) => {
+                writeln!(f, "  tls:      {} certificate from {} ({})", config.key_type, config.directory, config.challenge)?;
+                writeln!(f, "  san:      {}", config.domains.join(", "))?;
+                writeln!(f, "  store:    {} (renewed a third of its lifetime before expiry)", config.store.display())?;
+            }
+            None => writeln!(f, "  tls:      off")?,
+        }
+
+        if let Some(client_auth) = &self.client_auth {
+            writeln!(f, "  clients:  certificates from {} {}", client_auth.roots.display(), client_auth.mode)?;
+            if client_auth.mappings.is_empty() {
+                writeln!(f, "  users:    each certificate's common name, read-write")?;
+            } else {
+                writeln!(f, "  users:    {} --client-user mapping(s), other certificates refused", client_auth.mappings.len())?;
+            }
+        }
+
+        if let Some(upstream) = &self.upstream {
+            let hostname = if upstream.check_hostname { "checked" } else { "not checked" };
+            match &upstream.verify {
+                UpstreamVerify::None => writeln!(f, "  upstream: certificate not verified, SNI {}", upstream.server_name)?,
+                UpstreamVerify::Pin(pins) => writeln!(f, "  upstream: public key pinned ({} pin(s)), SNI {}", pins.len(), upstream.server_name)?,
+                UpstreamVerify::System => writeln!(f, "  upstream: verified against system roots, name {} {}", upstream.server_name, hostname)?,
+                UpstreamVerify::Ca(path) => writeln!(f, "  upstream: verified against {}, name {} {}", path.display(), upstream.server_name, hostname)?,
+            }
+        }
+
+        if self.redirect_https {
+            writeln!(f, "  redirect: plain HTTP to HTTPS")?;
+        }
+
+        if self.auth_token.is_some() {
+            writeln!(f, "  auth:     token required")?;
+        }
+
+        if let Some(audit_log) = &self.audit_log {
+            writeln!(f, "  audit:    {}", audit_log.display())?;
+        }
+
+        if self.tui {
+            writeln!(f, "  log:      {} (dashboard shows warnings and errors)", self.log.filter)?;
+        } else {
+            writeln!(f, "  log:      {} ({})", self.log.filter, self.log.console_format)?;
+        }
+        if let Some(file) = &self.log.file {
+            writeln!(f, "  log file: {} ({}, rotated {}, keep {})", file.path.display(), file.format, file.rotation, file.max_files)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether binding `admin` could take over the port of the public listener.
+fn shares_port(public: &ListenAddr, admin: &ListenAddr, default_port: u16) -> bool {
+    let is_wildcard = |host: &str| host == "0.0.0.0" || host == "::";
+
+    match (public, admin) {
+        (ListenAddr::Unix(public), ListenAddr::Unix(admin)) => public == admin,
+        (ListenAddr::Tcp { host: public_host, port: public_port }, ListenAddr::Tcp { host, port: Some(port) }) => {
+            *port != 0
+                && public_port.unwrap_or(default_port) == *port
+                && (public_host == host || is_wildcard(public_host) || is_wildcard(host))
+        }
+        _ => false,
+    }
+}
+
+/// `name=cert.pem,key.pem` from `--cert`, with the `name=` already split off.
+fn named_certificate(name: &str, files: &str) -> Result<NamedCertificate, String> {
+    let name = name.trim();
+    name.parse::<SubjectAltName>()?;
+    let (cert, private_key) = files.split_once(',').ok_or("expected name=cert.pem,key.pem")?;
+
+    Ok(NamedCertificate {
+        label: name.to_string(),
+        name: Some(name.to_ascii_lowercase()),
+        cert: PathBuf::from(cert.trim()),
+        private_key: PathBuf::from(private_key.trim()),
+    })
+}
+
+/// Every `<name>.pem` or `<name>.crt` with a `<name>.key` next to it, by name.
+fn certificate_dir(dir: &Path) -> Result<Vec<NamedCertificate>, String> {
+    let mut certificates: Vec<NamedCertificate> = fs::read_dir(dir)
+        .map_err(|err| err.to_string())?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "pem" || extension == "crt"))
+        .filter_map(|cert| {
+            let private_key = cert.with_extension("key");
+            let label = cert.file_stem()?.to_str()?.to_string();
+            private_key.is_file().then_some(NamedCertificate { label, name: None, cert, private_key })
+        })
+        .collect();
+    certificates.sort_by(|a, b| a.label.cmp(&b.label));
+
+    if certificates.is_empty() {
+        return Err("no <name>.pem or <name>.crt with a matching <name>.key".to_string());
+    }
+    Ok(certificates)
+}
+
+fn load_selected(
+    certificates: &[NamedCertificate],
+    default: usize,
+    passphrase: Option<&str>,
+    clients: Arc<dyn ClientCertVerifier>,
+) -> Result<LoadedTls, Box<dyn Error>> {
+    let mut loaded = Vec::new();
+    for (index, certificate) in certificates.iter().enumerate() {
+        let (chain, private_key) = load_tls_files(&certificate.private_key, &certificate.cert, passphrase)?;
+        let cert = &chain[0];
+
+        let names = match &certificate.name {
+            Some(name) => vec![name.clone()],
+            None => certificate_subject_alt_names(cert)?
+                .into_iter()
+                .filter_map(|name| match name {
+                    SubjectAltName::Dns(name) => Some(name),
+                    SubjectAltName::Ip(_) => None,
+                })
+                .collect(),
+        };
+        if names.is_empty() && index != default {
+            return Err(format!("{} has no DNS names to select it by and is not the default", certificate.cert.display()).into());
+        }
+
+        let fingerprint_sha256 = sha256_fingerprint(cert);
+        let spki_sha256 = spki_fingerprint(cert)?;
+        loaded.push((names, certified_key(chain, private_key)?, fingerprint_sha256, spki_sha256));
+    }
+
+    let (_, default_key, fingerprint_sha256, spki_sha256) = &loaded[default];
+    let mut resolver = SniResolver::new(default_key.clone());
+    for (names, certified, _, _) in &loaded {
+        for name in names {
+            resolver.add(name, certified.clone());
+        }
+    }
+
+    Ok(LoadedTls {
+        acceptor: resolving_tls_acceptor(Arc::new(resolver), clients),
+        fingerprint_sha256: fingerprint_sha256.clone(),
+        spki_sha256: spki_sha256.clone(),
+        from_authority: false,
+        leaf: None,
+        acme: None,
+    })
+}
+
+fn resolve_acme(settings: &Settings, errors: &mut Vec<String>) -> AcmeConfig {
+    let mut domains: Vec<String> = Vec::new();
+    for domain in settings.acme.iter().map(|domain| domain.trim().to_ascii_lowercase()) {
+        if domain.starts_with("*.") {
+            errors.push(format!("--acme {}: wildcard certificates need the DNS-01 challenge, which droppa cannot answer", domain));
+        } else {
+            match domain.parse::<SubjectAltName>() {
+                Ok(SubjectAltName::Dns(_)) if !domains.contains(&domain) => domains.push(domain),
+                Ok(SubjectAltName::Dns(_)) => {}
+                Ok(SubjectAltName::Ip(_)) => errors.push(format!("--acme {}: ACME certificates name domains, not IP addresses", domain)),
+                Err(err) => errors.push(format!("--acme: {}", err)),
+            }
+        }
+    }
+
+    match Url::parse(&settings.acme_directory) {
+        Ok(url) if url.scheme() == "https" => {}
+        Ok(_) => errors.push(format!("--acme-directory {}: must be an https URL", settings.acme_directory)),
+        Err(err) => errors.push(format!("--acme-directory {}: {}", settings.acme_directory, err)),
+    }
+
+    let challenge = settings.acme_challenge.parse().unwrap_or_else(|err| {
+        errors.push(format!("--acme-challenge: {}", err));
+        ChallengeType::TlsAlpn01
+    });
+
+    let key_type = match settings.key_type.parse() {
+        Ok(KeyType::Ed25519) => {
+            errors.push("--key-type ed25519: ACME CAs do not issue Ed25519 certificates".to_string());
+            KeyType::EcdsaP256
+        }
+        Ok(key_type) => key_type,
+        Err(err) => {
+            errors.push(format!("--key-type: {}", err));
+            KeyType::Rsa2048
+        }
+    };
+
+    let store = settings.acme_store.clone().unwrap_or_else(|| {
+        errors.push("--acme needs --acme-store <dir> to keep the account and certificates across restarts".to_string());
+        PathBuf::new()
+    });
+
+    AcmeConfig {
+        directory: settings.acme_directory.clone(),
+        domains,
+        contact: settings.acme_email.clone(),
+        store,
+        challenge,
+        roots: settings.acme_root.clone(),
+        key_type,
+    }
+}
+
+fn resolve_client_auth(
+    settings: &Settings,
+    mode: &Mode,
+    listeners: &[PlannedListener],
+    tls: Option<&TlsMaterial>,
+    errors: &mut Vec<String>,
+) -> Option<Arc<ClientAuth>> {
+    let Some(roots) = &settings.client_ca else {
+        if settings.client_auth != Settings::default().client_auth {
+            errors.push("--client-auth needs --client-ca".to_string());
+        }
+        if !settings.client_user.is_empty() {
+            errors.push("--client-user needs --client-ca".to_string());
+        }
+        return None;
+    };
+
+    let client_mode = settings.client_auth.parse().unwrap_or_else(|err| {
+        errors.push(format!("--client-auth: {}", err));
+        ClientAuthMode::Required
+    });
+
+    let mappings: Vec<ClientMapping> = settings
+        .client_user
+        .iter()
+        .filter_map(|mapping| mapping.parse().map_err(|err| errors.push(format!("--client-user {}: {}", mapping, err))).ok())
+        .collect();
+
+    if tls.is_none() {
+        errors.push("--client-ca given but every listener is plain".to_string());
+    }
+
+    if let Mode::ReverseProxy { .. } = mode {
+        if settings.client_user.iter().any(|mapping| mapping.rsplit_once('=').is_some_and(|(_, target)| target.contains(':'))) {
+            errors.push("--client-user roles only apply to the file server, not --proxy".to_string());
+        }
+        // The file server refuses plain requests itself; the proxy would relay them.
+        if client_mode == ClientAuthMode::Required {
+            for listener in listeners.iter().filter(|listener| !listener.tls) {
+                errors.push(format!("--client-auth required: plain listener {} would let clients without a certificate through --proxy", listener.address));
+            }
+        }
+    }
+
+    if client_mode == ClientAuthMode::Required && matches!(tls, Some(TlsMaterial::Acme { config }) if config.challenge == ChallengeType::TlsAlpn01) {
+        errors.push("--client-auth required turns away the CA's tls-alpn-01 handshake, use --acme-challenge http-01".to_string());
+    }
+
+    Some(Arc::new(ClientAuth { roots: roots.clone(), mode: client_mode, mappings }))
+}
+
+/// The upstream's TLS settings when `--proxy` targets `https://`. The `--upstream-*` flags are
+/// rejected anywhere else, where they would silently do nothing.
+fn resolve_upstream(settings: &Settings, mode: &Mode, errors: &mut Vec<String>) -> Option<UpstreamTls> {
+    let verify = settings.upstream_verify.parse().unwrap_or_else(|err| {
+        errors.push(format!("--upstream-verify: {}", err));
+        UpstreamVerify::None
+    });
+
+    let host = match mode {
+        Mode::ReverseProxy { target } if target.starts_with("https://") => Url::parse(target).ok().and_then(|url| match url.host()? {
+            Host::Domain(domain) => Some(domain.to_string()),
+            Host::Ipv4(ip) => Some(ip.to_string()),
+            Host::Ipv6(ip) => Some(ip.to_string()),
+        }),
+        _ => None,
+    };
+    let Some(host) = host else {
+        if verify != UpstreamVerify::None {
+            errors.push("--upstream-verify only applies to --proxy https://...".to_string());
+        }
+        if settings.upstream_name.is_some() {
+            errors.push("--upstream-name only applies to --proxy https://...".to_string());
+        }
+        if settings.upstream_no_hostname_check {
+            errors.push("--upstream-no-hostname-check only applies to --proxy https://...".to_string());
+        }
+        return None;
+    };
+
+    if settings.upstream_no_hostname_check && !matches!(verify, UpstreamVerify::System | UpstreamVerify::Ca(_)) {
+        errors.push(format!("--upstream-no-hostname-check has no effect with --upstream-verify {}", verify));
+    }
+
+    let check_hostname = matches!(verify, UpstreamVerify::System | UpstreamVerify::Ca(_)) && !settings.upstream_no_hostname_check;
+    let server_name = settings.upstream_name.clone().unwrap_or(host);
+    if ServerName::try_from(server_name.as_str()).is_err() {
+        errors.push(format!("--upstream-name {}: not a valid DNS name or IP address", server_name));
+    }
+
+    Some(UpstreamTls { verify, server_name, check_hostname })
+}
+
+/// Starting the certificate a little in the past keeps targets with a lagging clock happy.
+const CERT_BACKDATE: Duration = Duration::from_secs(3600);
+
+/// 9999-12-31T23:59:59Z since the epoch, the last time an X.509 certificate can express.
+const LATEST_NOT_AFTER: Duration = Duration::from_secs(253_402_300_799);
+
+fn resolve_certificate(settings: &Settings, listeners: &[PlannedListener], errors: &mut Vec<String>) -> CertificateSpec {
+    let subject_alt_names = if settings.san.is_empty() {
+        default_subject_alt_names(settings, listeners)
+    } else {
+        settings
+            .san
+            .iter()
+            .filter_map(|san| san.parse::<SubjectAltName>().map_err(|err| errors.push(format!("--san: {}", err))).ok())
+            .collect()
+    };
+
+    let key_type = settings.key_type.parse().unwrap_or_else(|err| {
+        errors.push(format!("--key-type: {}", err));
+        KeyType::Rsa2048
+    });
+
+    let country = settings.cert_country.as_ref().map(|country| {
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            errors.push(format!("--cert-country {}: expected a two-letter country code", country));
+        }
+        country.to_ascii_uppercase()
+    });
+
+    let not_before = match settings.cert_not_before.as_deref() {
+        Some(time) => humantime::parse_rfc3339_weak(time).unwrap_or_else(|err| {
+            errors.push(format!("--cert-not-before {}: {}", time, err));
+            SystemTime::now()
+        }),
+        None => SystemTime::now() - CERT_BACKDATE,
+    };
+
+    if settings.cert_valid_days == 0 {
+        errors.push("--cert-valid-days must be at least 1".to_string());
+    }
+    let validity = settings
+        .cert_valid_days
+        .checked_mul(24 * 3600)
+        .map(Duration::from_secs)
+        .filter(|validity| not_before.checked_add(*validity).is_some_and(|not_after| not_after <= SystemTime::UNIX_EPOCH + LATEST_NOT_AFTER))
+        .unwrap_or_else(|| {
+            errors.push(format!("--cert-valid-days {} runs past the year 9999", settings.cert_valid_days));
+            Duration::ZERO
+        });
+
+    let serial = settings.cert_serial.as_deref().and_then(|serial| {
+        parse_serial(serial).map_err(|err| errors.push(format!("--cert-serial: {}", err))).ok()
+    });
+
+    let key_usages = settings
+        .key_usage
+        .iter()
+        .filter_map(|usage| usage.parse().map_err(|err| errors.push(format!("--key-usage: {}", err))).ok())
+        .collect();
+    let extended_key_usages = settings
+        .ext_key_usage
+        .iter()
+        .filter_map(|usage| usage.parse().map_err(|err| errors.push(format!("--ext-key-usage: {}", err))).ok())
+        .collect();
+
+    CertificateSpec {
+        key_type,
+        common_name: settings.common_name.clone(),
+        organization: settings.cert_org.clone(),
+        organizational_unit: settings.cert_org_unit.clone(),
+        country,
+        subject_alt_names,
+        not_before,
+        validity,
+        serial,
+        key_usages,
+        extended_key_usages,
+    }
+}
+
+/// The common name, this host's name and every address a TLS listener answers on, so the
+/// certificate matches however a client reaches us.
+fn default_subject_alt_names(settings: &Settings, listeners: &[PlannedListener]) -> Vec<SubjectAltName> {
+    let mut names: Vec<SubjectAltName> = Vec::new();
+    let mut add = |name: SubjectAltName| {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    };
+
+    if let Ok(name) = settings.common_name.parse() {
+        add(name);
+    }
+    if let Some(name) = hostname::get().ok().and_then(|name| name.to_str().and_then(|name| name.parse().ok())) {
+        add(name);
+    }
+
+    for listener in listeners.iter().filter(|listener| listener.tls) {
+        let ListenAddr::Tcp { host, .. } = &listener.address else { continue };
+        match host.parse::<IpAddr>() {
+            Ok(ip) => bound_addresses(ip, settings.dual_stack, settings.interface.as_deref())
+                .into_iter()
+                .for_each(|ip| add(SubjectAltName::Ip(ip))),
+            Err(_) => {
+                if let Ok(name) = host.parse() {
+                    add(name);
+                }
+            }
+        }
+    }
+
+    names
+}
+
+fn resolve_log(settings: &Settings, errors: &mut Vec<String>) -> LogOptions {
+    if !LEVELS.contains(&settings.log_level.as_str()) {
+        errors.push(format!("--log-level {}: expected one of {}", settings.log_level, LEVELS.join(", ")));
+    }
+
+    let mut filter = verbose_level(&settings.log_level, settings.verbose).to_string();
+    if let Some(directives) = settings.log_filter.as_deref().filter(|directives| !directives.is_empty()) {
+        filter = format!("{},{}", filter, directives);
+        if let Err(err) = EnvFilter::try_new(&filter) {
+            errors.push(format!("--log-filter {}: {}", directives, err));
+        }
+    }
+
+    let mut format = |value: &str, flag: &str| {
+        value.parse::<LogFormat>().unwrap_or_else(|err| {
+            errors.push(format!("--{}: {}", flag, err));
+            LogFormat::Human
+        })
+    };
+    let console_format = format(&settings.log_format, "log-format");
+    let file_format = format(&settings.log_file_format, "log-file-format");
+
+    let rotation = settings.log_rotation.parse::<LogRotation>().unwrap_or_else(|err| {
+        errors.push(format!("--log-rotation: {}", err));
+        LogRotation::Never
+    });
+
+    LogOptions {
+        filter,
+        console_format,
+        quiet: settings.quiet,
+        console: !settings.tui,
+        file: settings.log_file.clone().map(|path| LogFile {
+            path,
+            format: file_format,
+            rotation,
+            max_files: settings.log_max_files,
+        }),
+    }
+}
+
+/// Accepts `http(s)://host[:port]` and spells the port out, which is what the proxy dials.
+fn normalize_target(target: &str) -> Result<String, String> {
+    let url = Url::parse(target).map_err(|e| format!("--proxy {}: {}", target, e))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("--proxy {}: scheme must be http or https", target));
+    }
+
+    let host = url.host_str().ok_or_else(|| format!("--proxy {}: missing host", target))?;
+
+    if url.path() != "/" || url.query().is_some() {
+        return Err(format!("--proxy {}: paths are not supported, give only scheme, host and port", target));
+    }
+
+    let port = url.port_or_known_default().ok_or_else(|| format!("--proxy {}: missing port", target))?;
+
+    Ok(format!("{}://{}:{}", url.scheme(), host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings { listen: vec!["127.0.0.1:0".parse().unwrap()], ..Settings::default() }
+    }
+
+    fn errors(settings: &Settings) -> Vec<String> {
+        match RunPlan::resolve(settings) {
+            Ok(_) => Vec::new(),
+            Err(PlanErrors(errors)) => errors,
+        }
+    }
+
+    #[test]
+    fn plain_file_server_resolves() {
+        assert_eq!(errors(&settings()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn key_pair_halves_are_refused_alone() {
+        let cert = Settings { cert: vec!["server.pem".to_string()], ..settings() };
+        assert!(errors(&cert).contains(&"--cert needs a matching --priv".to_string()));
+
+        let private_key = Settings { private_key: Some(PathBuf::from("server.key")), ..settings() };
+        assert!(errors(&private_key).contains(&"--priv needs a matching --cert".to_string()));
+    }
+
+    #[test]
+    fn file_server_options_are_refused_with_proxy() {
+        let proxy = Settings { proxy: Some("http://127.0.0.1:8080".to_string()), auth: true, redirect_https: true, ..settings() };
+        let errors = errors(&proxy);
+        assert!(errors.contains(&"--auth/--auth-token only apply to the file server, not --proxy".to_string()));
+        assert!(errors.contains(&"--redirect-https only applies to the file server, not --proxy".to_string()));
+    }
+
+    #[test]
+    fn redirect_https_needs_a_tls_listener() {
+        let redirect = Settings { redirect_https: true, ..settings() };
+        assert!(errors(&redirect).contains(&"--redirect-https needs at least one TLS listener".to_string()));
+    }
+
+    #[test]
+    fn proxy_target_must_be_http() {
+        let proxy = Settings { proxy: Some("ftp://127.0.0.1".to_string()), ..settings() };
+        assert!(errors(&proxy).iter().any(|error| error.starts_with("--proxy ftp://127.0.0.1")));
+    }
+
+    #[test]
+    fn certificate_validity_is_bounded() {
+        let zero = Settings { tls: true, cert_valid_days: 0, ..settings() };
+        assert!(errors(&zero).contains(&"--cert-valid-days must be at least 1".to_string()));
+
+        for days in [u64::MAX, 3_000_000] {
+            let huge = Settings { tls: true, cert_valid_days: days, ..settings() };
+            assert!(errors(&huge).contains(&format!("--cert-valid-days {} runs past the year 9999", days)));
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+use crate::config::cli;
+use crate::transport::listener::ListenerSpec;
+
+const ENV_PREFIX: &str = "DROPPA_";
+
+/// Settings `--print-config` does not show.
+const SECRET_KEYS: &[&str] = &["key-passphrase", "pkcs12-pass", "auth-token"];
+const REDACTED: &str = "<redacted>";
+
+/// Keys that were renamed, old name first. Config files and environments using the old
+/// name keep working.
+const RENAMED_KEYS: &[(&str, &str)] = &[("issuer", "common-name")];
+
+/// Keys that became repeatable. A single string in a config file still works.
+const WIDENED_KEYS: &[&str] = &["cert"];
+
+/// Effective settings after merging every source. Keys mirror the long CLI flags.
+///
+/// Precedence, lowest to highest: built-in defaults, top-level keys of the `--config` file,
+/// the selected `[profile.<name>]` table, `DROPPA_*` environment variables, command-line flags.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    pub listen: Vec<ListenerSpec>,
+    pub dual_stack: bool,
+    pub interface: Option<String>,
+    pub port: u16,
+    pub directory: PathBuf,
+    pub tls: bool,
+    pub key_type: String,
+    pub common_name: String,
+    pub san: Vec<String>,
+    pub cert_org: Option<String>,
+    pub cert_org_unit: Option<String>,
+    pub cert_country: Option<String>,
+    pub cert_not_before: Option<String>,
+    pub cert_valid_days: u64,
+    pub cert_serial: Option<String>,
+    pub cert_store: Option<PathBuf>,
+    pub ca: Option<PathBuf>,
+    pub key_usage: Vec<String>,
+    pub ext_key_usage: Vec<String>,
+    pub proxy: Option<String>,
+    #[serde(rename = "priv")]
+    pub private_key: Option<PathBuf>,
+    pub cert: Vec<String>,
+    pub cert_dir: Option<PathBuf>,
+    pub default_cert: Option<String>,
+    pub key_passphrase: Option<String>,
+    pub pkcs12: Option<PathBuf>,
+    pub pkcs12_pass: Option<String>,
+    pub acme: Vec<String>,
+    pub acme_directory: String,
+    pub acme_email: Option<String>,
+    pub acme_store: Option<PathBuf>,
+    pub acme_challenge: String,
+    pub acme_root: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: String,
+    pub client_user: Vec<String>,
+    pub upstream_verify: String,
+    pub upstream_name: Option<String>,
+    pub upstream_no_hostname_check: bool,
+    pub redirect_https: bool,
+    pub auth: bool,
+    pub auth_token: Option<String>,
+    pub ready_file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub admin_listen: Option<String>,
+    pub shutdown_timeout: u64,
+    pub log_level: String,
+    pub log_filter: Option<String>,
+    pub log_format: String,
+    pub log_file: Option<PathBuf>,
+    pub log_file_format: String,
+    pub log_rotation: String,
+    pub log_max_files: usize,
+    pub quiet: bool,
+    pub verbose: u8,
+    pub tui: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            dual_stack: false,
+            interface: None,
+            port: 8000,
+            directory: PathBuf::from("."),
+            tls: false,
+            key_type: "rsa2048".to_string(),
+            common_name: "getrekt.com".to_string(),
+            san: Vec::new(),
+            cert_org: None,
+            cert_org_unit: None,
+            cert_country: None,
+            cert_not_before: None,
+            cert_valid_days: 365,
+            cert_serial: None,
+            cert_store: None,
+            ca: None,
+            key_usage: vec!["digital-signature".to_string(), "key-encipherment".to_string()],
+            ext_key_usage: vec!["server-auth".to_string()],
+            proxy: None,
+            private_key: None,
+            cert: Vec::new(),
+            cert_dir: None,
+            default_cert: None,
+            key_passphrase: None,
+            pkcs12: None,
+            pkcs12_pass: None,
+            acme: Vec::new(),
+            acme_directory: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            acme_email: None,
+            acme_store: None,
+            acme_challenge: "tls-alpn-01".to_string(),
+            acme_root: None,
+            client_ca: None,
+            client_auth: "required".to_string(),
+            client_user: Vec::new(),
+            upstream_verify: "none".to_string(),
+            upstream_name: None,
+            upstream_no_hostname_check: false,
+            redirect_https: false,
+            auth: false,
+            auth_token: None,
+            ready_file: None,
+            audit_log: None,
+            admin_listen: None,
+            shutdown_timeout: 30,
+            log_level: "info".to_string(),
+            log_filter: None,
+            log_format: "human".to_string(),
+            log_file: None,
+            log_file_format: "json".to_string(),
+            log_rotation: "daily".to_string(),
+            log_max_files: 7,
+            quiet: false,
+            verbose: 0,
+            tui: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(matches: &ArgMatches) -> Result<Self, Box<dyn Error>> {
+        let defaults = Table::try_from(Settings::default())?;
+        let mut merged = defaults.clone();
+
+        let config_path = matches.get_one::<String>("config").cloned().or_else(|| env_var("CONFIG"));
+        let profile = matches.get_one::<String>("profile").cloned().or_else(|| env_var("PROFILE"));
+
+        match (config_path, profile) {
+            (Some(config_path), profile) => {
+                let content = fs::read_to_string(&config_path)
+                    .map_err(|e| format!("Failed to read config {}: {}", config_path, e))?;
+                let mut file: Table = content.parse()
+                    .map_err(|e| format!("Failed to parse config {}: {}", config_path, e))?;
+
+                let mut profiles = match file.remove("profile") {
+                    Some(Value::Table(profiles)) => profiles,
+                    Some(_) => return Err(format!("{}: 'profile' must be a table of [profile.<name>] sections", config_path).into()),
+                    None => Table::new(),
+                };
+
+                merged.extend(upgraded(file));
+
+                if let Some(profile) = profile {
+                    match profiles.remove(&profile) {
+                        Some(Value::Table(profile)) => merged.extend(upgraded(profile)),
+                        _ => return Err(format!("{}: no [profile.{}] section", config_path, profile).into()),
+                    }
+                }
+            }
+            (None, Some(profile)) => return Err(format!("--profile {} needs a --config file", profile).into()),
+            (None, None) => {}
+        }
+
+        let command = cli::command();
+        let keys: Vec<String> = command
+            .get_arguments()
+            .map(|arg| arg.get_id().to_string())
+            .filter(|id| !cli::META_ARGS.contains(&id.as_str()))
+            .collect();
+
+        for arg in command.get_arguments().filter(|arg| !cli::META_ARGS.contains(&arg.get_id().as_str())) {
+            let key = arg.get_id().as_str();
+            let old_names = RENAMED_KEYS.iter().filter(|(_, new)| *new == key).map(|(old, _)| env_name(old));
+            let found = std::iter::once(env_name(key))
+                .chain(old_names)
+                .find_map(|name| std::env::var(&name).ok().map(|raw| (name, raw)));
+            if let Some((name, raw)) = found {
+                let raw: Vec<&str> = match defaults.get(key) {
+                    Some(Value::Array(_)) => split_list(&raw, arg.get_value_delimiter()),
+                    _ => vec![raw.as_str()],
+                };
+                merged.insert(key.to_string(), coerce(&defaults, key, &raw).map_err(|e| format!("{}: {}", name, e))?);
+            }
+        }
+
+        for key in &keys {
+            if matches.value_source(key) != Some(ValueSource::CommandLine) {
+                continue;
+            }
+
+            if let Some(raw) = matches.get_raw(key) {
+                let raw: Vec<&str> = raw.filter_map(|value| value.to_str()).collect();
+                merged.insert(key.clone(), coerce(&defaults, key, &raw).map_err(|e| format!("--{}: {}", key, e))?);
+            }
+        }
+
+        let mut settings: Settings = Value::Table(merged).try_into()
+            .map_err(|e| format!("Invalid configuration: {}", e))?;
+
+        if settings.listen.is_empty() {
+            let default_listen = if settings.dual_stack { "[::]" } else { "0.0.0.0" };
+            settings.listen.push(default_listen.parse()?);
+        }
+
+        Ok(settings)
+    }
+
+    /// The merged settings for `--print-config`, with secrets replaced by `<redacted>`.
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error>> {
+        let mut table = Table::try_from(self)?;
+        for key in SECRET_KEYS {
+            if let Some(value) = table.get_mut(*key) {
+                *value = Value::String(REDACTED.to_string());
+            }
+        }
+        Ok(toml::to_string(&table)?)
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase().replace('-', "_"))
+}
+
+/// Brings a config file table written for an older droppa up to the current keys.
+fn upgraded(mut table: Table) -> Table {
+    for (old, new) in RENAMED_KEYS {
+        if let Some(value) = table.remove(*old) {
+            table.entry(new.to_string()).or_insert(value);
+        }
+    }
+    for key in WIDENED_KEYS {
+        if let Some(value @ Value::String(_)) = table.remove(*key) {
+            table.insert(key.to_string(), Value::Array(vec![value]));
+        }
+    }
+    table
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(format!("{}{}", ENV_PREFIX, name)).ok()
+}
+
+/// List values from the environment are split like the flag splits them: on commas for flags
+/// such as `--san`, otherwise on whitespace, since `--listen` and `--cert` values hold commas.
+fn split_list(raw: &str, delimiter: Option<char>) -> Vec<&str> {
+    match delimiter {
+        Some(delimiter) => raw.split(delimiter).map(str::trim).filter(|entry| !entry.is_empty()).collect(),
+        None => raw.split_whitespace().collect(),
+    }
+}
+
+/// Turns raw string values from the environment or the command line into the TOML type the
+/// default for `key` has, so `DROPPA_PORT=9000` lands as an integer. Keys without a default
+/// are taken as strings.
+fn coerce(defaults: &Table, key: &str, raw: &[&str]) -> Result<Value, String> {
+    let scalar = |template: Option<&Value>, raw: &str| -> Result<Value, String> {
+        match template {
+            Some(Value::Boolean(_)) => match raw.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(Value::Boolean(true)),
+                "0" | "false" | "no" | "off" => Ok(Value::Boolean(false)),
+                _ => Err(format!("expected a boolean, got '{}'", raw)),
+            },
+            Some(Value::Integer(_)) => raw
+                .parse::<i64>()
+                .map(Value::Integer)
+                .map_err(|_| format!("expected an integer, got '{}'", raw)),
+            _ => Ok(Value::String(raw.to_string())),
+        }
+    };
+
+    match defaults.get(key) {
+        Some(Value::Array(template)) => raw
+            .iter()
+            .map(|raw| scalar(template.first(), raw))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array),
+        template => scalar(template, raw.first().copied().unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_lists_split_like_their_flag() {
+        assert_eq!(split_list("a.test, b.test,,", Some(',')), vec!["a.test", "b.test"]);
+        assert_eq!(split_list("0.0.0.0:8443,tls  [::1]", None), vec!["0.0.0.0:8443,tls", "[::1]"]);
+    }
+
+    #[test]
+    fn print_config_redacts_secrets() {
+        let settings = Settings { auth_token: Some("hunter2".to_string()), pkcs12_pass: Some("hunter2".to_string()), ..Settings::default() };
+        let printed = settings.to_toml().unwrap();
+        assert!(!printed.contains("hunter2"));
+        assert!(printed.contains("auth-token = \"<redacted>\""));
+        assert!(!printed.contains("key-passphrase"));
+    }
+}
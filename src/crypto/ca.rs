@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose};
+use time::OffsetDateTime;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey, SigningKey};
+use tokio_rustls::rustls::{Certificate as RustlsCertificate, PrivateKey};
+use tracing::{debug, warn};
+
+use crate::crypto::certs::{issue_certificate, CertificateSpec, KeyType, SubjectAltName};
+use crate::crypto::keys::save_key_pair;
+use crate::crypto::tls::load_tls_files;
+
+const CERT_FILE: &str = "ca.pem";
+const KEY_FILE: &str = "ca-key.pem";
+
+const CA_COMMON_NAME: &str = "droppa local CA";
+const CA_VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 3600);
+
+/// SNI is up to the client, so stop caching past this many names rather than grow forever.
+const MAX_MINTED: usize = 1024;
+
+/// A persistent root that signs the leaf certificates droppa serves, so clients trust it once.
+pub struct CertificateAuthority {
+    signer: Certificate,
+    cert: Vec<u8>,
+}
+
+impl CertificateAuthority {
+    /// Creates the root in `dir`. An existing one is kept unless `force` is set, since
+    /// replacing it breaks every client that already trusts it.
+    pub fn init(dir: &Path, key_type: KeyType, force: bool) -> Result<Self, Box<dyn Error>> {
+        let cert_path = dir.join(CERT_FILE);
+        if cert_path.exists() && !force {
+            return Err(format!("{} already exists, pass --force to replace it", cert_path.display()).into());
+        }
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, CA_COMMON_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+        params.not_before = OffsetDateTime::now_utc() - time::Duration::hours(1);
+        params.not_after = OffsetDateTime::from(SystemTime::now() + CA_VALIDITY);
+        params.alg = key_type.algorithm();
+        params.key_pair = Some(key_type.generate()?);
+
+        let signer = Certificate::from_params(params)?;
+        let cert = signer.serialize_der()?;
+        let private_key = signer.serialize_private_key_der();
+
+        fs::create_dir_all(dir)?;
+        save_key_pair(&dir.join(KEY_FILE), &cert_path, &private_key, std::slice::from_ref(&cert))?;
+
+        Ok(Self { signer, cert })
+    }
+
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let (chain, private_key) = load_tls_files(&dir.join(KEY_FILE), &dir.join(CERT_FILE), None)
+            .map_err(|e| format!("{} (create it with `droppa ca init --ca {}`)", e, dir.display()))?;
+        let cert = chain.into_iter().next().ok_or("no root certificate")?;
+        let params = CertificateParams::from_ca_cert_der(&cert, KeyPair::from_der(&private_key)?)?;
+        let signer = Certificate::from_params(params)?;
+
+        Ok(Self { signer, cert })
+    }
+
+    /// The DER root certificate clients should import.
+    pub fn cert(&self) -> &[u8] {
+        &self.cert
+    }
+
+    pub fn cert_path(dir: &Path) -> PathBuf {
+        dir.join(CERT_FILE)
+    }
+}
+
+/// Serves a leaf for whatever name the client asks for via SNI, minted on first use and
+/// cached. Clients that send no SNI, such as those connecting by IP, get a leaf for the
+/// names of `spec`. All leaves share one key, so minting is a signature, not a key generation.
+pub struct MintingResolver {
+    authority: CertificateAuthority,
+    spec: CertificateSpec,
+    private_key: Vec<u8>,
+    signing_key: Arc<dyn SigningKey>,
+    default: Arc<CertifiedKey>,
+    minted: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl MintingResolver {
+    pub fn new(authority: CertificateAuthority, spec: CertificateSpec) -> Result<Self, Box<dyn Error>> {
+        let private_key = spec.key_type.generate()?.serialize_der();
+        let signing_key = any_supported_type(&PrivateKey(private_key.clone()))?;
+
+        let cert = issue_certificate(&spec, &private_key, &authority.signer)?;
+        let default = Arc::new(CertifiedKey::new(
+            vec![RustlsCertificate(cert), RustlsCertificate(authority.cert.clone())],
+            signing_key.clone(),
+        ));
+
+        Ok(Self { authority, spec, private_key, signing_key, default, minted: Mutex::default() })
+    }
+
+    fn mint(&self, name: &str) -> Result<Arc<CertifiedKey>, Box<dyn Error>> {
+        let spec = CertificateSpec {
+            common_name: name.to_string(),
+            subject_alt_names: vec![name.parse::<SubjectAltName>()?],
+            serial: None,
+            ..self.spec.clone()
+        };
+        let cert = issue_certificate(&spec, &self.private_key, &self.authority.signer)?;
+        debug!(name, "minted leaf certificate");
+
+        Ok(Arc::new(CertifiedKey::new(
+            vec![RustlsCertificate(cert), RustlsCertificate(self.authority.cert.clone())],
+            self.signing_key.clone(),
+        )))
+    }
+}
+
+impl ResolvesServerCert for MintingResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name().map(str::to_ascii_lowercase) else {
+            return Some(self.default.clone());
+        };
+
+        let mut minted = self.minted.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(certified) = minted.get(&name) {
+            return Some(certified.clone());
+        }
+
+        match self.mint(&name) {
+            Ok(certified) => {
+                if minted.len() < MAX_MINTED {
+                    minted.insert(name, certified.clone());
+                }
+                Some(certified)
+            }
+            Err(err) => {
+                warn!(name, error = %err, "failed to mint leaf certificate, serving the default");
+                Some(self.default.clone())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::certs::certificate_subject_alt_names;
+
+    fn spec() -> CertificateSpec {
+        CertificateSpec {
+            key_type: KeyType::EcdsaP256,
+            common_name: "localhost".to_string(),
+            organization: None,
+            organizational_unit: None,
+            country: None,
+            subject_alt_names: vec![SubjectAltName::Dns("localhost".to_string())],
+            not_before: SystemTime::now(),
+            validity: Duration::from_secs(86400),
+            serial: None,
+            key_usages: Vec::new(),
+            extended_key_usages: Vec::new(),
+        }
+    }
+
+    fn verify(chain: &[RustlsCertificate], root: &[u8], name: &str) -> Result<(), webpki::Error> {
+        let leaf = webpki::EndEntityCert::try_from(chain[0].0.as_slice())?;
+        let anchors = [webpki::TrustAnchor::try_from_cert_der(root)?];
+        let intermediates: Vec<&[u8]> = chain[1..].iter().map(|cert| cert.0.as_slice()).collect();
+        let now = webpki::Time::try_from(SystemTime::now()).unwrap();
+        leaf.verify_is_valid_tls_server_cert(&[&webpki::ECDSA_P256_SHA256], &webpki::TlsServerTrustAnchors(&anchors), &intermediates, now)?;
+        leaf.verify_is_valid_for_dns_name(webpki::DnsNameRef::try_from_ascii_str(name).unwrap())
+    }
+
+    #[test]
+    fn mints_leaves_that_chain_to_the_root() {
+        let dir = std::env::temp_dir().join(format!("droppa-ca-{}", std::process::id()));
+        CertificateAuthority::init(&dir, KeyType::EcdsaP256, false).unwrap();
+        assert!(CertificateAuthority::init(&dir, KeyType::EcdsaP256, false).is_err());
+        let authority = CertificateAuthority::load(&dir).unwrap();
+        let root = authority.cert().to_vec();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let resolver = MintingResolver::new(authority, spec()).unwrap();
+        let minted = resolver.mint("files.example").unwrap();
+        assert_eq!(minted.cert[1].0, root);
+        verify(&minted.cert, &root, "files.example").unwrap();
+        assert!(verify(&minted.cert, &root, "localhost").is_err());
+        let names = certificate_subject_alt_names(&minted.cert[0].0).unwrap();
+        assert!(names == vec![SubjectAltName::Dns("files.example".to_string())]);
+
+        verify(&resolver.default.cert, &root, "localhost").unwrap();
+    }
+}
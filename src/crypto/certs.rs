@@ -1,33 +1,431 @@
 use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use rand::rngs::OsRng;
+use rand::RngCore;
 use rcgen::Certificate;
-use rsa::pkcs1::EncodeRsaPublicKey;
 use rsa::RsaPrivateKey;
-use rcgen::{CertificateParams, KeyPair};
-use rsa::pkcs8::EncodePrivateKey;
+use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyPair, KeyUsagePurpose, SanType, SerialNumber, SignatureAlgorithm};
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+use rsa::traits::PublicKeyParts;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tracing::debug;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
 
-pub fn generate_self_signed_certificate(issuer: &str) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
-    let mut rng = OsRng;
-    let private_key = RsaPrivateKey::new(&mut rng, 2048)?;
+/// Serial numbers are at most 20 octets (RFC 5280, 4.1.2.2).
+const MAX_SERIAL_LEN: usize = 20;
 
-    let private_key_pem = private_key.to_pkcs8_pem(rsa::pkcs1::LineEnding::LF)?;
-    println!("{:?}", private_key.to_public_key().to_pkcs1_pem(rsa::pkcs1::LineEnding::LF)?);
+/// What a generated certificate says about itself.
+#[derive(Clone)]
+pub struct CertificateSpec {
+    pub key_type: KeyType,
+    pub common_name: String,
+    pub organization: Option<String>,
+    pub organizational_unit: Option<String>,
+    /// Two-letter ISO 3166 code.
+    pub country: Option<String>,
+    pub subject_alt_names: Vec<SubjectAltName>,
+    pub not_before: SystemTime,
+    pub validity: Duration,
+    /// Random when not given.
+    pub serial: Option<Vec<u8>>,
+    pub key_usages: Vec<KeyUsage>,
+    pub extended_key_usages: Vec<ExtendedKeyUsage>,
+}
 
-    let mut params = CertificateParams::default();
-    params.distinguished_name.push(rcgen::DnType::CommonName, issuer.to_string());
+#[derive(Clone, PartialEq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Ip(IpAddr),
+}
 
-    params.alg = &rcgen::PKCS_RSA_SHA256; 
+impl FromStr for SubjectAltName {
+    type Err = String;
 
-    let key_pair = KeyPair::from_pem(&private_key_pem)?;
-    params.key_pair = Some(key_pair);
+    /// An IP address, or a DNS name with an optional leading `*.` wildcard label.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if let Ok(ip) = value.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(SubjectAltName::Ip(ip));
+        }
+
+        let name = value.strip_prefix("*.").unwrap_or(value);
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        };
+        if name.is_empty() || name.len() > 253 || !name.split('.').all(valid_label) {
+            return Err(format!("'{}' is neither an IP address nor a DNS name", value));
+        }
+
+        Ok(SubjectAltName::Dns(value.to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for SubjectAltName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubjectAltName::Dns(name) => write!(f, "DNS:{}", name),
+            SubjectAltName::Ip(ip) => write!(f, "IP:{}", ip),
+        }
+    }
+}
+
+impl From<&SubjectAltName> for SanType {
+    fn from(name: &SubjectAltName) -> Self {
+        match name {
+            SubjectAltName::Dns(name) => SanType::DnsName(name.clone()),
+            SubjectAltName::Ip(ip) => SanType::IpAddress(*ip),
+        }
+    }
+}
+
+const KEY_TYPES: &[(&str, KeyType)] = &[
+    ("rsa2048", KeyType::Rsa2048),
+    ("rsa3072", KeyType::Rsa3072),
+    ("rsa4096", KeyType::Rsa4096),
+    ("ecdsa-p256", KeyType::EcdsaP256),
+    ("ecdsa-p384", KeyType::EcdsaP384),
+    ("ed25519", KeyType::Ed25519),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyType {
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+impl KeyType {
+    pub fn algorithm(self) -> &'static SignatureAlgorithm {
+        match self {
+            KeyType::Rsa2048 | KeyType::Rsa3072 | KeyType::Rsa4096 => &rcgen::PKCS_RSA_SHA256,
+            KeyType::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            KeyType::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+            KeyType::Ed25519 => &rcgen::PKCS_ED25519,
+        }
+    }
+
+    /// rcgen cannot create RSA keys, so those come from the `rsa` crate.
+    pub fn generate(self) -> Result<KeyPair, Box<dyn Error>> {
+        let bits = match self {
+            KeyType::Rsa2048 => 2048,
+            KeyType::Rsa3072 => 3072,
+            KeyType::Rsa4096 => 4096,
+            _ => return Ok(KeyPair::generate(self.algorithm())?),
+        };
+
+        let private_key = RsaPrivateKey::new(&mut OsRng, bits)?;
+        let private_key_pem = private_key.to_pkcs8_pem(rsa::pkcs1::LineEnding::LF)?;
+        Ok(KeyPair::from_pem(&private_key_pem)?)
+    }
+
+    /// The type of a PKCS#8 DER private key, if it is one droppa can generate.
+    pub fn of(private_key_der: &[u8]) -> Option<KeyType> {
+        if let Ok(private_key) = RsaPrivateKey::from_pkcs8_der(private_key_der) {
+            return match private_key.size() * 8 {
+                2048 => Some(KeyType::Rsa2048),
+                3072 => Some(KeyType::Rsa3072),
+                4096 => Some(KeyType::Rsa4096),
+                _ => None,
+            };
+        }
+
+        let key_pair = KeyPair::from_der(private_key_der).ok()?;
+        [KeyType::EcdsaP256, KeyType::EcdsaP384, KeyType::Ed25519]
+            .into_iter()
+            .find(|key_type| key_pair.is_compatible(key_type.algorithm()))
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        lookup(KEY_TYPES, value).ok_or_else(|| format!("unknown key type '{}', expected one of {}", value, names(KEY_TYPES)))
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", name(KEY_TYPES, *self))
+    }
+}
+
+/// Names as `openssl x509 -text` prints them, in kebab case.
+const KEY_USAGES: &[(&str, KeyUsage)] = &[
+    ("digital-signature", KeyUsage::DigitalSignature),
+    ("content-commitment", KeyUsage::ContentCommitment),
+    ("key-encipherment", KeyUsage::KeyEncipherment),
+    ("data-encipherment", KeyUsage::DataEncipherment),
+    ("key-agreement", KeyUsage::KeyAgreement),
+    ("key-cert-sign", KeyUsage::KeyCertSign),
+    ("crl-sign", KeyUsage::CrlSign),
+];
+
+const EXTENDED_KEY_USAGES: &[(&str, ExtendedKeyUsage)] = &[
+    ("server-auth", ExtendedKeyUsage::ServerAuth),
+    ("client-auth", ExtendedKeyUsage::ClientAuth),
+    ("code-signing", ExtendedKeyUsage::CodeSigning),
+    ("email-protection", ExtendedKeyUsage::EmailProtection),
+    ("time-stamping", ExtendedKeyUsage::TimeStamping),
+    ("ocsp-signing", ExtendedKeyUsage::OcspSigning),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyUsage {
+    DigitalSignature,
+    ContentCommitment,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+    KeyCertSign,
+    CrlSign,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExtendedKeyUsage {
+    ServerAuth,
+    ClientAuth,
+    CodeSigning,
+    EmailProtection,
+    TimeStamping,
+    OcspSigning,
+}
+
+impl FromStr for KeyUsage {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        lookup(KEY_USAGES, value).ok_or_else(|| format!("unknown key usage '{}', expected one of {}", value, names(KEY_USAGES)))
+    }
+}
+
+impl fmt::Display for KeyUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", name(KEY_USAGES, *self))
+    }
+}
+
+impl From<KeyUsage> for KeyUsagePurpose {
+    fn from(usage: KeyUsage) -> Self {
+        match usage {
+            KeyUsage::DigitalSignature => KeyUsagePurpose::DigitalSignature,
+            KeyUsage::ContentCommitment => KeyUsagePurpose::ContentCommitment,
+            KeyUsage::KeyEncipherment => KeyUsagePurpose::KeyEncipherment,
+            KeyUsage::DataEncipherment => KeyUsagePurpose::DataEncipherment,
+            KeyUsage::KeyAgreement => KeyUsagePurpose::KeyAgreement,
+            KeyUsage::KeyCertSign => KeyUsagePurpose::KeyCertSign,
+            KeyUsage::CrlSign => KeyUsagePurpose::CrlSign,
+        }
+    }
+}
+
+impl FromStr for ExtendedKeyUsage {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        lookup(EXTENDED_KEY_USAGES, value)
+            .ok_or_else(|| format!("unknown extended key usage '{}', expected one of {}", value, names(EXTENDED_KEY_USAGES)))
+    }
+}
+
+impl fmt::Display for ExtendedKeyUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", name(EXTENDED_KEY_USAGES, *self))
+    }
+}
+
+impl From<ExtendedKeyUsage> for ExtendedKeyUsagePurpose {
+    fn from(usage: ExtendedKeyUsage) -> Self {
+        match usage {
+            ExtendedKeyUsage::ServerAuth => ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsage::ClientAuth => ExtendedKeyUsagePurpose::ClientAuth,
+            ExtendedKeyUsage::CodeSigning => ExtendedKeyUsagePurpose::CodeSigning,
+            ExtendedKeyUsage::EmailProtection => ExtendedKeyUsagePurpose::EmailProtection,
+            ExtendedKeyUsage::TimeStamping => ExtendedKeyUsagePurpose::TimeStamping,
+            ExtendedKeyUsage::OcspSigning => ExtendedKeyUsagePurpose::OcspSigning,
+        }
+    }
+}
+
+fn lookup<T: Copy>(table: &[(&str, T)], value: &str) -> Option<T> {
+    table.iter().find(|(name, _)| *name == value).map(|(_, usage)| *usage)
+}
+
+fn name<T: PartialEq>(table: &[(&'static str, T)], usage: T) -> &'static str {
+    table.iter().find(|(_, candidate)| *candidate == usage).map_or("?", |(name, _)| name)
+}
+
+fn names<T>(table: &[(&str, T)]) -> String {
+    table.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+}
+
+/// Reads a serial given in hex, optionally `0x`-prefixed or colon-separated as openssl prints it.
+pub fn parse_serial(value: &str) -> Result<Vec<u8>, String> {
+    let digits: String = value.trim().trim_start_matches("0x").chars().filter(|c| *c != ':').collect();
+    let digits = if digits.len() % 2 == 1 { format!("0{}", digits) } else { digits };
+
+    let serial = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("'{}' is not a hex serial number", value))?;
+
+    let significant = serial.iter().skip_while(|byte| **byte == 0).count();
+    if significant == 0 {
+        return Err("the serial number must not be zero".to_string());
+    }
+    if significant > MAX_SERIAL_LEN {
+        return Err(format!("'{}' is longer than {} bytes", value, MAX_SERIAL_LEN));
+    }
+
+    Ok(serial[serial.len() - significant..].to_vec())
+}
+
+fn random_serial() -> Vec<u8> {
+    let mut serial = vec![0u8; 16];
+    OsRng.fill_bytes(&mut serial);
+    // Keep it positive and the full length once DER-encoded.
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+    serial
+}
 
-    let cert = Certificate::from_params(params)
+/// Returns the DER certificate and its PKCS#8 DER private key.
+pub fn generate_self_signed_certificate(spec: &CertificateSpec) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    self_signed_certificate(spec, spec.key_type.generate()?)
+}
+
+/// Like [`generate_self_signed_certificate`], but keeps an existing PKCS#8 DER key so pins on
+/// its public key stay valid.
+pub fn reissue_self_signed_certificate(spec: &CertificateSpec, private_key_der: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    self_signed_certificate(spec, KeyPair::from_der(private_key_der)?)
+}
+
+fn self_signed_certificate(spec: &CertificateSpec, key_pair: KeyPair) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let cert = Certificate::from_params(certificate_params(spec, key_pair))
         .map_err(|e| format!("Failed to generate certificate: {}", e))?;
     let cert_der = cert.serialize_der()
         .map_err(|e| format!("Failed to serialize certificate to DER: {}", e))?;
-    let private_key_der = private_key.to_pkcs8_der()
-        .map_err(|e| format!("Failed to convert private key to DER: {}", e))?;
 
-    Ok((cert_der, private_key_der.to_bytes().to_vec()))
+    debug!(
+        key_type = %spec.key_type,
+        common_name = spec.common_name,
+        sans = spec.subject_alt_names.len(),
+        "generated self-signed certificate"
+    );
+
+    Ok((cert_der, cert.serialize_private_key_der()))
+}
+
+/// A DER certificate for `spec` and an existing PKCS#8 DER key, signed by `issuer`.
+pub fn issue_certificate(spec: &CertificateSpec, private_key_der: &[u8], issuer: &Certificate) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cert = Certificate::from_params(certificate_params(spec, KeyPair::from_der(private_key_der)?))
+        .map_err(|e| format!("Failed to generate certificate: {}", e))?;
+    let cert_der = cert.serialize_der_with_signer(issuer)
+        .map_err(|e| format!("Failed to sign certificate: {}", e))?;
+
+    Ok(cert_der)
+}
+
+fn certificate_params(spec: &CertificateSpec, key_pair: KeyPair) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, spec.common_name.clone());
+    if let Some(organization) = &spec.organization {
+        params.distinguished_name.push(DnType::OrganizationName, organization.clone());
+    }
+    if let Some(organizational_unit) = &spec.organizational_unit {
+        params.distinguished_name.push(DnType::OrganizationalUnitName, organizational_unit.clone());
+    }
+    if let Some(country) = &spec.country {
+        params.distinguished_name.push(DnType::CountryName, country.clone());
+    }
+
+    params.subject_alt_names = spec.subject_alt_names.iter().map(SanType::from).collect();
+    params.not_before = OffsetDateTime::from(spec.not_before);
+    params.not_after = OffsetDateTime::from(spec.not_before + spec.validity);
+    params.serial_number = Some(SerialNumber::from(spec.serial.clone().unwrap_or_else(random_serial)));
+    params.key_usages = spec.key_usages.iter().copied().map(KeyUsagePurpose::from).collect();
+    params.extended_key_usages = spec.extended_key_usages.iter().copied().map(ExtendedKeyUsagePurpose::from).collect();
+
+    params.alg = spec.key_type.algorithm();
+    params.key_pair = Some(key_pair);
+    params
+}
+
+/// SHA-256 over the DER certificate, as colon-separated uppercase hex like `openssl x509 -fingerprint`.
+pub fn sha256_fingerprint(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// SHA-1 over the DER certificate as uppercase hex, the form .NET's `GetCertHashString()` returns.
+pub fn sha1_fingerprint(cert_der: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, cert_der)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
 }
 
+/// SHA-256 over the certificate's SubjectPublicKeyInfo in base64, the form curl's
+/// `--pinnedpubkey sha256//...` takes. Unlike the certificate fingerprint it survives reissuing.
+pub fn spki_fingerprint(cert_der: &[u8]) -> Result<String, Box<dyn Error>> {
+    let cert = parse_certificate(cert_der)?;
+    Ok(STANDARD.encode(Sha256::digest(cert.public_key().raw)))
+}
+
+/// The DNS names and IP addresses the certificate is valid for.
+pub fn certificate_subject_alt_names(cert_der: &[u8]) -> Result<Vec<SubjectAltName>, Box<dyn Error>> {
+    let cert = parse_certificate(cert_der)?;
+    let Some(extension) = cert.subject_alternative_name()? else {
+        return Ok(Vec::new());
+    };
+
+    let names = extension
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(SubjectAltName::Dns(name.to_ascii_lowercase())),
+            GeneralName::IPAddress(octets) => match *octets {
+                [a, b, c, d] => Some(SubjectAltName::Ip(Ipv4Addr::new(*a, *b, *c, *d).into())),
+                octets => <[u8; 16]>::try_from(octets).ok().map(|octets| SubjectAltName::Ip(Ipv6Addr::from(octets).into())),
+            },
+            _ => None,
+        })
+        .collect();
+
+    Ok(names)
+}
+
+pub fn certificate_not_before(cert_der: &[u8]) -> Result<SystemTime, Box<dyn Error>> {
+    let not_before = parse_certificate(cert_der)?.validity().not_before.timestamp();
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(not_before.max(0) as u64))
+}
+
+pub fn certificate_not_after(cert_der: &[u8]) -> Result<SystemTime, Box<dyn Error>> {
+    let not_after = parse_certificate(cert_der)?.validity().not_after.timestamp();
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64))
+}
+
+fn parse_certificate(cert_der: &[u8]) -> Result<X509Certificate<'_>, Box<dyn Error>> {
+    let (_, cert) = X509Certificate::from_der(cert_der).map_err(|e| format!("Failed to parse certificate: {}", e))?;
+    Ok(cert)
+}
@@ -0,0 +1,443 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier, NoClientAuth};
+use tokio_rustls::rustls::{Certificate, RootCertStore};
+use tracing::{info, warn};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::FromDer;
+
+use crate::crypto::certs::sha256_fingerprint;
+use crate::crypto::keys::load_certificates;
+use crate::transport::stream::PeerAddr;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuthMode {
+    /// The handshake fails without a certificate from the client CA.
+    Required,
+    /// Clients without a certificate get in as anonymous, subject to `--auth`.
+    Optional,
+}
+
+impl FromStr for ClientAuthMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "required" => Ok(ClientAuthMode::Required),
+            "optional" => Ok(ClientAuthMode::Optional),
+            _ => Err(format!("unknown mode {}, expected required or optional", value)),
+        }
+    }
+}
+
+impl fmt::Display for ClientAuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClientAuthMode::Required => "required",
+            ClientAuthMode::Optional => "optional",
+        })
+    }
+}
+
+/// What a client holding a certificate may do on the file server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// List and download.
+    Read,
+    /// Upload only, without seeing what is already there.
+    Write,
+    ReadWrite,
+}
+
+impl Role {
+    pub fn can_download(self) -> bool {
+        self != Role::Write
+    }
+
+    pub fn can_upload(self) -> bool {
+        self != Role::Read
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "read-write" => Ok(Role::ReadWrite),
+            _ => Err(format!("unknown role {}, expected read, write or read-write", value)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Read => "read",
+            Role::Write => "write",
+            Role::ReadWrite => "read-write",
+        })
+    }
+}
+
+/// Which certificates a `--client-user` entry applies to.
+enum Selector {
+    Any,
+    CommonName(String),
+    Organization(String),
+    OrganizationalUnit(String),
+    /// Uppercase hex without separators.
+    Fingerprint(String),
+}
+
+impl Selector {
+    fn matches(&self, subject: &Subject, fingerprint: &str) -> bool {
+        match self {
+            Selector::Any => true,
+            Selector::CommonName(name) => subject.common_names.contains(name),
+            Selector::Organization(name) => subject.organizations.contains(name),
+            Selector::OrganizationalUnit(name) => subject.organizational_units.contains(name),
+            Selector::Fingerprint(expected) => fingerprint.replace(':', "") == *expected,
+        }
+    }
+}
+
+/// `<selector>=[<user>][:<role>]` from `--client-user`, where the selector is `cn:<name>`,
+/// `o:<name>`, `ou:<name>`, `sha256:<fingerprint>` or `*`.
+pub struct ClientMapping {
+    selector: Selector,
+    /// The certificate's common name when not given.
+    user: Option<String>,
+    role: Role,
+}
+
+impl FromStr for ClientMapping {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (selector, target) = value.rsplit_once('=').ok_or("expected <selector>=<user>[:<role>]")?;
+        let (user, role) = match target.split_once(':') {
+            Some((user, role)) => (user, role.parse()?),
+            None => (target, Role::ReadWrite),
+        };
+
+        let selector = match selector.split_once(':') {
+            _ if selector == "*" => Selector::Any,
+            Some(("cn", name)) => Selector::CommonName(name.to_string()),
+            Some(("o", name)) => Selector::Organization(name.to_string()),
+            Some(("ou", name)) => Selector::OrganizationalUnit(name.to_string()),
+            Some(("sha256", fingerprint)) => {
+                let fingerprint = fingerprint.replace(':', "").to_ascii_uppercase();
+                if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("{}: expected a SHA-256 fingerprint in hex", fingerprint));
+                }
+                Selector::Fingerprint(fingerprint)
+            }
+            _ => return Err(format!("unknown selector {}, expected cn:, o:, ou:, sha256: or *", selector)),
+        };
+
+        Ok(Self { selector, user: Some(user.to_string()).filter(|user| !user.is_empty()), role })
+    }
+}
+
+/// A client certificate that verified against the client CA, and who it stands for.
+pub struct ClientIdentity {
+    pub user: String,
+    pub role: Role,
+    pub subject: String,
+    pub fingerprint: String,
+}
+
+pub enum Admission {
+    /// No certificate, which only `ClientAuthMode::Optional` lets through.
+    Anonymous,
+    Identified(Arc<ClientIdentity>),
+    /// Verified, but no `--client-user` entry covers it; the connection should be closed.
+    Refused,
+}
+
+/// Client certificate authentication for the TLS listeners, from `--client-ca`.
+pub struct ClientAuth {
+    pub roots: PathBuf,
+    pub mode: ClientAuthMode,
+    /// Tried in order. When empty, every certificate from the CA is let in as its common name.
+    pub mappings: Vec<ClientMapping>,
+}
+
+impl ClientAuth {
+    /// Has rustls ask for client certificates and verify them against the CA bundle.
+    pub fn verifier(&self) -> Result<Arc<dyn ClientCertVerifier>, Box<dyn Error>> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certificates(&self.roots)? {
+            roots.add(&Certificate(cert)).map_err(|e| format!("{}: {}", self.roots.display(), e))?;
+        }
+
+        Ok(match self.mode {
+            ClientAuthMode::Required => AllowAnyAuthenticatedClient::new(roots),
+            ClientAuthMode::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        })
+    }
+
+    /// Maps the certificate of a completed handshake to a user and logs who connected.
+    pub fn admit(&self, peer: PeerAddr, certificates: Option<&[Certificate]>) -> Admission {
+        let Some(leaf) = certificates.and_then(<[Certificate]>::first) else {
+            return Admission::Anonymous;
+        };
+
+        let fingerprint = sha256_fingerprint(&leaf.0);
+        let subject = match Subject::of(&leaf.0) {
+            Ok(subject) => subject,
+            Err(err) => {
+                warn!(peer = %peer, sha256 = fingerprint, error = %err, "refused client certificate");
+                return Admission::Refused;
+            }
+        };
+
+        let identity = if self.mappings.is_empty() {
+            Some(ClientIdentity {
+                user: subject.user_name(&fingerprint),
+                role: Role::ReadWrite,
+                subject: subject.name.clone(),
+                fingerprint: fingerprint.clone(),
+            })
+        } else {
+            self.mappings.iter().find(|mapping| mapping.selector.matches(&subject, &fingerprint)).map(|mapping| ClientIdentity {
+                user: mapping.user.clone().unwrap_or_else(|| subject.user_name(&fingerprint)),
+                role: mapping.role,
+                subject: subject.name.clone(),
+                fingerprint: fingerprint.clone(),
+            })
+        };
+
+        match identity {
+            Some(identity) => {
+                info!(
+                    peer = %peer,
+                    user = identity.user,
+                    role = %identity.role,
+                    subject = identity.subject,
+                    sha256 = identity.fingerprint,
+                    "client certificate accepted"
+                );
+                Admission::Identified(Arc::new(identity))
+            }
+            None => {
+                warn!(peer = %peer, subject = subject.name, sha256 = fingerprint, "client certificate matches no --client-user, closing");
+                Admission::Refused
+            }
+        }
+    }
+}
+
+/// Asks for client certificates when `client_auth` is given, never otherwise.
+pub fn client_verifier(client_auth: Option<&ClientAuth>) -> Result<Arc<dyn ClientCertVerifier>, Box<dyn Error>> {
+    match client_auth {
+        Some(client_auth) => client_auth.verifier(),
+        None => Ok(NoClientAuth::new()),
+    }
+}
+
+struct Subject {
+    /// RFC 4514 form, such as `CN=build-01, O=Red Team`.
+    name: String,
+    common_names: Vec<String>,
+    organizations: Vec<String>,
+    organizational_units: Vec<String>,
+}
+
+impl Subject {
+    fn of(cert_der: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let (_, cert) = X509Certificate::from_der(cert_der).map_err(|e| format!("Failed to parse certificate: {}", e))?;
+        let subject = cert.subject();
+        let values = |attributes: Vec<&x509_parser::x509::AttributeTypeAndValue>| -> Vec<String> {
+            attributes.into_iter().filter_map(|attribute| attribute.as_str().ok().map(str::to_string)).collect()
+        };
+
+        Ok(Self {
+            name: subject.to_string(),
+            common_names: values(subject.iter_common_name().collect()),
+            organizations: values(subject.iter_organization().collect()),
+            organizational_units: values(subject.iter_organizational_unit().collect()),
+        })
+    }
+
+    /// The common name, or the fingerprint for certificates without one.
+    fn user_name(&self, fingerprint: &str) -> String {
+        self.common_names.first().cloned().unwrap_or_else(|| fingerprint.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, SanType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, PrivateKey, ServerName};
+    use tokio_rustls::TlsConnector;
+    use crate::crypto::tls::generate_tls_acceptor;
+
+    const PEER: PeerAddr = PeerAddr::Tcp(SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 50000));
+
+    fn authority() -> rcgen::Certificate {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, "test client CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    /// A client certificate from `ca` with the given subject, and its PKCS#8 DER key.
+    fn client(ca: &rcgen::Certificate, common_name: &str, organization: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        params.distinguished_name.push(DnType::OrganizationName, organization);
+        params.distinguished_name.push(DnType::OrganizationalUnitName, "ops");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (cert.serialize_der_with_signer(ca).unwrap(), cert.serialize_private_key_der())
+    }
+
+    fn client_auth(mode: ClientAuthMode, mappings: &[&str]) -> ClientAuth {
+        ClientAuth {
+            roots: PathBuf::new(),
+            mode,
+            mappings: mappings.iter().map(|mapping| mapping.parse().unwrap()).collect(),
+        }
+    }
+
+    fn identified(admission: Admission) -> Option<(String, Role)> {
+        match admission {
+            Admission::Identified(identity) => Some((identity.user.clone(), identity.role)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_selectors_users_and_roles() {
+        let mapping: ClientMapping = "cn:alice=al:read".parse().unwrap();
+        assert!(matches!(&mapping.selector, Selector::CommonName(name) if name == "alice"));
+        assert_eq!(mapping.user.as_deref(), Some("al"));
+        assert_eq!(mapping.role, Role::Read);
+
+        let mapping: ClientMapping = "o:Red Team=:write".parse().unwrap();
+        assert!(matches!(&mapping.selector, Selector::Organization(name) if name == "Red Team"));
+        assert_eq!(mapping.user, None);
+        assert_eq!(mapping.role, Role::Write);
+
+        let mapping: ClientMapping = "ou:ops=bob".parse().unwrap();
+        assert!(matches!(&mapping.selector, Selector::OrganizationalUnit(name) if name == "ops"));
+        assert_eq!(mapping.role, Role::ReadWrite);
+
+        let mapping: ClientMapping = "*=guest:read".parse().unwrap();
+        assert!(matches!(mapping.selector, Selector::Any));
+    }
+
+    #[test]
+    fn parses_fingerprints_with_and_without_colons() {
+        let hex = "ab".repeat(32);
+        let colons = vec!["AB"; 32].join(":");
+        for fingerprint in [hex.as_str(), colons.as_str()] {
+            let mapping: ClientMapping = format!("sha256:{}=alice", fingerprint).parse().unwrap();
+            assert!(matches!(&mapping.selector, Selector::Fingerprint(expected) if *expected == "AB".repeat(32)));
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_mappings() {
+        for mapping in [
+            "cn:alice",
+            "dn:alice=alice",
+            "cn:alice=alice:admin",
+            "sha256:ABCD=alice",
+            &format!("sha256:{}=alice", "G".repeat(64)),
+            &format!("sha256:{}=alice", "A".repeat(66)),
+        ] {
+            assert!(mapping.parse::<ClientMapping>().is_err(), "{}", mapping);
+        }
+    }
+
+    #[test]
+    fn admits_by_the_first_matching_mapping() {
+        let ca = authority();
+        let (alice, _) = client(&ca, "alice", "Red Team");
+        let (bob, _) = client(&ca, "bob", "Blue Team");
+        let alice = [Certificate(alice)];
+        let bob = [Certificate(bob)];
+
+        let auth = client_auth(ClientAuthMode::Required, &["cn:alice=first:read", "o:Red Team=second:write", "ou:ops=:write"]);
+        assert_eq!(identified(auth.admit(PEER, Some(&alice))), Some(("first".to_string(), Role::Read)));
+        // No user given, so the common name.
+        assert_eq!(identified(auth.admit(PEER, Some(&bob))), Some(("bob".to_string(), Role::Write)));
+
+        let pinned = format!("sha256:{}=pinned:read", sha256_fingerprint(&bob[0].0));
+        let auth = client_auth(ClientAuthMode::Required, &[&pinned]);
+        assert_eq!(identified(auth.admit(PEER, Some(&bob))), Some(("pinned".to_string(), Role::Read)));
+        assert!(matches!(auth.admit(PEER, Some(&alice)), Admission::Refused));
+    }
+
+    #[test]
+    fn admits_without_mappings_as_the_common_name() {
+        let (alice, _) = client(&authority(), "alice", "Red Team");
+        let auth = client_auth(ClientAuthMode::Optional, &[]);
+
+        assert_eq!(identified(auth.admit(PEER, Some(&[Certificate(alice)]))), Some(("alice".to_string(), Role::ReadWrite)));
+        assert!(matches!(auth.admit(PEER, None), Admission::Anonymous));
+        assert!(matches!(auth.admit(PEER, Some(&[Certificate(b"not a certificate".to_vec())])), Admission::Refused));
+    }
+
+    /// Serves a "localhost" certificate from `ca` that requires client certificates from `ca`,
+    /// and connects with `identity`. Returns what `admit` made of the client, or `None` when
+    /// the handshake failed.
+    async fn handshake(ca: &rcgen::Certificate, identity: Option<(Vec<u8>, Vec<u8>)>) -> Option<(String, Role)> {
+        let ca_der = ca.serialize_der().unwrap();
+        let roots = std::env::temp_dir().join(format!("droppa-client-ca-{}-{}.pem", std::process::id(), identity.is_some()));
+        std::fs::write(&roots, pem::encode(&pem::Pem::new("CERTIFICATE", ca_der.clone()))).unwrap();
+        let auth = ClientAuth { roots: roots.clone(), mode: ClientAuthMode::Required, mappings: Vec::new() };
+        let verifier = auth.verifier().unwrap();
+        std::fs::remove_file(&roots).unwrap();
+
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = vec![SanType::DnsName("localhost".to_string())];
+        let server = rcgen::Certificate::from_params(params).unwrap();
+        let acceptor = generate_tls_acceptor(vec![server.serialize_der_with_signer(ca).unwrap()], server.serialize_private_key_der(), verifier).unwrap();
+
+        let mut trusted = RootCertStore::empty();
+        trusted.add(&Certificate(ca_der)).unwrap();
+        let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(trusted);
+        let config = match identity {
+            Some((cert, key)) => config.with_single_cert(vec![Certificate(cert)], PrivateKey(key)).unwrap(),
+            None => config.with_no_client_auth(),
+        };
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.ok()?;
+            let admitted = identified(auth.admit(PEER, stream.get_ref().1.peer_certificates()));
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+            admitted
+        });
+
+        if let Ok(mut stream) = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), client).await {
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        }
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn handshake_with_a_client_certificate() {
+        let ca = authority();
+        let alice = client(&ca, "alice", "Red Team");
+        assert_eq!(handshake(&ca, Some(alice)).await, Some(("alice".to_string(), Role::ReadWrite)));
+    }
+
+    #[tokio::test]
+    async fn handshake_without_a_client_certificate_fails_when_required() {
+        assert_eq!(handshake(&authority(), None).await, None);
+    }
+}
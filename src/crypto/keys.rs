@@ -0,0 +1,366 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use pem::Pem;
+use pkcs8::der::asn1::AnyRef;
+use pkcs8::der::Encode;
+use pkcs8::{AlgorithmIdentifierRef, EncryptedPrivateKeyInfo, ObjectIdentifier, PrivateKeyInfo};
+use rcgen::KeyPair;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::EncodePrivateKey;
+use rsa::RsaPrivateKey;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::FromDer;
+
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const CURVES: [ObjectIdentifier; 2] = [
+    ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7"),
+    ObjectIdentifier::new_unwrap("1.3.132.0.34"),
+];
+
+/// DER certificates, the server's own first and then any intermediates.
+pub type CertificateChain = Vec<Vec<u8>>;
+
+enum KeyFormat {
+    Pkcs8,
+    EncryptedPkcs8,
+    Pkcs1,
+    Sec1,
+}
+
+/// Every certificate in a PEM file, in file order, or the one in a DER file. Other PEM
+/// blocks are skipped, so a combined key and chain file works.
+pub fn load_certificates(path: &Path) -> Result<CertificateChain, Box<dyn Error>> {
+    let content = read(path)?;
+
+    let chain = if is_pem(&content) {
+        pem::parse_many(&content)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .into_iter()
+            .filter(|block| block.tag() == "CERTIFICATE")
+            .map(pem::Pem::into_contents)
+            .collect()
+    } else {
+        vec![content]
+    };
+
+    if chain.is_empty() {
+        return Err(format!("{}: no CERTIFICATE block found", path.display()).into());
+    }
+    for (index, cert) in chain.iter().enumerate() {
+        X509Certificate::from_der(cert)
+            .map_err(|e| format!("{}: certificate {} is not valid X.509: {}", path.display(), index + 1, e))?;
+    }
+
+    Ok(chain)
+}
+
+/// The first private key in a PEM or DER file as PKCS#8 DER, whether it was stored as
+/// PKCS#8, passphrase-encrypted PKCS#8, RSA PKCS#1 or EC SEC1.
+pub fn load_private_key(path: &Path, passphrase: Option<&str>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let content = read(path)?;
+    let located = |e: String| format!("{}: {}", path.display(), e);
+
+    if !is_pem(&content) {
+        // DER has no label to go by, but the PKCS#8 forms can be told apart by structure.
+        let decoded = if EncryptedPrivateKeyInfo::try_from(content.as_slice()).is_ok() {
+            decode(KeyFormat::EncryptedPkcs8, &content, passphrase)
+        } else if PrivateKeyInfo::try_from(content.as_slice()).is_ok() {
+            decode(KeyFormat::Pkcs8, &content, passphrase)
+        } else {
+            [KeyFormat::Pkcs1, KeyFormat::Sec1]
+                .into_iter()
+                .find_map(|format| decode(format, &content, passphrase).ok())
+                .ok_or_else(|| "not a PKCS#8, PKCS#1 or SEC1 private key in PEM or DER".to_string())
+        };
+        return Ok(decoded.map_err(located)?);
+    }
+
+    let blocks = pem::parse_many(&content).map_err(|e| located(e.to_string()))?;
+    let (format, block) = blocks
+        .iter()
+        .find_map(|block| {
+            let format = match block.tag() {
+                "PRIVATE KEY" => KeyFormat::Pkcs8,
+                "ENCRYPTED PRIVATE KEY" => KeyFormat::EncryptedPkcs8,
+                "RSA PRIVATE KEY" => KeyFormat::Pkcs1,
+                "EC PRIVATE KEY" => KeyFormat::Sec1,
+                _ => return None,
+            };
+            Some((format, block))
+        })
+        .ok_or_else(|| located("no PRIVATE KEY, ENCRYPTED PRIVATE KEY, RSA PRIVATE KEY or EC PRIVATE KEY block found".to_string()))?;
+
+    if block.headers().get("Proc-Type").is_some_and(|value| value.contains("ENCRYPTED")) {
+        return Err(located(format!(
+            "{} with legacy OpenSSL encryption is not supported, convert it with `openssl pkcs8 -topk8 -in {} -out <new key>`",
+            block.tag(),
+            path.display(),
+        ))
+        .into());
+    }
+
+    Ok(decode(format, block.contents(), passphrase).map_err(located)?)
+}
+
+/// Saves a PKCS#8 DER key and its DER chain as PEM files. Both are written in full under
+/// temporary names before either replaces the old pair, so a crash midway leaves the old
+/// key and certificate, not a key that no longer matches.
+pub fn save_key_pair(key_path: &Path, cert_path: &Path, private_key: &[u8], chain: &[Vec<u8>]) -> io::Result<()> {
+    let key = pem::encode(&Pem::new("PRIVATE KEY", private_key));
+    let certs: Vec<Pem> = chain.iter().map(|cert| Pem::new("CERTIFICATE", cert.clone())).collect();
+
+    let staged_key = stage(key_path, key.as_bytes(), true)?;
+    let staged_cert = stage(cert_path, pem::encode_many(&certs).as_bytes(), false).inspect_err(|_| {
+        let _ = fs::remove_file(&staged_key);
+    })?;
+
+    commit(&staged_key, key_path).inspect_err(|_| {
+        let _ = fs::remove_file(&staged_cert);
+    })?;
+    commit(&staged_cert, cert_path)
+}
+
+/// Replaces `path` with `content` readable by the owner only, through a temporary file and
+/// a rename, so the file is never half written and never keeps looser permissions it had.
+pub fn write_secret(path: &Path, content: &[u8]) -> io::Result<()> {
+    let staged = stage(path, content, true)?;
+    commit(&staged, path)
+}
+
+/// Writes `content` next to `path` under a temporary name, ready to be renamed over it.
+fn stage(path: &Path, content: &[u8], secret: bool) -> io::Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let staged = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, if secret { 0o600 } else { 0o644 });
+
+    let written = options.open(&staged).and_then(|mut file| {
+        // The mode only applies to new files, and a stale temporary may be left from a crash.
+        #[cfg(unix)]
+        if secret {
+            file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        }
+        file.write_all(content)?;
+        file.sync_all()
+    });
+    match written {
+        Ok(()) => Ok(staged),
+        Err(err) => {
+            let _ = fs::remove_file(&staged);
+            Err(err)
+        }
+    }
+}
+
+fn commit(staged: &Path, path: &Path) -> io::Result<()> {
+    fs::rename(staged, path).inspect_err(|_| {
+        let _ = fs::remove_file(staged);
+    })
+}
+
+/// Whether `private_key` (PKCS#8 DER) belongs to the public key in `cert_der`.
+pub fn key_matches_certificate(private_key: &[u8], cert_der: &[u8]) -> Result<bool, Box<dyn Error>> {
+    let key_pair = KeyPair::from_der(private_key)?;
+    let (_, cert) = X509Certificate::from_der(cert_der).map_err(|e| format!("Failed to parse certificate: {}", e))?;
+    Ok(key_pair.public_key_raw() == cert.public_key().subject_public_key.data.as_ref())
+}
+
+fn decode(format: KeyFormat, der: &[u8], passphrase: Option<&str>) -> Result<Vec<u8>, String> {
+    match format {
+        KeyFormat::Pkcs8 => supported(der.to_vec()),
+        KeyFormat::EncryptedPkcs8 => {
+            let passphrase = passphrase.ok_or("the key is encrypted, pass --key-passphrase")?;
+            let info = EncryptedPrivateKeyInfo::try_from(der).map_err(|e| format!("invalid encrypted PKCS#8 key: {}", e))?;
+            let decrypted = info
+                .decrypt(passphrase)
+                .map_err(|_| "wrong --key-passphrase, or an encryption scheme other than PBES2")?;
+            supported(decrypted.as_bytes().to_vec())
+        }
+        KeyFormat::Pkcs1 => {
+            let private_key = RsaPrivateKey::from_pkcs1_der(der).map_err(|e| format!("invalid RSA PKCS#1 key: {}", e))?;
+            let pkcs8 = private_key.to_pkcs8_der().map_err(|e| format!("invalid RSA PKCS#1 key: {}", e))?;
+            supported(pkcs8.as_bytes().to_vec())
+        }
+        KeyFormat::Sec1 => {
+            // SEC1 names its curve, but trying both supported ones is simpler than parsing it.
+            CURVES
+                .iter()
+                .filter_map(|curve| {
+                    let algorithm = AlgorithmIdentifierRef { oid: EC_PUBLIC_KEY, parameters: Some(AnyRef::from(curve)) };
+                    PrivateKeyInfo::new(algorithm, der).to_der().ok()
+                })
+                .find(|pkcs8| KeyPair::from_der(pkcs8).is_ok())
+                .ok_or_else(|| "invalid EC key, or a curve other than P-256 and P-384".to_string())
+        }
+    }
+}
+
+/// Only keys that can also sign the TLS handshake get through.
+fn supported(pkcs8: Vec<u8>) -> Result<Vec<u8>, String> {
+    KeyPair::from_der(&pkcs8).map_err(|e| format!("unsupported private key: {}", e))?;
+    Ok(pkcs8)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let content = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if content.iter().all(u8::is_ascii_whitespace) {
+        return Err(format!("{}: file is empty", path.display()).into());
+    }
+    Ok(content)
+}
+
+fn is_pem(content: &[u8]) -> bool {
+    content.trim_ascii_start().starts_with(b"-----BEGIN")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+    use pkcs8::pkcs5::pbes2;
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::pkcs8::DecodePrivateKey;
+    use crate::crypto::certs::{generate_self_signed_certificate, CertificateSpec, KeyType, SubjectAltName};
+
+    /// A file in the temp directory, removed again when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, content: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("droppa-keys-{}-{}", std::process::id(), name));
+            fs::write(&path, content).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn certificate(key_type: KeyType) -> (Vec<u8>, Vec<u8>) {
+        let spec = CertificateSpec {
+            key_type,
+            common_name: "localhost".to_string(),
+            organization: None,
+            organizational_unit: None,
+            country: None,
+            subject_alt_names: vec![SubjectAltName::Dns("localhost".to_string())],
+            not_before: SystemTime::now(),
+            validity: Duration::from_secs(86400),
+            serial: None,
+            key_usages: Vec::new(),
+            extended_key_usages: Vec::new(),
+        };
+        generate_self_signed_certificate(&spec).unwrap()
+    }
+
+    fn pem(tag: &str, der: &[u8]) -> Vec<u8> {
+        pem::encode(&pem::Pem::new(tag, der.to_vec())).into_bytes()
+    }
+
+    fn public_key(pkcs8: &[u8]) -> Vec<u8> {
+        KeyPair::from_der(pkcs8).unwrap().public_key_raw().to_vec()
+    }
+
+    /// Loads `der` from a DER file and from a PEM file labelled `tag`.
+    fn load_both(name: &str, tag: &str, der: &[u8], passphrase: Option<&str>) -> [Result<Vec<u8>, Box<dyn Error>>; 2] {
+        let der_file = TempFile::new(&format!("{}.der", name), der);
+        let pem_file = TempFile::new(&format!("{}.pem", name), &pem(tag, der));
+        [load_private_key(&der_file.0, passphrase), load_private_key(&pem_file.0, passphrase)]
+    }
+
+    #[test]
+    fn loads_rsa_keys_as_pkcs8_and_pkcs1() {
+        let (_, pkcs8) = certificate(KeyType::Rsa2048);
+        let pkcs1 = RsaPrivateKey::from_pkcs8_der(&pkcs8).unwrap().to_pkcs1_der().unwrap();
+
+        for loaded in load_both("rsa-pkcs8", "PRIVATE KEY", &pkcs8, None) {
+            assert_eq!(loaded.unwrap(), pkcs8);
+        }
+        for loaded in load_both("rsa-pkcs1", "RSA PRIVATE KEY", pkcs1.as_bytes(), None) {
+            assert_eq!(public_key(&loaded.unwrap()), public_key(&pkcs8));
+        }
+    }
+
+    #[test]
+    fn loads_ec_keys_as_sec1() {
+        let (_, pkcs8) = certificate(KeyType::EcdsaP256);
+        let sec1 = PrivateKeyInfo::try_from(pkcs8.as_slice()).unwrap().private_key.to_vec();
+
+        for loaded in load_both("ec-sec1", "EC PRIVATE KEY", &sec1, None) {
+            assert_eq!(public_key(&loaded.unwrap()), public_key(&pkcs8));
+        }
+    }
+
+    #[test]
+    fn loads_encrypted_pkcs8_with_the_right_passphrase_only() {
+        let (_, pkcs8) = certificate(KeyType::EcdsaP256);
+        let params = pbes2::Parameters::pbkdf2_sha256_aes256cbc(1000, &[7; 16], &[9; 16]).unwrap();
+        let encrypted = PrivateKeyInfo::try_from(pkcs8.as_slice()).unwrap().encrypt_with_params(params, "secret").unwrap();
+
+        for loaded in load_both("encrypted-right", "ENCRYPTED PRIVATE KEY", encrypted.as_bytes(), Some("secret")) {
+            assert_eq!(loaded.unwrap(), pkcs8);
+        }
+        for loaded in load_both("encrypted-wrong", "ENCRYPTED PRIVATE KEY", encrypted.as_bytes(), Some("guess")) {
+            assert!(loaded.unwrap_err().to_string().contains("wrong --key-passphrase"));
+        }
+        for loaded in load_both("encrypted-none", "ENCRYPTED PRIVATE KEY", encrypted.as_bytes(), None) {
+            assert!(loaded.unwrap_err().to_string().contains("pass --key-passphrase"));
+        }
+    }
+
+    #[test]
+    fn empty_files_are_refused() {
+        let empty = TempFile::new("empty.pem", b"\n");
+        assert!(load_private_key(&empty.0, None).unwrap_err().to_string().ends_with("file is empty"));
+        assert!(load_certificates(&empty.0).unwrap_err().to_string().ends_with("file is empty"));
+    }
+
+    #[test]
+    fn loads_a_full_chain_in_file_order() {
+        let (leaf, key) = certificate(KeyType::EcdsaP256);
+        let (issuer, _) = certificate(KeyType::EcdsaP256);
+        let combined = [pem("CERTIFICATE", &leaf), pem("PRIVATE KEY", &key), pem("CERTIFICATE", &issuer)].concat();
+        let file = TempFile::new("chain.pem", &combined);
+
+        assert_eq!(load_certificates(&file.0).unwrap(), vec![leaf, issuer]);
+    }
+
+    #[test]
+    fn saves_key_pairs_in_place_of_looser_files() {
+        let (cert, key) = certificate(KeyType::EcdsaP256);
+        let key_file = TempFile::new("saved-key.pem", b"old key");
+        let cert_file = TempFile::new("saved-cert.pem", b"old cert");
+        #[cfg(unix)]
+        fs::set_permissions(&key_file.0, std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+
+        save_key_pair(&key_file.0, &cert_file.0, &key, std::slice::from_ref(&cert)).unwrap();
+
+        assert_eq!(load_private_key(&key_file.0, None).unwrap(), key);
+        assert_eq!(load_certificates(&cert_file.0).unwrap(), vec![cert]);
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&key_file.0).unwrap().permissions()) & 0o777, 0o600);
+        let leftovers = fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(Result::ok)
+            .any(|entry| entry.file_name().to_string_lossy().starts_with(&format!(".droppa-keys-{}-saved", std::process::id())));
+        assert!(!leftovers);
+    }
+
+    #[test]
+    fn key_must_match_the_leaf() {
+        let (cert, key) = certificate(KeyType::EcdsaP256);
+        let (_, other_key) = certificate(KeyType::EcdsaP256);
+
+        assert!(key_matches_certificate(&key, &cert).unwrap());
+        assert!(!key_matches_certificate(&other_key, &cert).unwrap());
+    }
+}
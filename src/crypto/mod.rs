@@ -1,2 +1,9 @@
+pub mod ca;
 pub mod certs;
-pub mod tls;
\ No newline at end of file
+pub mod client_auth;
+pub mod keys;
+pub mod pkcs12;
+pub mod sni;
+pub mod store;
+pub mod tls;
+pub mod upstream;
\ No newline at end of file
@@ -0,0 +1,130 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use p12_keystore::error::Error as Pkcs12Error;
+use p12_keystore::{Certificate, EncryptionAlgorithm, KeyStore, KeyStoreEntry, MacAlgorithm, PrivateKeyChain};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::keys::{key_matches_certificate, write_secret, CertificateChain};
+
+/// Name certificate managers show for the key pair of an exported bundle.
+const FRIENDLY_NAME: &str = "droppa";
+
+/// The chain, leaf first, and PKCS#8 DER key of the first key entry in a `.p12` / `.pfx`
+/// bundle. A bundle without a password takes none.
+pub fn load_pkcs12(path: &Path, password: Option<&str>) -> Result<(CertificateChain, Vec<u8>), Box<dyn Error>> {
+    let content = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let keystore = KeyStore::from_pkcs12(&content, password.unwrap_or("")).map_err(|e| match e {
+        Pkcs12Error::MacError(_) if password.is_none() => format!("{}: the bundle has a password, pass --pkcs12-pass", path.display()),
+        Pkcs12Error::MacError(_) => format!("{}: wrong --pkcs12-pass", path.display()),
+        e => format!("{}: not a PKCS#12 bundle droppa can read: {}", path.display(), e),
+    })?;
+
+    let (_, entry) = keystore
+        .private_key_chain()
+        .ok_or_else(|| format!("{}: no private key with a certificate in the bundle", path.display()))?;
+    let chain: CertificateChain = entry.chain().iter().map(|cert| cert.as_der().to_vec()).collect();
+    let private_key = entry.key().to_vec();
+
+    if !key_matches_certificate(&private_key, &chain[0])? {
+        return Err(format!("{}: the private key does not belong to its certificate", path.display()).into());
+    }
+
+    Ok((chain, private_key))
+}
+
+/// Writes `chain` and its PKCS#8 DER key to `path` as a PKCS#12 bundle. `legacy` picks 3DES
+/// and SHA-1, which older Windows and macOS keychains need, over AES-256 and SHA-256.
+pub fn write_pkcs12(
+    path: &Path,
+    chain: &CertificateChain,
+    private_key: &[u8],
+    password: &str,
+    legacy: bool,
+) -> Result<(), Box<dyn Error>> {
+    let certificates = chain.iter().map(|cert| Certificate::from_der(cert)).collect::<Result<Vec<_>, _>>()?;
+    let local_key_id = Sha256::digest(&chain[0]);
+
+    let mut keystore = KeyStore::new();
+    keystore.add_entry(
+        FRIENDLY_NAME,
+        KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(private_key, local_key_id, certificates)),
+    );
+
+    let writer = if legacy {
+        keystore
+            .writer(password)
+            .encryption_algorithm(EncryptionAlgorithm::PbeWithShaAnd3KeyTripleDesCbc)
+            .mac_algorithm(MacAlgorithm::HmacSha1)
+    } else {
+        keystore.writer(password)
+    };
+
+    let bundle = writer.write()?;
+    write_secret(path, &bundle)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+    use crate::crypto::certs::{issue_certificate, CertificateSpec, KeyType, SubjectAltName};
+
+    /// DER object identifiers of PBES2 and of the PKCS#12 3DES scheme.
+    const PBES2: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d];
+    const PBE_SHA_3DES: &[u8] = &[0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x01, 0x03];
+
+    /// A leaf and its issuing root, since bundles are read back by following issuers.
+    fn chain() -> (CertificateChain, Vec<u8>) {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, "test bundle CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let authority = rcgen::Certificate::from_params(params).unwrap();
+
+        let private_key = KeyType::EcdsaP256.generate().unwrap().serialize_der();
+        let spec = CertificateSpec {
+            key_type: KeyType::EcdsaP256,
+            common_name: "localhost".to_string(),
+            organization: None,
+            organizational_unit: None,
+            country: None,
+            subject_alt_names: vec![SubjectAltName::Dns("localhost".to_string())],
+            not_before: SystemTime::now(),
+            validity: Duration::from_secs(86400),
+            serial: None,
+            key_usages: Vec::new(),
+            extended_key_usages: Vec::new(),
+        };
+        let leaf = issue_certificate(&spec, &private_key, &authority).unwrap();
+        (vec![leaf, authority.serialize_der().unwrap()], private_key)
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn round_trips_bundles() {
+        let (chain, private_key) = chain();
+
+        for legacy in [false, true] {
+            let path = std::env::temp_dir().join(format!("droppa-pkcs12-{}-{}.p12", std::process::id(), legacy));
+            write_pkcs12(&path, &chain, &private_key, "hunter2", legacy).unwrap();
+            let bundle = fs::read(&path).unwrap();
+            assert_eq!(contains(&bundle, PBE_SHA_3DES), legacy);
+            assert_eq!(contains(&bundle, PBES2), !legacy);
+
+            let (loaded_chain, loaded_key) = load_pkcs12(&path, Some("hunter2")).unwrap();
+            assert_eq!(loaded_chain, chain);
+            assert_eq!(loaded_key, private_key);
+
+            let wrong = load_pkcs12(&path, Some("hunter3")).unwrap_err().to_string();
+            assert!(wrong.contains("wrong --pkcs12-pass"), "{}", wrong);
+            let missing = load_pkcs12(&path, None).unwrap_err().to_string();
+            assert!(missing.contains("pass --pkcs12-pass"), "{}", missing);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+use tracing::warn;
+
+use crate::crypto::keys::CertificateChain;
+
+/// Picks among several certificates by the name the client asks for via SNI: an exact
+/// match first, then a `*.` wildcard one label up, then the default.
+pub struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+    pub fn new(default: Arc<CertifiedKey>) -> Self {
+        Self { by_name: HashMap::new(), default }
+    }
+
+    /// Serves `certified` for `name`. The first certificate added for a name keeps it.
+    pub fn add(&mut self, name: &str, certified: Arc<CertifiedKey>) {
+        let name = name.to_ascii_lowercase();
+        if self.by_name.contains_key(&name) {
+            warn!(name, "more than one certificate for this name, keeping the first");
+            return;
+        }
+        self.by_name.insert(name, certified);
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name().map(str::to_ascii_lowercase) else {
+            return Some(self.default.clone());
+        };
+
+        let wildcard = || name.split_once('.').and_then(|(_, parent)| self.by_name.get(&format!("*.{}", parent)));
+        Some(self.by_name.get(&name).or_else(wildcard).unwrap_or(&self.default).clone())
+    }
+}
+
+/// A DER certificate chain and its PKCS#8 DER key, ready to hand to a resolver.
+pub fn certified_key(chain: CertificateChain, private_key: Vec<u8>) -> Result<Arc<CertifiedKey>, Box<dyn Error>> {
+    let signing_key = any_supported_type(&PrivateKey(private_key))?;
+    Ok(Arc::new(CertifiedKey::new(chain.into_iter().map(Certificate).collect(), signing_key)))
+}
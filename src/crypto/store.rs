@@ -0,0 +1,96 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+use crate::crypto::certs::{
+    certificate_not_after, certificate_subject_alt_names, generate_self_signed_certificate,
+    reissue_self_signed_certificate, CertificateSpec, KeyType,
+};
+use crate::crypto::keys::save_key_pair;
+use crate::crypto::tls::load_tls_files;
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Renew once this close to expiry, or a third of the validity for short-lived certificates.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// A directory holding the generated certificate and key between runs, so clients that
+/// trusted or pinned it keep working after a restart.
+pub struct CertificateStore {
+    dir: PathBuf,
+}
+
+impl CertificateStore {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_path_buf() }
+    }
+
+    /// The stored certificate and PKCS#8 key while they still fit `spec`, otherwise a new
+    /// certificate written back to the store. Renewals keep the stored key when its type
+    /// is unchanged, so public key pins survive them.
+    pub fn load_or_generate(&self, spec: &CertificateSpec) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+        let cert_path = self.dir.join(CERT_FILE);
+        let key_path = self.dir.join(KEY_FILE);
+
+        let stored = if cert_path.exists() || key_path.exists() {
+            let (chain, private_key) = load_tls_files(&key_path, &cert_path, None)?;
+            chain.into_iter().next().map(|cert| (cert, private_key))
+        } else {
+            None
+        };
+
+        let (cert, private_key) = match stored {
+            None => {
+                info!(store = %self.dir.display(), "generating certificate for the store");
+                generate_self_signed_certificate(spec)?
+            }
+            Some((cert, private_key)) => match stale_reason(spec, &cert, &private_key)? {
+                None => {
+                    info!(store = %self.dir.display(), "reusing stored certificate");
+                    return Ok((cert, private_key));
+                }
+                Some(reason) => {
+                    info!(store = %self.dir.display(), reason, "renewing stored certificate");
+                    if KeyType::of(&private_key) == Some(spec.key_type) {
+                        reissue_self_signed_certificate(spec, &private_key)?
+                    } else {
+                        generate_self_signed_certificate(spec)?
+                    }
+                }
+            },
+        };
+
+        self.save(&cert, &private_key)?;
+        Ok((cert, private_key))
+    }
+
+    fn save(&self, cert: &[u8], private_key: &[u8]) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        save_key_pair(&self.dir.join(KEY_FILE), &self.dir.join(CERT_FILE), private_key, &[cert.to_vec()])?;
+        Ok(())
+    }
+}
+
+/// Why the stored certificate no longer fits `spec`, or `None` when it can be reused.
+fn stale_reason(spec: &CertificateSpec, cert: &[u8], private_key: &[u8]) -> Result<Option<String>, Box<dyn Error>> {
+    let not_after = certificate_not_after(cert)?;
+    if not_after < SystemTime::now() + RENEW_BEFORE.min(spec.validity / 3) {
+        return Ok(Some(format!("expires {}", humantime::format_rfc3339_seconds(not_after))));
+    }
+
+    let stored_names = certificate_subject_alt_names(cert)?;
+    let same_names = stored_names.iter().all(|name| spec.subject_alt_names.contains(name))
+        && spec.subject_alt_names.iter().all(|name| stored_names.contains(name));
+    if !same_names {
+        return Ok(Some("subject alternative names changed".to_string()));
+    }
+
+    if KeyType::of(private_key) != Some(spec.key_type) {
+        return Ok(Some("key type changed".to_string()));
+    }
+
+    Ok(None)
+}
@@ -1,19 +1,21 @@
-use rustls_pemfile::{certs, pkcs8_private_keys};
 use tokio_rustls::rustls::{Certificate, ClientConfig, Error as RustlsError, PrivateKey, RootCertStore, ServerConfig};
 use tokio_rustls::TlsAcceptor;
 use tokio_rustls::client::TlsStream;
-use std::fs::File;
 use std::path::Path;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use std::error::Error;
 use tokio_rustls::rustls::client::{ServerCertVerifier, ServerCertVerified};
-use std::io::BufReader;
+use tokio_rustls::rustls::server::{ClientCertVerifier, ResolvesServerCert};
 
-use crate::crypto::certs::generate_self_signed_certificate;
+use crate::acme::challenges::{AcmeResolver, ChallengeType, ACME_TLS_ALPN};
+use crate::crypto::keys::{key_matches_certificate, load_certificates, load_private_key, CertificateChain};
+
+/// How long a client gets to finish the TLS handshake, so a stalled one does not hold up shutdown.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 struct NoCertVerification;
 
@@ -33,7 +35,7 @@ impl ServerCertVerifier for NoCertVerification {
 
 pub enum MaybeTlsStream {
     Plain(TcpStream),
-    Tls(TlsStream<TcpStream>),
+    Tls(Box<TlsStream<TcpStream>>),
 }
 
 impl MaybeTlsStream {
@@ -52,35 +54,64 @@ impl MaybeTlsStream {
     }
 }
 
-pub fn prepare_tls_cert(
-    ssl_issuer: &str,
-    private_key_path: Option<&Path>,
-    cert_path: Option<&Path>,
-) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
-    match (private_key_path, cert_path) {
-        (Some(key_path), Some(cert_path)) => {
-            let key_file = File::open(key_path)?;
-            let cert_file = File::open(cert_path)?;
-            let private_key = pkcs8_private_keys(&mut BufReader::new(key_file))?.remove(0);
-            let cert = certs(&mut BufReader::new(cert_file))?.remove(0);
-            Ok((cert, private_key))
-        }
-        _ => generate_self_signed_certificate(ssl_issuer),
+/// The certificate chain, leaf first, and the PKCS#8 DER key that belongs to the leaf.
+pub fn load_tls_files(key_path: &Path, cert_path: &Path, passphrase: Option<&str>) -> Result<(CertificateChain, Vec<u8>), Box<dyn Error>> {
+    let chain = load_certificates(cert_path)?;
+    let private_key = load_private_key(key_path, passphrase)?;
+
+    if !key_matches_certificate(&private_key, &chain[0])? {
+        return Err(format!(
+            "{} does not belong to the first certificate in {}, which must be the server's own before any intermediates",
+            key_path.display(),
+            cert_path.display(),
+        )
+        .into());
     }
+
+    Ok((chain, private_key))
 }
 
-pub fn generate_tls_acceptor(cert: Vec<u8>, private_key: Vec<u8>) -> Result<TlsAcceptor, Box<dyn Error>> {
-    let rustls_cert = Certificate(cert);
+pub fn generate_tls_acceptor(
+    chain: CertificateChain,
+    private_key: Vec<u8>,
+    clients: Arc<dyn ClientCertVerifier>,
+) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let rustls_chain = chain.into_iter().map(Certificate).collect();
     let rustls_private_key = PrivateKey(private_key);
 
     let config = ServerConfig::builder()
         .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(vec![rustls_cert], rustls_private_key)?;
+        .with_client_cert_verifier(clients)
+        .with_single_cert(rustls_chain, rustls_private_key)?;
 
     Ok(TlsAcceptor::from(Arc::new(config)))
 }
 
+/// An acceptor that picks the certificate per handshake instead of serving a single one.
+pub fn resolving_tls_acceptor(resolver: Arc<dyn ResolvesServerCert>, clients: Arc<dyn ClientCertVerifier>) -> TlsAcceptor {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(clients)
+        .with_cert_resolver(resolver);
+
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Like `resolving_tls_acceptor`, but also offers the protocol TLS-ALPN-01 validation
+/// handshakes with. rustls turns away clients whose ALPN list misses every offered
+/// protocol, so `http/1.1` is offered too, and nothing is offered without TLS-ALPN-01.
+pub fn acme_tls_acceptor(resolver: Arc<AcmeResolver>, challenge: ChallengeType, clients: Arc<dyn ClientCertVerifier>) -> TlsAcceptor {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(clients)
+        .with_cert_resolver(resolver);
+    if challenge == ChallengeType::TlsAlpn01 {
+        config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec(), b"http/1.1".to_vec()];
+    }
+
+    TlsAcceptor::from(Arc::new(config))
+}
+
 pub fn generate_tls_connector() -> Result<ClientConfig, Box<dyn Error>> {
     let mut config = ClientConfig::builder()
         .with_safe_defaults()
@@ -94,3 +125,97 @@ pub fn generate_tls_connector() -> Result<ClientConfig, Box<dyn Error>> {
 
     Ok(config)
 }
+
+/// A client config that verifies servers against the system roots, plus the PEM or DER
+/// certificates in `extra_roots`.
+pub fn verifying_tls_connector(extra_roots: Option<&Path>) -> Result<ClientConfig, Box<dyn Error>> {
+    let mut roots = RootCertStore::empty();
+    let native: Vec<Vec<u8>> = rustls_native_certs::load_native_certs()?.into_iter().map(|cert| cert.0).collect();
+    // System stores can hold certificates webpki cannot parse; those are skipped.
+    roots.add_parsable_certificates(&native);
+
+    if let Some(path) = extra_roots {
+        for cert in load_certificates(path)? {
+            roots.add(&Certificate(cert)).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+    }
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio_rustls::rustls::server::NoClientAuth;
+    use tokio_rustls::rustls::ServerName;
+    use tokio_rustls::TlsConnector;
+    use crate::crypto::certs::{generate_self_signed_certificate, CertificateSpec, ExtendedKeyUsage, KeyType, KeyUsage, SubjectAltName};
+
+    /// The default verifier is skipped, but the handshake signature is still checked.
+    async fn handshake(key_type: KeyType) {
+        let spec = CertificateSpec {
+            key_type,
+            common_name: "localhost".to_string(),
+            organization: None,
+            organizational_unit: None,
+            country: None,
+            subject_alt_names: vec![SubjectAltName::Dns("localhost".to_string())],
+            not_before: SystemTime::now() - Duration::from_secs(3600),
+            validity: Duration::from_secs(86400),
+            serial: None,
+            key_usages: vec![KeyUsage::DigitalSignature],
+            extended_key_usages: vec![ExtendedKeyUsage::ServerAuth],
+        };
+        let (cert, private_key) = generate_self_signed_certificate(&spec).unwrap();
+        let acceptor = generate_tls_acceptor(vec![cert], private_key, NoClientAuth::new()).unwrap();
+        let connector = TlsConnector::from(Arc::new(generate_tls_connector().unwrap()));
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), client).await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(received, b"hello", "{}", key_type);
+    }
+
+    #[tokio::test]
+    async fn handshake_rsa2048() {
+        handshake(KeyType::Rsa2048).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_rsa3072() {
+        handshake(KeyType::Rsa3072).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_rsa4096() {
+        handshake(KeyType::Rsa4096).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_ecdsa_p256() {
+        handshake(KeyType::EcdsaP256).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_ecdsa_p384() {
+        handshake(KeyType::EcdsaP384).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_ed25519() {
+        handshake(KeyType::Ed25519).await;
+    }
+}
@@ -0,0 +1,345 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{Certificate, ClientConfig, Error as RustlsError, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::FromDer;
+
+use crate::crypto::certs::{certificate_subject_alt_names, sha256_fingerprint, spki_fingerprint, SubjectAltName};
+use crate::crypto::keys::load_certificates;
+use crate::crypto::tls::generate_tls_connector;
+
+/// The signature algorithms rustls itself accepts from servers.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// How the reverse proxy checks the certificate of an `https://` upstream, from `--upstream-verify`.
+#[derive(Clone, PartialEq, Eq)]
+pub enum UpstreamVerify {
+    /// Anything goes, which is what impersonating a lab target usually needs.
+    None,
+    /// The operating system's trust store.
+    System,
+    /// Only CAs from this PEM or DER bundle.
+    Ca(PathBuf),
+    /// SHA-256 hashes of the leaf's SubjectPublicKeyInfo; the chain and names are not looked at.
+    Pin(Vec<[u8; 32]>),
+}
+
+impl FromStr for UpstreamVerify {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            _ if value == "none" => Ok(UpstreamVerify::None),
+            _ if value == "system" => Ok(UpstreamVerify::System),
+            Some(("ca", path)) if !path.is_empty() => Ok(UpstreamVerify::Ca(PathBuf::from(path))),
+            Some(("pin", pins)) => pins.split(',').map(parse_pin).collect::<Result<_, _>>().map(UpstreamVerify::Pin),
+            _ => Err(format!("unknown mode {}, expected none, system, ca:<file> or pin:<sha256>", value)),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamVerify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamVerify::None => f.write_str("none"),
+            UpstreamVerify::System => f.write_str("system"),
+            UpstreamVerify::Ca(path) => write!(f, "ca:{}", path.display()),
+            UpstreamVerify::Pin(pins) => {
+                let pins: Vec<String> = pins.iter().map(|pin| STANDARD.encode(pin)).collect();
+                write!(f, "pin:{}", pins.join(","))
+            }
+        }
+    }
+}
+
+/// A pin in the `sha256//<base64>` form droppa and curl print, bare base64, or hex.
+fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+    let pin = pin.trim().trim_start_matches("sha256//");
+    let hex = pin.replace(':', "");
+    let bytes = if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..64).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16)).collect::<Result<Vec<u8>, _>>().map_err(|e| e.to_string())?
+    } else {
+        STANDARD.decode(pin).map_err(|_| format!("{}: expected a SHA-256 public key pin in base64 or hex", pin))?
+    };
+    <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| format!("{}: a SHA-256 pin is 32 bytes", pin))
+}
+
+/// The TLS side of connecting to the upstream, resolved from the `--upstream-*` settings.
+pub struct UpstreamTls {
+    pub verify: UpstreamVerify,
+    /// Sent as SNI and, with `check_hostname`, looked for in the certificate.
+    pub server_name: String,
+    pub check_hostname: bool,
+}
+
+impl UpstreamTls {
+    pub fn connector(&self) -> Result<UpstreamConnector, Box<dyn Error>> {
+        let server_name = ServerName::try_from(self.server_name.as_str()).map_err(|_| format!("{}: invalid server name", self.server_name))?;
+
+        let verifier = match &self.verify {
+            UpstreamVerify::None => return Ok(UpstreamConnector { connector: TlsConnector::from(Arc::new(generate_tls_connector()?)), server_name }),
+            UpstreamVerify::System => {
+                let native: Vec<Vec<u8>> = rustls_native_certs::load_native_certs()?.into_iter().map(|cert| cert.0).collect();
+                // System stores can hold certificates webpki cannot parse; those are skipped.
+                let roots: Vec<Vec<u8>> = native.into_iter().filter(|der| webpki::TrustAnchor::try_from_cert_der(der).is_ok()).collect();
+                if roots.is_empty() {
+                    return Err("no usable certificates in the system trust store".into());
+                }
+                UpstreamVerifier { name: self.server_name.clone(), roots, pins: Vec::new(), check_hostname: self.check_hostname }
+            }
+            UpstreamVerify::Ca(path) => {
+                let roots = load_certificates(path)?;
+                for der in &roots {
+                    webpki::TrustAnchor::try_from_cert_der(der).map_err(|e| format!("{}: {:?}", path.display(), e))?;
+                }
+                UpstreamVerifier { name: self.server_name.clone(), roots, pins: Vec::new(), check_hostname: self.check_hostname }
+            }
+            UpstreamVerify::Pin(pins) => UpstreamVerifier {
+                name: self.server_name.clone(),
+                roots: Vec::new(),
+                pins: pins.clone(),
+                check_hostname: false,
+            },
+        };
+
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        config.dangerous().set_certificate_verifier(Arc::new(verifier));
+
+        Ok(UpstreamConnector { connector: TlsConnector::from(Arc::new(config)), server_name })
+    }
+}
+
+/// Built once at startup and shared by every proxied connection.
+pub struct UpstreamConnector {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+impl UpstreamConnector {
+    pub async fn connect(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        self.connector.connect(self.server_name.clone(), stream).await
+    }
+}
+
+struct UpstreamVerifier {
+    /// The server name, for the log.
+    name: String,
+    /// DER trust anchors; empty when pinning.
+    roots: Vec<Vec<u8>>,
+    pins: Vec<[u8; 32]>,
+    check_hostname: bool,
+}
+
+impl UpstreamVerifier {
+    fn verify(&self, end_entity: &Certificate, intermediates: &[Certificate], server_name: &ServerName, now: SystemTime) -> Result<(), String> {
+        let leaf = X509Certificate::from_der(&end_entity.0).map_err(|e| format!("cannot parse certificate: {}", e))?.1;
+
+        if !self.pins.is_empty() {
+            let spki: [u8; 32] = Sha256::digest(leaf.public_key().raw).into();
+            return match self.pins.contains(&spki) {
+                true => Ok(()),
+                false => Err(format!("public key sha256//{} matches no pin", STANDARD.encode(spki))),
+            };
+        }
+
+        let cert = webpki::EndEntityCert::try_from(end_entity.0.as_slice()).map_err(|e| format!("{:?}", e))?;
+        let anchors: Vec<webpki::TrustAnchor> = self.roots.iter().filter_map(|der| webpki::TrustAnchor::try_from_cert_der(der).ok()).collect();
+        let chain: Vec<&[u8]> = intermediates.iter().map(|cert| cert.0.as_slice()).collect();
+        let now = webpki::Time::try_from(now).map_err(|_| "cannot read the clock".to_string())?;
+        cert.verify_is_valid_tls_server_cert(SIGNATURE_ALGORITHMS, &webpki::TlsServerTrustAnchors(&anchors), &chain, now)
+            .map_err(|e| format!("{:?}", e))?;
+
+        if !self.check_hostname {
+            return Ok(());
+        }
+        match server_name {
+            ServerName::DnsName(name) => {
+                let name = webpki::DnsNameRef::try_from_ascii_str(name.as_ref()).map_err(|_| "invalid server name".to_string())?;
+                cert.verify_is_valid_for_dns_name(name).map_err(|_| "certificate is not valid for the server name".to_string())
+            }
+            // webpki only checks DNS names, so IP addresses are looked up among the SANs here.
+            ServerName::IpAddress(ip) => {
+                let names = certificate_subject_alt_names(&end_entity.0).map_err(|e| e.to_string())?;
+                match names.contains(&SubjectAltName::Ip(*ip)) {
+                    true => Ok(()),
+                    false => Err(format!("certificate is not valid for {}", ip)),
+                }
+            }
+            _ => Err("unsupported server name".to_string()),
+        }
+    }
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        match self.verify(end_entity, intermediates, server_name, now) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(err) => {
+                warn!(server_name = self.name, error = err, "upstream certificate rejected");
+                for (depth, cert) in std::iter::once(end_entity).chain(intermediates).enumerate() {
+                    log_presented(depth, cert);
+                }
+                Err(RustlsError::InvalidCertificateData(err))
+            }
+        }
+    }
+}
+
+/// One line per certificate the upstream sent, leaf first, for working out what it should have sent.
+fn log_presented(depth: usize, cert: &Certificate) {
+    let sha256 = sha256_fingerprint(&cert.0);
+    match X509Certificate::from_der(&cert.0) {
+        Ok((_, parsed)) => {
+            let names = certificate_subject_alt_names(&cert.0).unwrap_or_default();
+            let names: Vec<String> = names
+                .iter()
+                .map(|name| match name {
+                    SubjectAltName::Dns(name) => name.clone(),
+                    SubjectAltName::Ip(ip) => ip.to_string(),
+                })
+                .collect();
+            warn!(
+                depth,
+                subject = %parsed.subject(),
+                issuer = %parsed.issuer(),
+                san = names.join(","),
+                not_after = %parsed.validity().not_after,
+                sha256,
+                spki = format!("sha256//{}", spki_fingerprint(&cert.0).unwrap_or_default()),
+                "presented certificate"
+            );
+        }
+        Err(_) => warn!(depth, sha256, "presented certificate, unparsable"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::path::Path;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, SanType};
+
+    fn authority() -> rcgen::Certificate {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, "test upstream CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn leaf(ca: &rcgen::Certificate, name: SanType) -> Certificate {
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = vec![name];
+        Certificate(rcgen::Certificate::from_params(params).unwrap().serialize_der_with_signer(ca).unwrap())
+    }
+
+    fn verifier(roots: Vec<Vec<u8>>, pins: Vec<[u8; 32]>) -> UpstreamVerifier {
+        UpstreamVerifier { name: "upstream".to_string(), roots, pins, check_hostname: true }
+    }
+
+    fn dns(name: &str) -> ServerName {
+        ServerName::try_from(name).unwrap()
+    }
+
+    #[test]
+    fn parses_pins_in_every_form() {
+        let pin = [0xab; 32];
+        let base64 = STANDARD.encode(pin);
+        let colons = vec!["ab"; 32].join(":");
+        for written in [format!("sha256//{}", base64), base64.clone(), "AB".repeat(32), colons] {
+            assert_eq!(parse_pin(&written), Ok(pin), "{}", written);
+        }
+    }
+
+    #[test]
+    fn refuses_pins_of_the_wrong_length() {
+        assert!(parse_pin(&STANDARD.encode([0xab; 31])).unwrap_err().contains("32 bytes"));
+        assert!(parse_pin(&"ab".repeat(33)).is_err());
+        assert!(parse_pin("not a pin!").is_err());
+    }
+
+    #[test]
+    fn parses_verify_modes() {
+        assert!(matches!("none".parse(), Ok(UpstreamVerify::None)));
+        assert!(matches!("system".parse(), Ok(UpstreamVerify::System)));
+        assert!(matches!("ca:/etc/ca.pem".parse(), Ok(UpstreamVerify::Ca(path)) if path == Path::new("/etc/ca.pem")));
+        let two = format!("pin:{},sha256//{}", "AB".repeat(32), STANDARD.encode([0xcd; 32]));
+        assert!(matches!(two.parse(), Ok(UpstreamVerify::Pin(pins)) if pins == vec![[0xab; 32], [0xcd; 32]]));
+        for wrong in ["ca:", "pin:", "strict"] {
+            assert!(wrong.parse::<UpstreamVerify>().is_err(), "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn pins_match_the_public_key_only() {
+        let cert = leaf(&authority(), SanType::DnsName("localhost".to_string()));
+        let pin = parse_pin(&spki_fingerprint(&cert.0).unwrap()).unwrap();
+        let now = SystemTime::now();
+
+        assert!(verifier(Vec::new(), vec![pin]).verify(&cert, &[], &dns("elsewhere"), now).is_ok());
+        let refused = verifier(Vec::new(), vec![[0; 32]]).verify(&cert, &[], &dns("localhost"), now).unwrap_err();
+        assert!(refused.contains("matches no pin"));
+    }
+
+    #[test]
+    fn ca_mode_needs_the_issuing_ca() {
+        let (issuer, stranger) = (authority(), authority());
+        let cert = leaf(&issuer, SanType::DnsName("localhost".to_string()));
+        let now = SystemTime::now();
+
+        assert!(verifier(vec![issuer.serialize_der().unwrap()], Vec::new()).verify(&cert, &[], &dns("localhost"), now).is_ok());
+        assert!(verifier(vec![stranger.serialize_der().unwrap()], Vec::new()).verify(&cert, &[], &dns("localhost"), now).is_err());
+        let misnamed = verifier(vec![issuer.serialize_der().unwrap()], Vec::new()).verify(&cert, &[], &dns("elsewhere"), now);
+        assert_eq!(misnamed, Err("certificate is not valid for the server name".to_string()));
+    }
+
+    #[test]
+    fn ip_addresses_are_looked_up_among_the_sans() {
+        let issuer = authority();
+        let cert = leaf(&issuer, SanType::IpAddress("127.0.0.1".parse().unwrap()));
+        let roots = vec![issuer.serialize_der().unwrap()];
+        let ip = |ip: &str| ServerName::IpAddress(ip.parse::<IpAddr>().unwrap());
+        let now = SystemTime::now();
+
+        assert!(verifier(roots.clone(), Vec::new()).verify(&cert, &[], &ip("127.0.0.1"), now).is_ok());
+        assert_eq!(verifier(roots, Vec::new()).verify(&cert, &[], &ip("127.0.0.2"), now), Err("certificate is not valid for 127.0.0.2".to_string()));
+    }
+}
@@ -0,0 +1,54 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tracing::{debug, warn};
+
+use crate::http::response::{self, Body};
+use crate::lifecycle::metrics;
+use crate::lifecycle::shutdown::Shutdown;
+use crate::transport::listener::{Listener, ACCEPT_RETRY_DELAY};
+
+/// Serves `/metrics`, `/healthz` and `/readyz` in plain HTTP. It keeps answering while the
+/// public listeners drain, so `/readyz` can report the shutdown.
+pub async fn start_admin_server(listener: Listener, shutdown: Shutdown, ready: Arc<AtomicBool>) -> Result<(), Box<dyn Error>> {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(listener = %listener.local_addr(), error = %err, "accept failed, retrying");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let shutdown = shutdown.clone();
+        let ready = ready.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle_request(request, shutdown.clone(), ready.clone()));
+
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                debug!(peer = %remote_addr, error = %e, "error serving admin connection");
+            }
+        });
+    }
+}
+
+async fn handle_request(request: Request<Incoming>, shutdown: Shutdown, ready: Arc<AtomicBool>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => response::metrics(metrics::render(&shutdown.transfers.stats())),
+        (&Method::GET, "/healthz") => response::text("ok\n", StatusCode::OK),
+        (&Method::GET, "/readyz") if shutdown.is_triggered() => response::text("shutting down\n", StatusCode::SERVICE_UNAVAILABLE),
+        (&Method::GET, "/readyz") if !ready.load(Ordering::SeqCst) => response::text("starting\n", StatusCode::SERVICE_UNAVAILABLE),
+        (&Method::GET, "/readyz") => response::text("ready\n", StatusCode::OK),
+        _ => response::empty_404(),
+    };
+
+    Ok(response)
+}
@@ -0,0 +1,50 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::body::Incoming;
+use hyper::header::AUTHORIZATION;
+use hyper::Request;
+use rand::RngCore;
+
+/// Returns a random 128-bit token, hex encoded.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Accepts `Authorization: Bearer <token>`, or Basic auth with the token as the password so
+/// browsers can use their login prompt.
+pub fn is_authorized(request: &Request<Incoming>, token: &str) -> bool {
+    let header = match request.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok()) {
+        Some(header) => header,
+        None => return false,
+    };
+
+    if let Some(bearer) = header.strip_prefix("Bearer ") {
+        return constant_time_eq(bearer.trim().as_bytes(), token.as_bytes());
+    }
+
+    if let Some(basic) = header.strip_prefix("Basic ") {
+        let decoded = match STANDARD.decode(basic.trim()) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+
+        let password = match decoded.iter().position(|&byte| byte == b':') {
+            Some(colon) => &decoded[colon + 1..],
+            None => return false,
+        };
+
+        return constant_time_eq(password, token.as_bytes());
+    }
+
+    false
+}
+
+/// Who to record for a request let in by the token. The Basic auth username is not it, since
+/// the client picks that freely and only the password is checked.
+pub const TOKEN_USER: &str = "token";
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::acme::challenges::Challenges;
+use crate::crypto::client_auth::{ClientAuth, ClientIdentity};
+use crate::lifecycle::audit::AuditLog;
+use crate::lifecycle::events::DirectoryEvents;
+use crate::lifecycle::transfers::Transfers;
+use crate::transport::stream::PeerAddr;
+use crate::views::oneliners::CertificatePins;
+
+pub struct ServerContext {
+    pub dir: Arc<PathBuf>,
+    pub transfers: Arc<Transfers>,
+    pub redirect_https: bool,
+    pub auth_token: Option<String>,
+    /// The certificate is self-signed, so generated client commands skip verification.
+    pub self_signed: bool,
+    /// Fingerprints of the served certificate, for commands that pin it instead.
+    pub pins: Option<Arc<CertificatePins>>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub events: Arc<DirectoryEvents>,
+    /// Pending ACME HTTP-01 tokens, answered ahead of any redirect or auth check.
+    pub acme_challenges: Option<Arc<Challenges>>,
+    /// Set with `--client-ca`; TLS connections then carry the identity it admitted.
+    pub client_auth: Option<Arc<ClientAuth>>,
+}
+
+#[derive(Clone)]
+pub struct Connection {
+    /// The id the connection is tracked under in `Transfers::activity`.
+    pub id: u64,
+    pub remote_addr: PeerAddr,
+    pub secure: bool,
+    /// Who the client certificate says is on the other end, when one was presented.
+    pub client: Option<Arc<ClientIdentity>>,
+}
+
+/// Who made a request, as recorded in the audit log.
+pub struct Client {
+    pub connection: u64,
+    pub remote_addr: PeerAddr,
+    pub user: Option<String>,
+    /// SHA-256 fingerprint of the client certificate.
+    pub certificate: Option<String>,
+}
@@ -1,27 +1,44 @@
 use ammonia::clean;
-use rouille::input::multipart::{get_multipart_input, MultipartError};
-use rouille::{Request, Response};
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::sync::Arc;
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Method, Request, Response, StatusCode};
+use multer::{Field, Multipart};
+use percent_encoding::percent_decode_str;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use crate::http::context::{Client, Connection, ServerContext};
+use crate::lifecycle::audit::{AuditedTransfer, Direction};
+use crate::lifecycle::events::UploadNotice;
+use crate::lifecycle::transfers::TrackedTransfer;
+use crate::http::response::{self, Body};
+use crate::transport::stream::PeerAddr;
+use crate::views::oneliners::Target;
 use crate::views::views::index_view;
 
-pub fn index(request: &Request, dir: &PathBuf) -> Response {
+pub async fn index(context: &ServerContext, base_url: Option<&str>) -> Response<Body> {
+    let target = base_url.map(|base_url| Target {
+        base_url,
+        auth_token: context.auth_token.as_deref(),
+        insecure: context.self_signed && base_url.starts_with("https://"),
+        pins: context.pins.as_deref(),
+    });
+
+    response::html(index_view(list_files(&context.dir).await, target.as_ref()))
+}
+
+/// Names of the regular files at the top of `dir`, the ones the listing shows.
+pub async fn list_files(dir: &Path) -> Vec<String> {
     let mut files = Vec::new();
 
-    let entries = match fs::read_dir(dir) {
+    let mut entries = match fs::read_dir(dir).await {
         Ok(entries) => entries,
-        Err(_) => return Response::html(index_view(files)),
+        Err(_) => return files,
     };
 
-    for entry in entries {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(_) => continue,
-        };
-
-        let file_type = match entry.file_type() {
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_type = match entry.file_type().await {
             Ok(file_type) => file_type,
             Err(_) => continue,
         };
@@ -31,67 +48,230 @@ pub fn index(request: &Request, dir: &PathBuf) -> Response {
         }
 
         if let Some(file_name) = entry.file_name().to_str() {
-            let sanitized_file_name = clean(file_name);
-            files.push(sanitized_file_name.to_string());
+            files.push(file_name.to_string());
         }
     }
 
-    Response::html(index_view(files))
+    files
 }
 
-pub fn get(request: &Request, dir: &PathBuf) -> Response {
-    let filepath = dir.join(&request.url()[1..]);
-    if filepath.is_file() {
-        match fs::read(filepath) {
-            Ok(content) => Response::from_data("application/octet-stream", content),
-            Err(_) => Response::empty_404(),
+pub async fn get(request: &Request<Incoming>, client: &Client, context: &ServerContext) -> Response<Body> {
+    let filepath = match resolve_path(&context.dir, request.uri().path()) {
+        Some(filepath) => filepath,
+        None => return response::empty_404(),
+    };
+
+    let file = match File::open(&filepath).await {
+        Ok(file) => file,
+        Err(_) => return response::empty_404(),
+    };
+
+    match file.metadata().await {
+        // No body goes out, so it is neither a download nor worth an audit line.
+        Ok(metadata) if metadata.is_file() && request.method() == Method::HEAD => response::file_stream(file, metadata.len(), |_| {}),
+        Ok(metadata) if metadata.is_file() => {
+            context.transfers.record_download();
+            let path = audit_path(&context.dir, &filepath);
+            let tracked = context.transfers.track(client.connection, Direction::Download, path.clone(), Some(metadata.len()));
+            let mut audit = AuditedTransfer::begin(
+                context.audit.as_ref(),
+                Direction::Download,
+                client.remote_addr,
+                client.user.clone(),
+                path,
+            )
+            .status(StatusCode::OK.as_u16())
+            .certificate(client.certificate.clone())
+            .expect(metadata.len());
+
+            response::file_stream(file, metadata.len(), move |chunk| {
+                tracked.sent(chunk.len());
+                audit.update(chunk);
+            })
         }
-    } else {
-        Response::empty_404()
+        _ => response::empty_404(),
     }
 }
 
-pub fn store(request: &Request, dir: &Arc<PathBuf>) -> Response {
-    let mut multipart = match get_multipart_input(request) {
-        Ok(multipart) => multipart,
-        Err(MultipartError::WrongContentType) => {
-            return Response::text("Invalid Content-Type").with_status_code(400)
-        }
-        Err(MultipartError::BodyAlreadyExtracted) => {
-            return Response::text("Body already extracted").with_status_code(400)
-        }
+pub async fn store(request: Request<Incoming>, client: &Client, context: &ServerContext, base_url: Option<&str>) -> Response<Body> {
+    let boundary = match request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| multer::parse_boundary(value).ok())
+    {
+        Some(boundary) => boundary,
+        None => return reject(context, ("content_type", "Invalid Content-Type", StatusCode::BAD_REQUEST)),
     };
 
+    let mut multipart = Multipart::new(request.into_body().into_data_stream(), boundary);
+
     let mut files_saved = 0;
 
-    while let Some(mut field) = multipart.next() {
-        if let Some(filename) = field.headers.filename.clone() {
-            let sanitized_filename = clean(&filename);
-            let filepath = dir.join(sanitized_filename.to_string());
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(_) => return reject(context, ("malformed", "Malformed multipart body", StatusCode::BAD_REQUEST)),
+        };
 
-            let mut file = match File::create(&filepath) {
-                Ok(file) => file,
-                Err(_) => return Response::text("Failed to create file").with_status_code(500),
+        if let Some(filename) = field.file_name() {
+            let Some(sanitized_filename) = upload_name(&clean(filename)) else {
+                return reject(context, ("filename", "Invalid file name", StatusCode::BAD_REQUEST));
             };
+            let filepath = context.dir.join(&sanitized_filename);
 
-            let mut buffer = [0u8; 4096];
-            while let Ok(bytes_read) = field.data.read(&mut buffer) {
-                if bytes_read == 0 {
-                    break;
-                }
-                
-                if let Err(_) = file.write_all(&buffer[..bytes_read]) {
-                    return Response::text("Failed to write file").with_status_code(500);
+            let path = audit_path(&context.dir, &filepath);
+            let tracked = context.transfers.track(client.connection, Direction::Upload, path.clone(), None);
+            let mut audit = AuditedTransfer::begin(
+                context.audit.as_ref(),
+                Direction::Upload,
+                client.remote_addr,
+                client.user.clone(),
+                path,
+            )
+            .certificate(client.certificate.clone());
+
+            let bytes = match receive_file(&mut field, &filepath, &tracked, &mut audit, context).await {
+                Ok(bytes) => bytes,
+                Err(rejection) => {
+                    audit.abort(rejection.2.as_u16());
+                    return reject(context, rejection);
                 }
-            }
-            
+            };
+
+            audit.status(StatusCode::OK.as_u16()).complete();
+            context.transfers.record_upload();
+            context.events.upload_received(UploadNotice {
+                name: sanitized_filename,
+                bytes,
+                client: client_name(client.remote_addr),
+            });
             files_saved += 1;
         }
     }
 
     if files_saved > 0 {
-        return index(request, dir);
+        index(context, base_url).await
     } else {
-        return Response::text("No files uploaded").with_status_code(400);
+        reject(context, ("empty", "No files uploaded", StatusCode::BAD_REQUEST))
+    }
+}
+
+async fn receive_file(
+    field: &mut Field<'_>,
+    filepath: &Path,
+    tracked: &TrackedTransfer,
+    audit: &mut AuditedTransfer,
+    context: &ServerContext,
+) -> Result<u64, Rejection> {
+    let mut file = File::create(filepath)
+        .await
+        .map_err(|_| ("create", "Failed to create file", StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let mut bytes = 0;
+
+    loop {
+        // Not reading applies backpressure, so a paused upload stalls the client.
+        context.transfers.activity.wait_uploads_resumed().await;
+
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => return Err(("read", "Failed to read upload", StatusCode::BAD_REQUEST)),
+        };
+
+        file.write_all(&chunk)
+            .await
+            .map_err(|_| ("write", "Failed to write file", StatusCode::INTERNAL_SERVER_ERROR))?;
+
+        tracked.received(chunk.len());
+        audit.update(&chunk);
+        bytes += chunk.len() as u64;
+    }
+
+    file.flush()
+        .await
+        .map_err(|_| ("write", "Failed to write file", StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(bytes)
+}
+
+/// Why an upload was refused: the metrics label, the message for the client and the status.
+type Rejection = (&'static str, &'static str, StatusCode);
+
+fn reject(context: &ServerContext, (reason, message, status): Rejection) -> Response<Body> {
+    context.transfers.record_upload_rejection(reason);
+    response::text(message, status)
+}
+
+/// The client's address without the port, as the upload notice shows it.
+fn client_name(remote_addr: PeerAddr) -> String {
+    match remote_addr {
+        PeerAddr::Tcp(addr) => addr.ip().to_string(),
+        PeerAddr::Unix => "unix socket".to_string(),
+    }
+}
+
+/// The path relative to the served directory, as it appears in the audit log.
+fn audit_path(dir: &Path, filepath: &Path) -> String {
+    filepath.strip_prefix(dir).unwrap_or(filepath).display().to_string()
+}
+
+/// The origin the client reached us on, which is what pasted commands should point at.
+pub fn base_url(request: &Request<Incoming>, connection: &Connection) -> Option<String> {
+    let host = request.headers().get(HOST).and_then(|value| value.to_str().ok())?;
+    let scheme = if connection.secure { "https" } else { "http" };
+    Some(format!("{}://{}", scheme, host))
+}
+
+pub fn redirect_https(request: &Request<Incoming>) -> Response<Body> {
+    let host = match request.headers().get(HOST).and_then(|value| value.to_str().ok()) {
+        Some(host) => host,
+        None => return response::text("Missing Host header", StatusCode::BAD_REQUEST),
+    };
+
+    let path = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+
+    response::redirect(&format!("https://{}{}", host, path))
+}
+
+/// The last component of a multipart file name. Names that are empty, absolute or climb with
+/// `..` are refused rather than trimmed, since the client meant somewhere else.
+fn upload_name(filename: &str) -> Option<String> {
+    let path = Path::new(filename);
+    if path.is_absolute() || path.has_root() || path.components().any(|component| component == Component::ParentDir) {
+        return None;
+    }
+    let name = path.file_name()?.to_str()?;
+    Some(name.to_string())
+}
+
+fn resolve_path(dir: &Path, url_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(url_path.trim_start_matches('/')).decode_utf8().ok()?;
+    let relative = Path::new(decoded.as_ref());
+
+    if relative.components().any(|component| !matches!(component, Component::Normal(_))) {
+        return None;
+    }
+
+    Some(dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_name_keeps_plain_names() {
+        assert_eq!(upload_name("nc.exe").as_deref(), Some("nc.exe"));
+        assert_eq!(upload_name("loot/creds.txt").as_deref(), Some("creds.txt"));
+    }
+
+    #[test]
+    fn upload_name_refuses_traversal() {
+        for filename in ["../../x", "../x", "a/../../x", "/etc/x", "..", ".", ""] {
+            assert_eq!(upload_name(filename), None, "{}", filename);
+        }
     }
 }
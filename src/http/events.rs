@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use futures_util::stream;
+use hyper::Response;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio_util::sync::CancellationToken;
+
+use crate::http::context::ServerContext;
+use crate::http::controller::list_files;
+use crate::http::response::{self, sse_event, Body};
+use crate::lifecycle::events::DirectoryEvent;
+use crate::views::oneliners::{CertificatePins, Target};
+use crate::views::views::file_list_view;
+
+/// Served ahead of the files, so a file by this name is shadowed.
+pub const EVENTS_PATH: &str = "/.droppa/events";
+
+/// Proxies drop idle streams, so say something now and then.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Streams the web GUI's live updates: a fresh `listing` of `<li>` entries on connect and
+/// whenever the directory changes, and an `upload` notice for every file received.
+pub fn events(context: &ServerContext, base_url: Option<&str>) -> Response<Body> {
+    let (receiver, closed) = context.events.subscribe();
+
+    let feed = EventFeed {
+        receiver,
+        closed,
+        dir: context.dir.clone(),
+        base_url: base_url.map(str::to_string),
+        auth_token: context.auth_token.clone(),
+        self_signed: context.self_signed,
+        pins: context.pins.clone(),
+        listing_stale: true,
+    };
+
+    response::event_stream(stream::unfold(feed, |mut feed| async move {
+        let event = feed.next().await?;
+        Some((Ok(event), feed))
+    }))
+}
+
+struct EventFeed {
+    receiver: Receiver<DirectoryEvent>,
+    closed: CancellationToken,
+    dir: Arc<PathBuf>,
+    base_url: Option<String>,
+    auth_token: Option<String>,
+    self_signed: bool,
+    pins: Option<Arc<CertificatePins>>,
+    listing_stale: bool,
+}
+
+impl EventFeed {
+    /// The next event to send, or `None` once droppa shuts down.
+    async fn next(&mut self) -> Option<Bytes> {
+        loop {
+            if std::mem::take(&mut self.listing_stale) {
+                return Some(sse_event("listing", &self.listing().await));
+            }
+
+            let received = tokio::select! {
+                received = self.receiver.recv() => received,
+                _ = tokio::time::sleep(KEEPALIVE) => return Some(Bytes::from_static(b": keep-alive\n\n")),
+                _ = self.closed.cancelled() => return None,
+            };
+
+            match received {
+                // Missed events may have been changes, so re-list to be safe.
+                Ok(DirectoryEvent::Changed) | Err(RecvError::Lagged(_)) => self.listing_stale = true,
+                Ok(DirectoryEvent::UploadReceived(notice)) => {
+                    if let Ok(notice) = serde_json::to_string(&notice) {
+                        return Some(sse_event("upload", &notice));
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    async fn listing(&self) -> String {
+        let target = self.base_url.as_deref().map(|base_url| Target {
+            base_url,
+            auth_token: self.auth_token.as_deref(),
+            insecure: self.self_signed && base_url.starts_with("https://"),
+            pins: self.pins.as_deref(),
+        });
+
+        file_list_view(list_files(&self.dir).await, target.as_ref())
+    }
+}
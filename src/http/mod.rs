@@ -1,4 +1,9 @@
+pub mod admin;
+pub mod auth;
+pub mod context;
 pub mod controller;
+pub mod events;
 pub mod routes;
 pub mod server;
-pub mod intercept;
\ No newline at end of file
+pub mod intercept;
+pub mod response;
@@ -0,0 +1,119 @@
+use bytes::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
+use hyper::{Response, StatusCode};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+pub type Body = BoxBody<Bytes, std::io::Error>;
+
+fn full(content: impl Into<Bytes>) -> Body {
+    Full::new(content.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+pub fn html(content: String) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(full(content))
+        .unwrap()
+}
+
+pub fn text(content: &'static str, status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(full(content))
+        .unwrap()
+}
+
+pub fn metrics(content: String) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")
+        .body(full(content))
+        .unwrap()
+}
+
+pub fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(LOCATION, location)
+        .body(full(Bytes::new()))
+        .unwrap()
+}
+
+pub fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Basic realm=\"droppa\"")
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(full("Unauthorized"))
+        .unwrap()
+}
+
+/// The key authorization an ACME CA fetches for HTTP-01.
+pub fn key_authorization(content: String) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .body(full(content))
+        .unwrap()
+}
+
+pub fn forbidden() -> Response<Body> {
+    text("Forbidden", StatusCode::FORBIDDEN)
+}
+
+pub fn method_not_allowed() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(ALLOW, "GET, HEAD, POST")
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(full("Method Not Allowed"))
+        .unwrap()
+}
+
+pub fn empty_404() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(full(Bytes::new()))
+        .unwrap()
+}
+
+pub fn file_stream(file: File, length: u64, on_chunk: impl FnMut(&Bytes) + Send + Sync + 'static) -> Response<Body> {
+    let stream = ReaderStream::new(file)
+        .inspect_ok(on_chunk)
+        .map_ok(Frame::data);
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_LENGTH, length)
+        .body(StreamBody::new(stream).boxed())
+        .unwrap()
+}
+
+/// A `text/event-stream` response that stays open until `events` ends.
+pub fn event_stream(events: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static) -> Response<Body> {
+    let stream = events.map_ok(Frame::data);
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(StreamBody::new(stream).boxed())
+        .unwrap()
+}
+
+/// One server-sent event; every line of `data` gets its own `data:` field, and empty data
+/// still gets one so the browser dispatches the event.
+pub fn sse_event(name: &str, data: &str) -> Bytes {
+    let mut event = format!("event: {}\n", name);
+    for line in data.trim_end_matches('\n').split('\n') {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    Bytes::from(event)
+}
@@ -1,21 +1,83 @@
-use rouille::{Request, Response};
-use std::path::PathBuf;
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response};
+use std::convert::Infallible;
 use std::sync::Arc;
 
-use crate::http::controller::{get, index, store};
-use crate::http::intercept::intercept_request; 
+use crate::crypto::client_auth::{ClientAuthMode, Role};
+use crate::http::auth::{is_authorized, TOKEN_USER};
+use crate::http::context::{Client, Connection, ServerContext};
+use crate::http::controller::{base_url, get, index, redirect_https, store};
+use crate::http::events::{events, EVENTS_PATH};
+use crate::http::intercept::intercept_request;
 use crate::http::intercept::intercept_response;
+use crate::http::response::{self, Body};
 
-pub fn handle_request(request: &Request, dir: &Arc<PathBuf>) -> Response {
-    intercept_request(request);
+pub async fn handle_request(request: Request<Incoming>, connection: Connection, context: Arc<ServerContext>) -> Result<Response<Body>, Infallible> {
+    intercept_request(&request, connection.remote_addr);
 
-    let response = match request.method() {
-        "POST" if request.url() == "/" => store(request, dir),
-        "GET" if request.url() == "/" => index(request, dir),
-        _ => get(request, dir),
+    let base_url = base_url(&request, &connection);
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let client = Client {
+        connection: connection.id,
+        remote_addr: connection.remote_addr,
+        user: match &connection.client {
+            Some(identity) => Some(identity.user.clone()),
+            None => context.auth_token.as_ref().map(|_| TOKEN_USER.to_string()),
+        },
+        certificate: connection.client.as_ref().map(|identity| identity.fingerprint.clone()),
     };
+    let certificate_required = context.client_auth.as_ref().is_some_and(|client_auth| client_auth.mode == ClientAuthMode::Required);
 
-    let response = intercept_response(response);
+    // The CA fetches HTTP-01 tokens over plain HTTP and without credentials.
+    let key_authorization = match (&context.acme_challenges, request.method()) {
+        (Some(challenges), &Method::GET) => challenges.http_response(&path),
+        _ => None,
+    };
+
+    let response = match (request.method(), request.uri().path(), key_authorization) {
+        (_, _, Some(key_authorization)) => response::key_authorization(key_authorization),
+        _ if context.redirect_https && !connection.secure => redirect_https(&request),
+        // Only reachable over plain HTTP, since the handshake already demands the certificate.
+        _ if certificate_required && connection.client.is_none() => response::forbidden(),
+        // A client certificate stands in for the token.
+        _ if connection.client.is_none() && context.auth_token.as_deref().is_some_and(|token| !is_authorized(&request, token)) => {
+            response::unauthorized()
+        }
+        _ if connection.client.as_ref().is_some_and(|identity| !permits(identity.role, &method)) => response::forbidden(),
+        _ if ![Method::GET, Method::HEAD, Method::POST].contains(&method) => response::method_not_allowed(),
+        (&Method::POST, "/", _) => store(request, &client, &context, base_url.as_deref()).await,
+        (&Method::GET, "/", _) => index(&context, base_url.as_deref()).await,
+        (&Method::GET, EVENTS_PATH, _) => events(&context, base_url.as_deref()),
+        _ => get(&request, &client, &context).await,
+    };
+
+    context.transfers.record_response(response.status().as_u16());
+    context.transfers.activity.record_request(connection.remote_addr, method.as_str(), &path, response.status().as_u16());
+
+    Ok(intercept_response(response, connection.remote_addr))
+}
+
+/// Reads, listings included, need the read role; anything else, uploads included, the write role.
+fn permits(role: Role, method: &Method) -> bool {
+    match *method {
+        Method::GET | Method::HEAD => role.can_download(),
+        _ => role.can_upload(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    response
+    #[test]
+    fn only_reads_pass_with_the_read_role() {
+        assert!(permits(Role::Read, &Method::GET));
+        assert!(permits(Role::Read, &Method::HEAD));
+        for method in [Method::POST, Method::PUT, Method::DELETE, Method::PATCH] {
+            assert!(!permits(Role::Read, &method));
+            assert!(permits(Role::Write, &method));
+        }
+        assert!(!permits(Role::Write, &Method::GET));
+    }
 }
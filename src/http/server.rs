@@ -1,12 +1,139 @@
-use std::{path::PathBuf, sync::Arc};
-use rouille;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
 
+use crate::acme::challenges::ACME_TLS_ALPN;
+use crate::crypto::client_auth::Admission;
+use crate::crypto::tls::TLS_HANDSHAKE_TIMEOUT;
+use crate::http::context::{Connection, ServerContext};
 use crate::http::routes;
+use crate::lifecycle::shutdown::Shutdown;
+use crate::transport::listener::{Listener, ACCEPT_RETRY_DELAY};
+use crate::transport::stream::{PeerAddr, Stream};
 
-pub fn start_rouille_server(address: String, dir: Arc<PathBuf>) {
-    std::thread::spawn(move || {
-        rouille::start_server(&address, move |request| {
-            routes::handle_request(request, &dir)
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+pub async fn start_server(
+    listener: Listener,
+    context: Arc<ServerContext>,
+    acceptor: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(listener = %listener.local_addr(), error = %err, "accept failed, retrying");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = shutdown.triggered() => return Ok(()),
+        };
+
+        let context = context.clone();
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let transfer = context.transfers.begin(remote_addr, "http");
+            let kill = transfer.kill_token();
+
+            tokio::select! {
+                _ = handle_connection(stream, remote_addr, transfer.id(), context, acceptor, shutdown) => {}
+                _ = kill.cancelled() => info!(peer = %remote_addr, "connection killed"),
+            }
         });
-    });
+    }
+}
+
+async fn handle_connection(
+    stream: Stream,
+    remote_addr: PeerAddr,
+    id: u64,
+    context: Arc<ServerContext>,
+    acceptor: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) {
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => {
+            let connection = Connection { id, remote_addr, secure: false, client: None };
+            return serve_connection(stream, connection, context, shutdown).await;
+        }
+    };
+
+    let is_tls = tokio::select! {
+        is_tls = is_tls_client_hello(&stream) => is_tls,
+        _ = shutdown.triggered() => return,
+    };
+
+    if !is_tls {
+        let connection = Connection { id, remote_addr, secure: false, client: None };
+        return serve_connection(stream, connection, context, shutdown).await;
+    }
+
+    let accepted = tokio::select! {
+        accepted = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)) => accepted,
+        _ = shutdown.triggered() => return,
+    };
+
+    match accepted.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))) {
+        Ok(stream) if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) => {
+            // The validation certificate was all the CA wanted from this connection.
+            debug!(peer = %remote_addr, "answered TLS-ALPN-01 validation");
+        }
+        Ok(stream) => {
+            let client = match context.client_auth.as_ref().map(|client_auth| client_auth.admit(remote_addr, stream.get_ref().1.peer_certificates())) {
+                Some(Admission::Refused) => return,
+                Some(Admission::Identified(identity)) => Some(identity),
+                Some(Admission::Anonymous) | None => None,
+            };
+
+            context.transfers.activity.set_kind(id, "https");
+            let connection = Connection { id, remote_addr, secure: true, client };
+            serve_connection(stream, connection, context, shutdown).await
+        }
+        Err(e) => {
+            context.transfers.record_tls_failure();
+            warn!(peer = %remote_addr, error = %e, "TLS handshake failed");
+        }
+    }
+}
+
+/// Peeks at the first byte of the connection without consuming it; a TLS ClientHello
+/// always opens with a handshake record.
+async fn is_tls_client_hello(stream: &Stream) -> bool {
+    let mut first_byte = [0u8; 1];
+    matches!(stream.peek(&mut first_byte).await, Ok(1) if first_byte[0] == TLS_HANDSHAKE_RECORD)
+}
+
+async fn serve_connection<S>(stream: S, connection: Connection, context: Arc<ServerContext>, shutdown: Shutdown)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let connection_peer = connection.remote_addr;
+    let service = service_fn(move |request| routes::handle_request(request, connection.clone(), context.clone()));
+
+    let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.triggered() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(e) = result {
+        debug!(peer = %connection_peer, error = %e, "error serving connection");
+    }
 }
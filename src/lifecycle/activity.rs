@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::lifecycle::audit::Direction;
+use crate::transport::stream::PeerAddr;
+
+const RECENT_REQUESTS: usize = 200;
+const RECENT_ERRORS: usize = 100;
+
+/// What is happening right now, per connection, for the dashboard.
+pub struct Activity {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, ConnectionEntry>>,
+    requests: Mutex<VecDeque<RequestEntry>>,
+    errors: Mutex<VecDeque<ErrorEntry>>,
+    upstream: Mutex<Option<UpstreamStatus>>,
+    uploads_paused: watch::Sender<bool>,
+}
+
+#[derive(Clone)]
+pub struct ConnectionEntry {
+    pub id: u64,
+    pub peer: PeerAddr,
+    pub kind: &'static str,
+    pub started: Instant,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub transfer: Option<TransferProgress>,
+    kill: CancellationToken,
+}
+
+#[derive(Clone)]
+pub struct TransferProgress {
+    pub direction: Direction,
+    pub path: String,
+    pub bytes: u64,
+    pub total: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct RequestEntry {
+    pub at: Instant,
+    pub peer: PeerAddr,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+}
+
+#[derive(Clone)]
+pub struct ErrorEntry {
+    pub at: Instant,
+    pub level: tracing::Level,
+    pub message: String,
+}
+
+#[derive(Clone)]
+pub struct UpstreamStatus {
+    pub target: String,
+    pub checked: Instant,
+    pub result: Result<Duration, String>,
+}
+
+pub struct ActivitySnapshot {
+    pub connections: Vec<ConnectionEntry>,
+    pub requests: Vec<RequestEntry>,
+    pub errors: Vec<ErrorEntry>,
+    pub upstream: Option<UpstreamStatus>,
+    pub uploads_paused: bool,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            connections: Mutex::default(),
+            requests: Mutex::default(),
+            errors: Mutex::default(),
+            upstream: Mutex::default(),
+            uploads_paused: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Activity {
+    /// Registers a connection and returns its id and the token that kills it.
+    pub fn open(&self, peer: PeerAddr, kind: &'static str) -> (u64, CancellationToken) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let kill = CancellationToken::new();
+
+        lock(&self.connections).insert(id, ConnectionEntry {
+            id,
+            peer,
+            kind,
+            started: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            transfer: None,
+            kill: kill.clone(),
+        });
+
+        (id, kill)
+    }
+
+    pub fn close(&self, id: u64) {
+        lock(&self.connections).remove(&id);
+    }
+
+    pub fn set_kind(&self, id: u64, kind: &'static str) {
+        if let Some(entry) = lock(&self.connections).get_mut(&id) {
+            entry.kind = kind;
+        }
+    }
+
+    /// Drops the connection mid-stream. Returns `false` when it already closed.
+    pub fn kill(&self, id: u64) -> bool {
+        match lock(&self.connections).get(&id) {
+            Some(entry) => {
+                entry.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_in(&self, id: u64, bytes: usize) {
+        if let Some(entry) = lock(&self.connections).get_mut(&id) {
+            entry.bytes_in += bytes as u64;
+            if let Some(transfer) = entry.transfer.as_mut().filter(|transfer| matches!(transfer.direction, Direction::Upload)) {
+                transfer.bytes += bytes as u64;
+            }
+        }
+    }
+
+    pub fn add_out(&self, id: u64, bytes: usize) {
+        if let Some(entry) = lock(&self.connections).get_mut(&id) {
+            entry.bytes_out += bytes as u64;
+            if let Some(transfer) = entry.transfer.as_mut().filter(|transfer| matches!(transfer.direction, Direction::Download)) {
+                transfer.bytes += bytes as u64;
+            }
+        }
+    }
+
+    pub fn start_transfer(&self, id: u64, direction: Direction, path: String, total: Option<u64>) {
+        if let Some(entry) = lock(&self.connections).get_mut(&id) {
+            entry.transfer = Some(TransferProgress { direction, path, bytes: 0, total });
+        }
+    }
+
+    pub fn finish_transfer(&self, id: u64) {
+        if let Some(entry) = lock(&self.connections).get_mut(&id) {
+            entry.transfer = None;
+        }
+    }
+
+    pub fn record_request(&self, peer: PeerAddr, method: &str, path: &str, status: u16) {
+        push_bounded(&self.requests, RECENT_REQUESTS, RequestEntry {
+            at: Instant::now(),
+            peer,
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+        });
+    }
+
+    pub fn record_error(&self, level: tracing::Level, message: String) {
+        push_bounded(&self.errors, RECENT_ERRORS, ErrorEntry { at: Instant::now(), level, message });
+    }
+
+    pub fn record_upstream(&self, target: &str, result: Result<Duration, String>) {
+        *lock(&self.upstream) = Some(UpstreamStatus { target: target.to_string(), checked: Instant::now(), result });
+    }
+
+    pub fn set_uploads_paused(&self, paused: bool) {
+        self.uploads_paused.send_replace(paused);
+    }
+
+    pub fn uploads_paused(&self) -> bool {
+        *self.uploads_paused.borrow()
+    }
+
+    /// Returns at once unless uploads are paused, then waits for them to resume.
+    pub async fn wait_uploads_resumed(&self) {
+        let mut paused = self.uploads_paused.subscribe();
+        let _ = paused.wait_for(|paused| !paused).await;
+    }
+
+    pub fn snapshot(&self) -> ActivitySnapshot {
+        ActivitySnapshot {
+            connections: lock(&self.connections).values().cloned().collect(),
+            requests: lock(&self.requests).iter().rev().cloned().collect(),
+            errors: lock(&self.errors).iter().rev().cloned().collect(),
+            upstream: lock(&self.upstream).clone(),
+            uploads_paused: self.uploads_paused(),
+        }
+    }
+}
+
+fn push_bounded<T>(entries: &Mutex<VecDeque<T>>, capacity: usize, entry: T) {
+    let mut entries = lock(entries);
+    if entries.len() == capacity {
+        entries.pop_front();
+    }
+    entries.push_back(entry);
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
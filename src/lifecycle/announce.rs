@@ -0,0 +1,70 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use serde::Serialize;
+
+/// The single JSON line printed once every listener is accepting, so wrappers can wait for
+/// readiness instead of sleeping.
+#[derive(Serialize)]
+pub struct Announcement {
+    pub event: &'static str,
+    pub pid: u32,
+    pub mode: &'static str,
+    pub listeners: Vec<AnnouncedListener>,
+    pub admin_url: Option<String>,
+    pub tls_fingerprint_sha256: Option<String>,
+    /// Base64 SHA-256 of the certificate's public key, for `curl --pinnedpubkey sha256//...`.
+    pub tls_spki_sha256: Option<String>,
+    pub auth_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AnnouncedListener {
+    pub url: String,
+    pub address: String,
+    pub port: Option<u16>,
+    pub tls: bool,
+    pub advertised: Vec<String>,
+}
+
+impl Announcement {
+    pub fn ready(mode: &'static str) -> Self {
+        Self {
+            event: "ready",
+            pid: std::process::id(),
+            mode,
+            listeners: Vec::new(),
+            admin_url: None,
+            tls_fingerprint_sha256: None,
+            tls_spki_sha256: None,
+            auth_token: None,
+        }
+    }
+
+    /// Prints the announcement to stdout, or writes it to `ready_file` through a temporary
+    /// file and a rename so readers never see a partial line. FIFOs and other special files
+    /// are written in place.
+    pub fn publish(&self, ready_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+        let line = format!("{}\n", serde_json::to_string(self)?);
+
+        let ready_file = match ready_file {
+            Some(ready_file) => ready_file,
+            None => {
+                print!("{}", line);
+                return Ok(());
+            }
+        };
+
+        match fs::metadata(ready_file) {
+            Ok(metadata) if !metadata.is_file() => fs::write(ready_file, line)?,
+            _ => {
+                let mut temporary = ready_file.as_os_str().to_owned();
+                temporary.push(".tmp");
+                fs::write(&temporary, line)?;
+                fs::rename(&temporary, ready_file)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::transport::stream::PeerAddr;
+
+/// Append-only JSON Lines evidence trail of every file that left or entered the box.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Completed,
+    Aborted,
+}
+
+#[derive(Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub direction: Direction,
+    pub client_ip: Option<String>,
+    pub client_port: Option<u16>,
+    pub user: Option<String>,
+    /// SHA-256 fingerprint of the client certificate, with `--client-ca`.
+    pub client_cert_sha256: Option<String>,
+    pub path: String,
+    pub bytes: u64,
+    pub sha256: String,
+    pub duration_ms: u64,
+    /// `None` when the connection went away before a response was sent.
+    pub status: Option<u16>,
+    pub outcome: Outcome,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(err) => return warn!(error = %err, "failed to serialize audit record"),
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!(error = %err, "failed to write audit record");
+        }
+    }
+}
+
+/// One transfer in flight. Hashes bytes as they pass and writes its record when dropped,
+/// so a client hanging up mid-stream still leaves an `aborted` entry. Does nothing when
+/// auditing is off.
+pub struct AuditedTransfer {
+    log: Option<Arc<AuditLog>>,
+    direction: Direction,
+    client: PeerAddr,
+    user: Option<String>,
+    certificate: Option<String>,
+    path: String,
+    started: Instant,
+    hasher: Sha256,
+    bytes: u64,
+    expected: Option<u64>,
+    status: Option<u16>,
+    completed: bool,
+}
+
+impl AuditedTransfer {
+    pub fn begin(log: Option<&Arc<AuditLog>>, direction: Direction, client: PeerAddr, user: Option<String>, path: String) -> Self {
+        Self {
+            log: log.cloned(),
+            direction,
+            client,
+            user,
+            certificate: None,
+            path,
+            started: Instant::now(),
+            hasher: Sha256::new(),
+            bytes: 0,
+            expected: None,
+            status: None,
+            completed: false,
+        }
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn certificate(mut self, fingerprint: Option<String>) -> Self {
+        self.certificate = fingerprint;
+        self
+    }
+
+    /// The transfer counts as completed once this many bytes have passed.
+    pub fn expect(mut self, length: u64) -> Self {
+        self.expected = Some(length);
+        self
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        if self.log.is_some() {
+            self.hasher.update(chunk);
+            self.bytes += chunk.len() as u64;
+        }
+    }
+
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+
+    pub fn abort(mut self, status: u16) {
+        self.status = Some(status);
+    }
+}
+
+impl Drop for AuditedTransfer {
+    fn drop(&mut self) {
+        let log = match self.log.take() {
+            Some(log) => log,
+            None => return,
+        };
+
+        let completed = self.completed || self.expected == Some(self.bytes);
+        let (client_ip, client_port) = match self.client {
+            PeerAddr::Tcp(addr) => (Some(addr.ip().to_string()), Some(addr.port())),
+            PeerAddr::Unix => (None, None),
+        };
+
+        log.record(&AuditRecord {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            direction: self.direction,
+            client_ip,
+            client_port,
+            user: self.user.take(),
+            client_cert_sha256: self.certificate.take(),
+            path: std::mem::take(&mut self.path),
+            bytes: self.bytes,
+            sha256: format!("{:x}", std::mem::take(&mut self.hasher).finalize()),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            status: self.status,
+            outcome: if completed { Outcome::Completed } else { Outcome::Aborted },
+        });
+    }
+}
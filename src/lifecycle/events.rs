@@ -0,0 +1,88 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::lifecycle::shutdown::Shutdown;
+
+/// A single copy or upload fires an event per write; wait this long for the burst to end.
+const SETTLE: Duration = Duration::from_millis(250);
+const CAPACITY: usize = 64;
+
+/// Changes to the served directory, fanned out to every open event stream of the web GUI.
+pub struct DirectoryEvents {
+    sender: broadcast::Sender<DirectoryEvent>,
+    closed: CancellationToken,
+}
+
+#[derive(Clone)]
+pub enum DirectoryEvent {
+    /// Files appeared, changed or disappeared, so the listing is stale.
+    Changed,
+    UploadReceived(UploadNotice),
+}
+
+#[derive(Clone, Serialize)]
+pub struct UploadNotice {
+    pub name: String,
+    pub bytes: u64,
+    pub client: String,
+}
+
+impl DirectoryEvents {
+    /// Every event stream ends when `shutdown` triggers, so the drain does not wait on them.
+    pub fn new(shutdown: &Shutdown) -> Arc<Self> {
+        let events = Arc::new(Self { sender: broadcast::Sender::new(CAPACITY), closed: CancellationToken::new() });
+
+        let closed = events.closed.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            shutdown.triggered().await;
+            closed.cancel();
+        });
+
+        events
+    }
+
+    /// Watches the top level of `dir` for as long as the returned watcher is held.
+    pub fn watch(&self, dir: &Path) -> notify::Result<RecommendedWatcher> {
+        let (raw_sender, mut raw_events) = mpsc::unbounded_channel();
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            // Reads of served files show up as access events; they do not change the listing.
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+            Ok(_) => {
+                let _ = raw_sender.send(());
+            }
+            Err(e) => warn!(error = %e, "directory watch failed"),
+        })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        let sender = self.sender.clone();
+
+        tokio::spawn(async move {
+            while raw_events.recv().await.is_some() {
+                tokio::time::sleep(SETTLE).await;
+                while raw_events.try_recv().is_ok() {}
+
+                debug!("served directory changed");
+                let _ = sender.send(DirectoryEvent::Changed);
+            }
+        });
+
+        Ok(watcher)
+    }
+
+    /// A receiver for new events and the token that ends the stream.
+    pub fn subscribe(&self) -> (broadcast::Receiver<DirectoryEvent>, CancellationToken) {
+        (self.sender.subscribe(), self.closed.clone())
+    }
+
+    pub fn upload_received(&self, notice: UploadNotice) {
+        let _ = self.sender.send(DirectoryEvent::UploadReceived(notice));
+    }
+}
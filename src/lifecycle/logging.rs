@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt as layer_fmt, EnvFilter, Layer, Registry};
+
+use crate::lifecycle::transfers::Transfers;
+
+pub const LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected 'human' or 'json'", value)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Human => write!(f, "human"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum LogRotation {
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "never" => Ok(LogRotation::Never),
+            "minutely" => Ok(LogRotation::Minutely),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            _ => Err(format!("unknown log rotation '{}', expected never, minutely, hourly or daily", value)),
+        }
+    }
+}
+
+impl fmt::Display for LogRotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogRotation::Never => write!(f, "never"),
+            LogRotation::Minutely => write!(f, "minutely"),
+            LogRotation::Hourly => write!(f, "hourly"),
+            LogRotation::Daily => write!(f, "daily"),
+        }
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+        }
+    }
+}
+
+pub struct LogFile {
+    pub path: PathBuf,
+    pub format: LogFormat,
+    pub rotation: LogRotation,
+    /// How many rotated files to keep, 0 for all of them.
+    pub max_files: usize,
+}
+
+/// Where log events go and which ones are kept. `filter` uses `RUST_LOG` directive syntax,
+/// e.g. `info,droppa::proxy=trace,hyper=warn`.
+pub struct LogOptions {
+    pub filter: String,
+    pub console_format: LogFormat,
+    /// Only warnings and errors reach the console; the log file keeps `filter`.
+    pub quiet: bool,
+    /// Off while the dashboard owns the terminal; warnings and errors show in its pane instead.
+    pub console: bool,
+    pub file: Option<LogFile>,
+}
+
+/// Installs the global subscriber: a console sink on stdout, colored when it is a terminal,
+/// an optional rotating file sink, and a feed of warnings and errors into `transfers` for the
+/// dashboard. Hold on to the returned guard until exit so buffered file output is flushed.
+pub fn init(options: &LogOptions, transfers: &Arc<Transfers>) -> Result<Option<WorkerGuard>, Box<dyn Error>> {
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![ActivityLayer { transfers: transfers.clone() }.boxed()];
+    let mut guard = None;
+
+    if options.console {
+        let console_filter = if options.quiet { EnvFilter::try_new("warn")? } else { EnvFilter::try_new(&options.filter)? };
+
+        let console = match options.console_format {
+            LogFormat::Human => layer_fmt::layer().with_writer(io::stdout).with_ansi(io::stdout().is_terminal()).boxed(),
+            LogFormat::Json => layer_fmt::layer().json().with_writer(io::stdout).boxed(),
+        };
+        layers.push(console.with_filter(console_filter).boxed());
+    }
+
+    if let Some(file) = &options.file {
+        let directory = file.path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(".".as_ref());
+        let prefix = file.path.file_stem().ok_or_else(|| format!("--log-file {} has no file name", file.path.display()))?;
+        std::fs::create_dir_all(directory).map_err(|e| format!("--log-file {}: {}", file.path.display(), e))?;
+
+        // Rotated files keep the extension: droppa.jsonl becomes droppa.2024-05-01.jsonl.
+        let mut appender = RollingFileAppender::builder()
+            .rotation(file.rotation.into())
+            .filename_prefix(prefix.to_string_lossy());
+        if let Some(extension) = file.path.extension() {
+            appender = appender.filename_suffix(extension.to_string_lossy());
+        }
+        if file.max_files > 0 {
+            appender = appender.max_log_files(file.max_files);
+        }
+        let appender = appender
+            .build(directory)
+            .map_err(|e| format!("--log-file {}: {}", file.path.display(), e))?;
+
+        let (writer, file_guard) = tracing_appender::non_blocking(appender);
+        guard = Some(file_guard);
+
+        let sink = match file.format {
+            LogFormat::Human => layer_fmt::layer().with_writer(writer).with_ansi(false).boxed(),
+            LogFormat::Json => layer_fmt::layer().json().with_writer(writer).boxed(),
+        };
+        layers.push(sink.with_filter(EnvFilter::try_new(&options.filter)?).boxed());
+    }
+
+    tracing_subscriber::registry().with(layers).try_init()?;
+
+    Ok(guard)
+}
+
+/// Keeps recent warnings and errors for the dashboard's error pane.
+struct ActivityLayer {
+    transfers: Arc<Transfers>,
+}
+
+impl<S: Subscriber> Layer<S> for ActivityLayer {
+    fn on_event(&self, event: &Event<'_>, _context: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+
+        let mut message = MessageVisitor::default();
+        event.record(&mut message);
+        self.transfers.activity.record_error(level, message.0);
+    }
+}
+
+/// Renders an event as its message followed by `key=value` for the other fields.
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        use std::fmt::Write;
+
+        if field.name() == "message" {
+            let fields = std::mem::take(&mut self.0);
+            let _ = write!(self.0, "{:?}{}", value, fields);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Raises `level` by one step per `-v`.
+pub fn verbose_level(level: &str, verbose: u8) -> &'static str {
+    let index = LEVELS.iter().position(|candidate| *candidate == level).unwrap_or(2);
+    LEVELS[(index + verbose as usize).min(LEVELS.len() - 1)]
+}
@@ -0,0 +1,150 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::lifecycle::transfers::TransferStats;
+
+/// Upper bounds in seconds, spanning a LAN round trip to a slow upstream over a VPN.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+pub struct HistogramSnapshot {
+    /// Observations per bucket, not cumulative.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+/// Renders the counters in the Prometheus text exposition format.
+pub fn render(stats: &TransferStats) -> String {
+    let mut out = String::new();
+
+    metric(&mut out, "droppa_connections_active", "gauge", "Connections currently open", stats.active as u64);
+    metric(&mut out, "droppa_connections_total", "counter", "Connections accepted", stats.connections);
+    metric(&mut out, "droppa_downloads_total", "counter", "Files served", stats.downloads);
+    metric(&mut out, "droppa_uploads_total", "counter", "Files received", stats.uploads);
+    metric(&mut out, "droppa_proxied_connections_total", "counter", "Connections relayed to the upstream", stats.proxied);
+    metric(&mut out, "droppa_bytes_sent_total", "counter", "Bytes sent to clients", stats.bytes_sent);
+    metric(&mut out, "droppa_bytes_received_total", "counter", "Bytes received from clients", stats.bytes_received);
+    metric(&mut out, "droppa_tls_handshake_failures_total", "counter", "Client TLS handshakes that failed", stats.tls_handshake_failures);
+
+    header(&mut out, "droppa_http_responses_total", "counter", "HTTP responses by status code");
+    for (code, count) in &stats.responses {
+        let _ = writeln!(out, "droppa_http_responses_total{{code=\"{}\"}} {}", code, count);
+    }
+
+    header(&mut out, "droppa_upload_rejections_total", "counter", "Uploads refused, by reason");
+    for (reason, count) in &stats.upload_rejections {
+        let _ = writeln!(out, "droppa_upload_rejections_total{{reason=\"{}\"}} {}", reason, count);
+    }
+
+    histogram(&mut out, "droppa_upstream_connect_seconds", "Time to connect to the upstream, TLS handshake included", &stats.upstream_connect);
+    histogram(&mut out, "droppa_upstream_first_byte_seconds", "Time from forwarding a request to the first upstream response byte", &stats.upstream_first_byte);
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, snapshot: &HistogramSnapshot) {
+    header(out, name, "histogram", help);
+
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(&snapshot.buckets) {
+        cumulative += count;
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, snapshot.count);
+    let _ = writeln!(out, "{}_sum {}", name, snapshot.sum);
+    let _ = writeln!(out, "{}_count {}", name, snapshot.count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::transfers::Transfers;
+
+    #[test]
+    fn histogram_counts_each_observation_once() {
+        let histogram = Histogram::default();
+        for millis in [3, 3, 200, 20_000] {
+            histogram.observe(Duration::from_millis(millis));
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.buckets, vec![2, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(snapshot.count, 4);
+        assert!((snapshot.sum - 20.206).abs() < 1e-9);
+    }
+
+    #[test]
+    fn render_is_prometheus_exposition_text() {
+        let transfers = Transfers::default();
+        transfers.record_response(200);
+        transfers.record_response(404);
+        transfers.record_upload_rejection("too-large");
+        transfers.record_upstream_connect(Duration::from_millis(30));
+        transfers.record_upstream_connect(Duration::from_secs(30));
+
+        let rendered = render(&transfers.stats());
+        let mut typed = Vec::new();
+        for line in rendered.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(["counter", "gauge", "histogram"].contains(&kind), "{}", line);
+                typed.push(name.to_string());
+                continue;
+            }
+            if line.starts_with("# HELP ") {
+                continue;
+            }
+            // A sample: a name declared by the TYPE line above, optional labels and a number.
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+            let name = series.split('{').next().unwrap();
+            assert!(typed.last().is_some_and(|family| name.starts_with(family.as_str())), "{}", line);
+            assert!(!series.contains('{') || series.ends_with("\"}"), "{}", line);
+        }
+
+        assert!(rendered.contains("droppa_http_responses_total{code=\"404\"} 1\n"));
+        assert!(rendered.contains("droppa_upload_rejections_total{reason=\"too-large\"} 1\n"));
+        assert!(rendered.contains("droppa_upstream_connect_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(rendered.contains("droppa_upstream_connect_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(rendered.contains("droppa_upstream_connect_seconds_bucket{le=\"10\"} 1\n"));
+        assert!(rendered.contains("droppa_upstream_connect_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("droppa_upstream_connect_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,8 @@
+pub mod activity;
+pub mod announce;
+pub mod audit;
+pub mod events;
+pub mod logging;
+pub mod metrics;
+pub mod shutdown;
+pub mod transfers;
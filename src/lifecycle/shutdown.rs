@@ -0,0 +1,77 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::lifecycle::transfers::Transfers;
+
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    pub transfers: Arc<Transfers>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Stops the accept loops on the first SIGINT/SIGTERM and exits immediately on the second.
+    pub fn listen_for_signals(&self) {
+        let shutdown = self.clone();
+
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            warn!("Shutting down, waiting for active transfers (signal again to force)");
+            shutdown.trigger();
+
+            wait_for_signal().await;
+            error!("Forced exit");
+            info!("{}", shutdown.transfers.summary());
+            std::process::exit(130);
+        });
+    }
+
+    pub async fn drain(&self, timeout: Duration) {
+        if !self.transfers.wait_idle(timeout).await {
+            warn!("Drain timed out, abandoning {} active transfers", self.transfers.active());
+        }
+
+        info!("{}", self.transfers.summary());
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
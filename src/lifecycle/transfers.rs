@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::lifecycle::activity::Activity;
+use crate::lifecycle::audit::Direction;
+use crate::lifecycle::metrics::{Histogram, HistogramSnapshot};
+use crate::transport::stream::PeerAddr;
+
+#[derive(Default)]
+pub struct Transfers {
+    active: AtomicUsize,
+    idle: Notify,
+    connections: AtomicU64,
+    downloads: AtomicU64,
+    uploads: AtomicU64,
+    proxied: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    responses: Mutex<BTreeMap<u16, u64>>,
+    tls_handshake_failures: AtomicU64,
+    upload_rejections: Mutex<BTreeMap<&'static str, u64>>,
+    upstream_connect: Histogram,
+    upstream_first_byte: Histogram,
+    pub activity: Activity,
+}
+
+/// A point-in-time copy of every counter, for the metrics endpoint.
+pub struct TransferStats {
+    pub active: usize,
+    pub connections: u64,
+    pub downloads: u64,
+    pub uploads: u64,
+    pub proxied: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub responses: BTreeMap<u16, u64>,
+    pub tls_handshake_failures: u64,
+    pub upload_rejections: BTreeMap<&'static str, u64>,
+    pub upstream_connect: HistogramSnapshot,
+    pub upstream_first_byte: HistogramSnapshot,
+}
+
+pub struct TransferGuard {
+    transfers: Arc<Transfers>,
+    id: u64,
+    kill: CancellationToken,
+}
+
+impl TransferGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancelled when the connection is killed from the dashboard.
+    pub fn kill_token(&self) -> CancellationToken {
+        self.kill.clone()
+    }
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.transfers.activity.close(self.id);
+        if self.transfers.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.transfers.idle.notify_waiters();
+        }
+    }
+}
+
+/// A file moving over a tracked connection. Counts its bytes and clears the connection's
+/// progress when dropped.
+pub struct TrackedTransfer {
+    transfers: Arc<Transfers>,
+    connection: u64,
+}
+
+impl TrackedTransfer {
+    pub fn sent(&self, bytes: usize) {
+        self.transfers.record_sent(self.connection, bytes);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.transfers.record_received(self.connection, bytes);
+    }
+}
+
+impl Drop for TrackedTransfer {
+    fn drop(&mut self) {
+        self.transfers.activity.finish_transfer(self.connection);
+    }
+}
+
+impl Transfers {
+    pub fn begin(self: &Arc<Self>, peer: PeerAddr, kind: &'static str) -> TransferGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        let (id, kill) = self.activity.open(peer, kind);
+        TransferGuard { transfers: self.clone(), id, kill }
+    }
+
+    pub fn track(self: &Arc<Self>, connection: u64, direction: Direction, path: String, total: Option<u64>) -> TrackedTransfer {
+        self.activity.start_transfer(connection, direction, path, total);
+        TrackedTransfer { transfers: self.clone(), connection }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn record_download(&self) {
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upload(&self) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_proxied(&self) {
+        self.proxied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, connection: u64, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.activity.add_out(connection, bytes);
+    }
+
+    pub fn record_received(&self, connection: u64, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.activity.add_in(connection, bytes);
+    }
+
+    pub fn record_response(&self, status: u16) {
+        *lock(&self.responses).entry(status).or_default() += 1;
+    }
+
+    pub fn record_tls_failure(&self) {
+        self.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upload_rejection(&self, reason: &'static str) {
+        *lock(&self.upload_rejections).entry(reason).or_default() += 1;
+    }
+
+    pub fn record_upstream_connect(&self, latency: Duration) {
+        self.upstream_connect.observe(latency);
+    }
+
+    pub fn record_upstream_first_byte(&self, latency: Duration) {
+        self.upstream_first_byte.observe(latency);
+    }
+
+    /// Waits until every in-flight transfer has finished, or the timeout elapses.
+    /// Returns `true` when the server drained cleanly.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let drained = async {
+            loop {
+                let notified = self.idle.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+
+                if self.active() == 0 {
+                    return;
+                }
+
+                notified.await;
+            }
+        };
+
+        tokio::time::timeout(timeout, drained).await.is_ok()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} connections, {} downloads, {} uploads, {} proxied, {} bytes sent, {} bytes received",
+            self.connections.load(Ordering::Relaxed),
+            self.downloads.load(Ordering::Relaxed),
+            self.uploads.load(Ordering::Relaxed),
+            self.proxied.load(Ordering::Relaxed),
+            self.bytes_sent.load(Ordering::Relaxed),
+            self.bytes_received.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn stats(&self) -> TransferStats {
+        TransferStats {
+            active: self.active(),
+            connections: self.connections.load(Ordering::Relaxed),
+            downloads: self.downloads.load(Ordering::Relaxed),
+            uploads: self.uploads.load(Ordering::Relaxed),
+            proxied: self.proxied.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            responses: lock(&self.responses).clone(),
+            tls_handshake_failures: self.tls_handshake_failures.load(Ordering::Relaxed),
+            upload_rejections: lock(&self.upload_rejections).clone(),
+            upstream_connect: self.upstream_connect.snapshot(),
+            upstream_first_byte: self.upstream_first_byte.snapshot(),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
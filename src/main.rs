@@ -1,148 +1,440 @@
+mod acme;
 mod views;
 mod transport;
 mod proxy;
 mod mitm;
 mod http;
 mod crypto;
+mod lifecycle;
+mod config;
+mod tui;
 
-use std::{path::PathBuf, sync::Arc};
-use clap::{Arg, Command};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{future::Future, pin::Pin, sync::Arc};
+use futures_util::future::join_all;
+use acme::challenges::Challenges;
+use config::cli;
+use config::plan::{Mode, RunPlan, TlsMaterial};
+use config::settings::Settings;
+use crypto::ca::CertificateAuthority;
+use crypto::certs::{sha256_fingerprint, KeyType};
+use crypto::client_auth::ClientAuth;
+use crypto::upstream::{UpstreamConnector, UpstreamTls};
+use crypto::pkcs12::write_pkcs12;
+use http::admin;
+use http::context::ServerContext;
 use http::server;
+use lifecycle::announce::{AnnouncedListener, Announcement};
+use lifecycle::audit::AuditLog;
+use lifecycle::events::DirectoryEvents;
+use lifecycle::logging;
+use lifecycle::shutdown::Shutdown;
 use proxy::proxy::start_ssl_proxy;
+use transport::interfaces::{advertised_urls, AdvertisedUrl};
+use transport::listener::Listener;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+use views::oneliners::{download_commands, upload_commands, CertificatePins, Target};
+
+const STARTUP_ONELINER_FILES: usize = 5;
 
 #[tokio::main]
-async fn main() {
-    let matches = Command::new("DROPPA")
-        .version("1.0")
-        .author("Krystian Bajno")
-        .about("A simple file server server with optional TLS")
-        .arg(Arg::new("listen")
-            .long("listen")
-            .alias("host")
-            .value_name("listen")
-            .help("Set the listening address")
-            .default_value("0.0.0.0")
-            .action(clap::ArgAction::Set))
-        .arg(Arg::new("port")
-            .long("port")
-            .value_name("port")
-            .help("Set the listening port")
-            .default_value("8000")
-            .action(clap::ArgAction::Set))
-        .arg(Arg::new("directory")
-            .long("directory")
-            .short('d')
-            .value_name("directory")
-            .help("Set the directory to serve files from")
-            .default_value(".")
-            .action(clap::ArgAction::Set))
-        .arg(Arg::new("tls")
-            .long("tls")
-            .alias("ssl")
-            .help("Enable TLS")
-            .action(clap::ArgAction::SetTrue))
-        .arg(Arg::new("issuer")
-            .long("issuer")
-            .help("Set TLS issuer")
-            .default_value("getrekt.com")
-            .action(clap::ArgAction::Set))
-        .arg(Arg::new("proxy")
-            .long("proxy")
-            .help("Setup as reverse proxy")
-            .value_name("proxy")
-            .default_value("")
-            .action(clap::ArgAction::Set))
-        .arg(Arg::new("priv")
-            .long("priv")
-            .value_name("private_key")
-            .help("Path to the private key file")
-            .action(clap::ArgAction::Set))
-        .arg(Arg::new("cert")
-            .long("cert")
-            .value_name("certificate")
-            .help("Path to the certificate file")
-            .action(clap::ArgAction::Set))
-        .get_matches();
-
-    let listen_address = matches.get_one::<String>("listen").unwrap();
-    let port = matches.get_one::<String>("port").unwrap();
-    let directory = matches.get_one::<String>("directory").unwrap();
-    let enable_ssl = matches.get_one::<bool>("tls").unwrap();
-    let issuer = matches.get_one::<String>("issuer").unwrap();
-    let proxy_target_addr = matches.get_one::<String>("proxy").unwrap();
-
-    let dir = Arc::new(PathBuf::from(directory));
-
-    let private_key_path = matches.get_one::<String>("priv").map(PathBuf::from);
-    let cert_path = matches.get_one::<String>("cert").map(PathBuf::from);
-
-    if should_start_tls_proxy(enable_ssl, proxy_target_addr, &private_key_path, &cert_path) {
-        start_tls_proxy(listen_address, port, dir.clone(), issuer, private_key_path.clone(), cert_path.clone()).await;
-    } 
-
-    if should_start_plain_server(enable_ssl, proxy_target_addr, &private_key_path, &cert_path)  {
-        start_plain_server(listen_address, port, dir.clone());
-    }
-
-    if !proxy_target_addr.trim().is_empty() {
-        start_reverse_proxy(listen_address, port, proxy_target_addr, issuer, private_key_path, cert_path).await;
-    }
-
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(1));
+async fn main() -> ExitCode {
+    let matches = cli::parse();
+
+    let settings = match Settings::load(&matches) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("DROPPA: {}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    if let Some(("ca", ca)) = matches.subcommand() {
+        if let Some(("init", init)) = ca.subcommand() {
+            return ExitCode::from(init_ca(&settings, init.get_flag("force")));
+        }
     }
-}
 
-fn should_start_tls_proxy(enable_ssl: &bool, proxy_target_addr: &str, private_key_path: &Option<PathBuf>, cert_path: &Option<PathBuf>) -> bool {
-    proxy_target_addr.is_empty() && (*enable_ssl || (private_key_path.is_some() && cert_path.is_some()))
+    if let Some(("cert", cert)) = matches.subcommand() {
+        if let Some(("export", export)) = cert.subcommand() {
+            let bundle = export.get_one::<String>("pkcs12").expect("--pkcs12 is required");
+            return ExitCode::from(export_cert(&settings, Path::new(bundle), export.get_flag("legacy")));
+        }
+    }
+
+    if matches.get_flag("print-config") {
+        match settings.to_toml() {
+            Ok(config) => print!("{}", config),
+            Err(err) => {
+                eprintln!("DROPPA: {}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let plan = match RunPlan::resolve(&settings) {
+        Ok(plan) => plan,
+        Err(errors) => {
+            eprint!("DROPPA: Invalid configuration:\n{}", errors);
+            return ExitCode::from(2);
+        }
+    };
+
+    let shutdown = Shutdown::new();
+
+    let _log_guard = match logging::init(&plan.log, &shutdown.transfers) {
+        Ok(guard) => guard,
+        Err(err) => {
+            eprintln!("DROPPA: Failed to set up logging: {}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    // The dashboard takes over the terminal, so nothing else prints to it.
+    let console = !plan.log.quiet && !plan.tui;
+
+    if console {
+        print!("DROPPA: Run plan\n{}", plan);
+    }
+
+    if matches.get_flag("check") {
+        match plan.check().await {
+            Ok(()) => println!("DROPPA: Check passed"),
+            Err(errors) => {
+                eprint!("DROPPA: Check failed:\n{}", errors);
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let tls = match plan.tls.as_ref().map(|material| material.load(plan.client_auth.as_deref())).transpose() {
+        Ok(tls) => tls,
+        Err(err) => {
+            error!("Failed to load TLS material: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let upstream_tls = match plan.upstream.as_ref().map(UpstreamTls::connector).transpose() {
+        Ok(upstream_tls) => upstream_tls.map(Arc::new),
+        Err(err) => {
+            error!("Failed to set up upstream TLS: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    shutdown.listen_for_signals();
+
+    let audit = match plan.audit_log.as_deref().map(AuditLog::open).transpose() {
+        Ok(audit) => audit.map(Arc::new),
+        Err(err) => {
+            error!("Failed to open audit log: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let events = DirectoryEvents::new(&shutdown);
+
+    // Held until exit; without it the web GUI still gets upload notices, just no refreshes.
+    let _watcher = match &plan.mode {
+        Mode::FileServer { directory } => events
+            .watch(directory)
+            .inspect_err(|err| warn!("Not watching {} for live listing updates: {}", directory.display(), err))
+            .ok(),
+        Mode::ReverseProxy { .. } => None,
+    };
+
+    let context = match &plan.mode {
+        Mode::FileServer { directory } => Some(Arc::new(ServerContext {
+            dir: Arc::new(directory.clone()),
+            transfers: shutdown.transfers.clone(),
+            redirect_https: plan.redirect_https,
+            auth_token: plan.auth_token.clone(),
+            self_signed: matches!(plan.tls, Some(TlsMaterial::Generated { .. } | TlsMaterial::Authority { .. })),
+            pins: tls.as_ref().and_then(|tls| tls.leaf.as_deref()).and_then(|leaf| CertificatePins::of(leaf).ok()).map(Arc::new),
+            audit,
+            events,
+            acme_challenges: tls.as_ref().and_then(|tls| tls.acme.as_ref()).map(|acme| acme.challenges()),
+            client_auth: plan.client_auth.clone(),
+        })),
+        Mode::ReverseProxy { .. } => None,
+    };
+
+    let mut services: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+
+    let mut announcement = Announcement::ready(match plan.mode {
+        Mode::FileServer { .. } => "file-server",
+        Mode::ReverseProxy { .. } => "reverse-proxy",
+    });
+    announcement.tls_fingerprint_sha256 = tls.as_ref().map(|tls| tls.fingerprint_sha256.clone());
+    announcement.tls_spki_sha256 = tls.as_ref().map(|tls| tls.spki_sha256.clone());
+    announcement.auth_token = plan.auth_token.clone();
+
+    let mut oneliner_url = None;
+
+    for planned in &plan.listeners {
+        let listener = match Listener::bind(&planned.address, &plan.bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Failed to listen on {}: {}", planned.address.with_default_port(plan.bind.default_port), err);
+                return ExitCode::FAILURE;
+            }
+        };
+
+        let acceptor = if planned.tls { tls.as_ref().map(|tls| tls.acceptor.clone()) } else { None };
+        let scheme = if acceptor.is_some() { "https" } else { "http" };
+
+        let advertised = listener
+            .socket_addr()
+            .map(|bound| advertised_urls(bound, scheme, plan.bind.dual_stack, plan.bind.interface.as_deref()))
+            .unwrap_or_default();
+
+        announcement.listeners.push(AnnouncedListener {
+            url: listener.url(scheme),
+            address: listener.local_addr(),
+            port: listener.local_port(),
+            tls: acceptor.is_some(),
+            advertised: advertised.iter().map(|advertised| advertised.url.clone()).collect(),
+        });
+
+        if oneliner_url.is_none() {
+            oneliner_url = advertised.first().map(|advertised| advertised.url.clone());
+        }
+
+        match (&plan.mode, &context) {
+            (Mode::ReverseProxy { target }, _) => {
+                if console {
+                    println!("DROPPA: Proxy running on {} -> targeting {}", listener.url(scheme), target);
+                    print_advertised(&listener, &advertised);
+                }
+                let acme_challenges = tls.as_ref().and_then(|tls| tls.acme.as_ref()).map(|acme| acme.challenges());
+                let client_auth = plan.client_auth.clone();
+                services.push(Box::pin(start_reverse_proxy(listener, acceptor, target, upstream_tls.clone(), acme_challenges, client_auth, shutdown.clone())));
+            }
+            (Mode::FileServer { .. }, Some(context)) => {
+                if console {
+                    println!("DROPPA: Serving on {} from directory {}", listener.url(scheme), context.dir.display());
+                    print_advertised(&listener, &advertised);
+                }
+                services.push(Box::pin(start_file_server(listener, context.clone(), acceptor, shutdown.clone())));
+            }
+            (Mode::FileServer { .. }, None) => unreachable!("file server mode always builds a context"),
+        }
+    }
+
+    if let Some(acme) = tls.as_ref().and_then(|tls| tls.acme.clone()) {
+        // Started once the listeners are bound, since the CA validates through them.
+        tokio::spawn(acme.run(shutdown.clone()));
+    }
+
+    if let (Some(tls), true) = (&tls, console) {
+        if tls.acme.is_some() {
+            // Every order brings a new key, so pinning the current one would break on renewal.
+            println!("DROPPA: TLS certificate from ACME, renewed automatically; fingerprints of each are logged");
+        } else if tls.from_authority {
+            // Leaves get a new key every run, so only the root is worth trusting or pinning.
+            println!("DROPPA: TLS CA certificate SHA-256 {}", tls.fingerprint_sha256);
+        } else {
+            println!("DROPPA: TLS certificate SHA-256 {}", tls.fingerprint_sha256);
+            println!("DROPPA: TLS public key pin sha256//{}", tls.spki_sha256);
+        }
+    }
+
+    if let (Some(context), Some(base_url), true) = (&context, &oneliner_url, console) {
+        print_oneliners(context, base_url);
+    }
+
+    let ready = Arc::new(AtomicBool::new(false));
+
+    if let Some(address) = &plan.admin {
+        let listener = match Listener::bind(address, &plan.admin_bind()).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Failed to listen on admin address {}: {}", address, err);
+                return ExitCode::FAILURE;
+            }
+        };
+
+        announcement.admin_url = Some(listener.url("http"));
+        if console {
+            println!("DROPPA: Admin endpoints on {} (/metrics, /healthz, /readyz)", listener.url("http"));
+        }
+
+        // Detached so it keeps answering /readyz and /metrics while the public listeners drain.
+        tokio::spawn(start_admin(listener, shutdown.clone(), ready.clone()));
+    }
+
+    if let Err(err) = announcement.publish(plan.ready_file.as_deref()) {
+        error!("Failed to write startup announcement: {}", err);
+    }
+
+    ready.store(true, Ordering::SeqCst);
+
+    let dashboard_done = CancellationToken::new();
+    let dashboard = plan.tui.then(|| {
+        let title = announcement.listeners.iter().map(|listener| listener.url.as_str()).collect::<Vec<_>>().join(" ");
+        let (shutdown, done) = (shutdown.clone(), dashboard_done.clone());
+        tokio::task::spawn_blocking(move || tui::dashboard::run(shutdown, title, done))
+    });
+
+    join_all(services).await;
+    shutdown.drain(plan.shutdown_timeout).await;
+
+    if let Some(dashboard) = dashboard {
+        dashboard_done.cancel();
+        match dashboard.await {
+            Ok(Ok(())) => println!("DROPPA: {}", shutdown.transfers.summary()),
+            Ok(Err(err)) => eprintln!("DROPPA: Dashboard failed: {}", err),
+            Err(err) => eprintln!("DROPPA: Dashboard failed: {}", err),
+        }
+    }
+
+    ExitCode::SUCCESS
 }
 
-fn should_start_plain_server(enable_ssl: &bool, proxy_target_addr: &str, private_key_path: &Option<PathBuf>, cert_path: &Option<PathBuf>) -> bool {
-    proxy_target_addr.is_empty() && (!*enable_ssl || !(private_key_path.is_some() || cert_path.is_some()))
+/// `droppa ca init`: creates the root and says how to use it. Returns the exit code.
+fn init_ca(settings: &Settings, force: bool) -> u8 {
+    let Some(dir) = &settings.ca else {
+        eprintln!("DROPPA: ca init needs --ca <dir>");
+        return 2;
+    };
+    let key_type: KeyType = match settings.key_type.parse() {
+        Ok(key_type) => key_type,
+        Err(err) => {
+            eprintln!("DROPPA: --key-type: {}", err);
+            return 2;
+        }
+    };
+
+    match CertificateAuthority::init(dir, key_type, force) {
+        Ok(authority) => {
+            println!("DROPPA: Created {} CA {}", key_type, CertificateAuthority::cert_path(dir).display());
+            println!("DROPPA: CA certificate SHA-256 {}", sha256_fingerprint(authority.cert()));
+            println!("DROPPA: Trust it on clients once, then serve with --tls --ca {}", dir.display());
+            0
+        }
+        Err(err) => {
+            eprintln!("DROPPA: Failed to create CA in {}: {}", dir.display(), err);
+            1
+        }
+    }
 }
 
+/// `droppa cert export`: bundles the certificate and key the other flags would serve.
+/// Returns the exit code.
+fn export_cert(settings: &Settings, bundle: &Path, legacy: bool) -> u8 {
+    let plan = match RunPlan::resolve(settings) {
+        Ok(plan) => plan,
+        Err(errors) => {
+            eprint!("DROPPA: Invalid configuration:\n{}", errors);
+            return 2;
+        }
+    };
+    let Some(material) = &plan.tls else {
+        eprintln!("DROPPA: Nothing to export, every listener is plain; pass --tls, --cert/--priv or --pkcs12");
+        return 2;
+    };
 
-async fn start_tls_proxy(
-    listen_address: &str,
-    port: &str,
-    dir: Arc<PathBuf>,
-    issuer: &str,
-    private_key_path: Option<PathBuf>,
-    cert_path: Option<PathBuf>,
-) {
-    let target_address = format!("127.0.0.1:{}", port);
+    let exported = material.key_pair().and_then(|(chain, private_key)| {
+        let password = settings.pkcs12_pass.as_deref().unwrap_or("");
+        write_pkcs12(bundle, &chain, &private_key, password, legacy)?;
+        Ok(chain)
+    });
 
-    server::start_rouille_server(target_address.clone(), dir.clone());
+    match exported {
+        Ok(chain) => {
+            let plural = if chain.len() == 1 { "" } else { "s" };
+            println!("DROPPA: Wrote {} with the key and {} certificate{}", bundle.display(), chain.len(), plural);
+            println!("DROPPA: TLS certificate SHA-256 {}", sha256_fingerprint(&chain[0]));
+            if settings.pkcs12_pass.as_deref().unwrap_or("").is_empty() {
+                println!("DROPPA: The bundle has no password, pass --pkcs12-pass to set one");
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("DROPPA: Failed to export {}: {}", bundle.display(), err);
+            1
+        }
+    }
+}
+
+fn print_advertised(listener: &Listener, advertised: &[AdvertisedUrl]) {
+    if !listener.socket_addr().is_some_and(|bound| bound.ip().is_unspecified()) {
+        return;
+    }
 
-    let proxy_address = format!("{}:{}", listen_address, port);
-    println!("DROPPA: TLS Proxy running on https://{}, from directory {}", proxy_address, dir.clone().display());
+    for advertised in advertised {
+        println!("DROPPA:   {} ({})", advertised.url, advertised.label());
+    }
+}
 
-    match start_ssl_proxy(&proxy_address, &target_address, issuer, private_key_path.as_deref(), cert_path.as_deref()).await {
-        Ok(()) => println!("OK"),
-        Err(err) => println!("{:?}", err),
+/// Prints paste-ready commands for the first few files and for uploading, so they need not be
+/// retyped on the target. The web GUI lists them for every file.
+fn print_oneliners(context: &ServerContext, base_url: &str) {
+    let target = Target {
+        base_url,
+        auth_token: context.auth_token.as_deref(),
+        insecure: context.self_signed && base_url.starts_with("https://"),
+        pins: context.pins.as_deref(),
     };
+
+    let mut files: Vec<String> = std::fs::read_dir(context.dir.as_path())
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+                .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+
+    for file in files.iter().take(STARTUP_ONELINER_FILES) {
+        println!("DROPPA: Download {}", file);
+        for oneliner in download_commands(&target, file) {
+            println!("  {}", oneliner.command);
+        }
+    }
+
+    if files.len() > STARTUP_ONELINER_FILES {
+        println!("DROPPA: ... {} more files, see {}/ for their one-liners", files.len() - STARTUP_ONELINER_FILES, base_url);
+    }
+
+    println!("DROPPA: Upload (replace FILE)");
+    for oneliner in upload_commands(&target, "FILE") {
+        println!("  {}", oneliner.command);
+    }
+}
+
+async fn start_file_server(listener: Listener, context: Arc<ServerContext>, acceptor: Option<TlsAcceptor>, shutdown: Shutdown) {
+    if let Err(err) = server::start_server(listener, context, acceptor, shutdown).await {
+        error!("File server stopped: {}", err);
+    }
+}
+
+async fn start_admin(listener: Listener, shutdown: Shutdown, ready: Arc<AtomicBool>) {
+    if let Err(err) = admin::start_admin_server(listener, shutdown, ready).await {
+        error!("Admin server stopped: {}", err);
+    }
 }
 
 async fn start_reverse_proxy(
-    listen_address: &str,
-    port: &str,
+    listener: Listener,
+    acceptor: Option<TlsAcceptor>,
     proxy_target_addr: &str,
-    issuer: &str,
-    private_key_path: Option<PathBuf>,
-    cert_path: Option<PathBuf>,
+    upstream_tls: Option<Arc<UpstreamConnector>>,
+    acme_challenges: Option<Arc<Challenges>>,
+    client_auth: Option<Arc<ClientAuth>>,
+    shutdown: Shutdown,
 ) {
-    let target_address = format!("{}", proxy_target_addr);
-    let proxy_address = format!("{}:{}", listen_address, port);
-    println!("DROPPA: TLS Proxy running on https://{} -> targeting {}", proxy_address, target_address);
-
-    match start_ssl_proxy(&proxy_address, &target_address, issuer, private_key_path.as_deref(), cert_path.as_deref()).await {
-        Ok(()) => println!("OK"),
-        Err(err) => println!("{:?}", err),
+    match start_ssl_proxy(listener, acceptor, proxy_target_addr, upstream_tls, acme_challenges, client_auth, shutdown).await {
+        Ok(()) => debug!("Reverse proxy stopped"),
+        Err(err) => error!("Reverse proxy stopped: {}", err),
     };
 }
-
-fn start_plain_server(listen_address: &str, port: &str, dir: Arc<PathBuf>) {
-    let server_address = format!("{}:{}", listen_address, port);
-    server::start_rouille_server(server_address.clone(), dir.clone());
-    println!("DROPPA: Serving on http://{} from directory {}", server_address, dir.display());
-}
\ No newline at end of file
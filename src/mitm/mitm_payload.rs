@@ -3,7 +3,7 @@ use crate::mitm::mitm::{RequestModifier, ResponseModifier, DefaultRequestModifie
 pub struct CustomRequestModifier;
 
 impl RequestModifier for CustomRequestModifier {
-    fn modify(&self, request: &str, needle: &str, payload: &str) -> String {
+    fn modify(&self, request: &str, _needle: &str, payload: &str) -> String {
         // Modifying the HOST header is important for proxy to work correctly.
         let payload = format!("Host: {}", payload);
         DefaultRequestModifier.modify(request, "Host:", &payload)
@@ -1,3 +1,4 @@
 pub mod mitm_handler;
+#[allow(clippy::module_inception)]
 pub mod mitm;
 pub mod mitm_payload;
\ No newline at end of file
@@ -1 +1,2 @@
+#[allow(clippy::module_inception)]
 pub mod proxy;
\ No newline at end of file
@@ -1,40 +1,73 @@
+use std::collections::VecDeque;
 use std::error::Error;
-use std::path::Path;
+use std::io;
 use std::sync::Arc;
-use std::convert::TryFrom;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::{TlsAcceptor, TlsConnector};
-use tokio_rustls::rustls::ServerName;
-use colored::*;
-
-use crate::crypto::tls::{generate_tls_acceptor, generate_tls_connector, prepare_tls_cert, MaybeTlsStream};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use hyper::Uri;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, trace, warn};
+
+use crate::acme::challenges::{Challenges, ACME_TLS_ALPN};
+use crate::crypto::client_auth::{Admission, ClientAuth};
+use crate::crypto::tls::{MaybeTlsStream, TLS_HANDSHAKE_TIMEOUT};
+use crate::crypto::upstream::UpstreamConnector;
+use crate::lifecycle::shutdown::Shutdown;
 use crate::mitm::mitm_handler::MitmHandler;
+use crate::transport::listener::{Listener, ACCEPT_RETRY_DELAY};
+use crate::transport::stream::{PeerAddr, Stream};
+
+/// How long a relayed connection must stay quiet after shutdown begins before it is closed.
+const SHUTDOWN_IDLE: Duration = Duration::from_secs(1);
 
 pub async fn start_ssl_proxy(
-    server_address: &str,
+    listener: Listener,
+    acceptor: Option<TlsAcceptor>,
     target_address: &str,
-    ssl_issuer: &str,
-    private_key_path: Option<&Path>,
-    cert_path: Option<&Path>,
+    upstream_tls: Option<Arc<UpstreamConnector>>,
+    acme_challenges: Option<Arc<Challenges>>,
+    client_auth: Option<Arc<ClientAuth>>,
+    shutdown: Shutdown,
 ) -> Result<(), Box<dyn Error>> {
-    let (cert, private_key) = prepare_tls_cert(ssl_issuer, private_key_path, cert_path)?;
-
-    let acceptor = generate_tls_acceptor(cert, private_key)?;
+    let target = Target { address: target_address.to_string(), tls: upstream_tls };
 
-    let listener = TcpListener::bind(server_address).await?;
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(listener = %listener.local_addr(), error = %err, "accept failed, retrying");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = shutdown.triggered() => break,
+        };
 
-    while let Ok((stream, _)) = listener.accept().await {
-        let ip = stream.peer_addr().unwrap().ip();
-        let port = stream.peer_addr().unwrap().port();
-        println!("{}", format!("{ip}:{port} - Accepted a new TLS connection").green());
+        let kind = if acceptor.is_some() { "TLS" } else { "plain" };
+        info!(peer = %peer_addr, kind, "accepted connection");
 
         let acceptor = acceptor.clone();
-        let target_address = target_address.to_string();
+        let target = target.clone();
+        let acme_challenges = acme_challenges.clone();
+        let client_auth = client_auth.clone();
+        let shutdown = shutdown.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(acceptor, stream, target_address).await {
-                eprintln!("{}", format!("Error handling connection: {:?}", e).red());
+            let transfers = &shutdown.transfers;
+            let transfer = transfers.begin(peer_addr, if acceptor.is_some() { "proxy+tls" } else { "proxy" });
+            let kill = transfer.kill_token();
+            let connection = Connection { id: transfer.id(), peer: peer_addr };
+            transfers.record_proxied();
+
+            tokio::select! {
+                result = handle_connection(acceptor, stream, target, acme_challenges, client_auth, connection, &shutdown) => {
+                    if let Err(e) = result {
+                        warn!(peer = %peer_addr, error = %e, "error handling connection");
+                    }
+                }
+                _ = kill.cancelled() => info!(peer = %peer_addr, "connection killed"),
             }
         });
     }
@@ -42,108 +75,325 @@ pub async fn start_ssl_proxy(
     Ok(())
 }
 
-async fn handle_connection(
-    acceptor: TlsAcceptor,
-    stream: TcpStream,
-    target_address: String,
-) -> Result<(), Box<dyn Error>> {
-    println!("{}", "Accepted connection from client.".cyan());
-
-    let mitm_handler = MitmHandler::new();
+/// Where connections are relayed to.
+#[derive(Clone)]
+struct Target {
+    /// The normalized `--proxy` URL.
+    address: String,
+    /// Set for `https://` upstreams.
+    tls: Option<Arc<UpstreamConnector>>,
+}
 
-    let mut client_stream = acceptor.accept(stream).await?;
-    println!("{}", "TLS handshake with client successful.".cyan());
+/// The client side of a relayed connection, as the dashboard knows it.
+#[derive(Clone, Copy)]
+struct Connection {
+    id: u64,
+    peer: PeerAddr,
+}
 
-    let (trim_target_address, domain, is_target_https) = {
-        let trim_target_address = target_address.trim_start_matches("https://").trim_start_matches("http://");
-        let is_target_https = target_address.starts_with("https://");
-        let domain = trim_target_address.split(':').next().ok_or("Invalid target address")?.to_string();
+async fn handle_connection(
+    acceptor: Option<TlsAcceptor>,
+    mut stream: Stream,
+    target: Target,
+    acme_challenges: Option<Arc<Challenges>>,
+    client_auth: Option<Arc<ClientAuth>>,
+    connection: Connection,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let transfers = &shutdown.transfers;
+    match acceptor {
+        Some(acceptor) => {
+            let accepted = tokio::select! {
+                accepted = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)) => accepted,
+                _ = shutdown.triggered() => return Ok(()),
+            };
+            let client_stream = accepted
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")))
+                .inspect_err(|_| transfers.record_tls_failure())?;
+            if client_stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) {
+                debug!(peer = %connection.peer, "answered TLS-ALPN-01 validation");
+                return Ok(());
+            }
+            if let Some(client_auth) = client_auth {
+                if let Admission::Refused = client_auth.admit(connection.peer, client_stream.get_ref().1.peer_certificates()) {
+                    return Ok(());
+                }
+            }
+            debug!("TLS handshake with client successful");
+            relay(client_stream, target, connection, shutdown).await
+        }
+        None => {
+            if let Some(challenges) = acme_challenges {
+                let answered = tokio::select! {
+                    answered = answer_http_challenge(&mut stream, &challenges) => answered?,
+                    _ = shutdown.triggered() => return Ok(()),
+                };
+                if answered {
+                    debug!(peer = %connection.peer, "answered HTTP-01 validation");
+                    return Ok(());
+                }
+            }
+            relay(stream, target, connection, shutdown).await
+        }
+    }
+}
 
-        (trim_target_address, domain.to_string(), is_target_https)
+/// Answers a request for a pending HTTP-01 token here instead of relaying it, as the
+/// upstream knows nothing of our ACME orders. Returns whether the request was one.
+async fn answer_http_challenge(stream: &mut Stream, challenges: &Challenges) -> Result<bool, Box<dyn Error>> {
+    let mut head = [0u8; 1024];
+    let n = stream.peek(&mut head).await?;
+    let key_authorization = match request_line(&head[..n]) {
+        Some((method, path)) if method == "GET" => challenges.http_response(&path),
+        _ => None,
     };
+    let Some(key_authorization) = key_authorization else { return Ok(false) };
+
+    // Consume the request before answering, or closing with it unread resets the connection.
+    let mut request = Vec::new();
+    while !is_end_of_headers(&request) && request.len() < 16 * 1024 {
+        let n = stream.read(&mut head).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&head[..n]);
+    }
 
-    let mut server_stream = if is_target_https {
-        println!("{}", format!("Connecting to target (TLS): {}", trim_target_address).green());
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        key_authorization.len(),
+        key_authorization,
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(true)
+}
 
-        let connector = generate_tls_connector()?;
-        let server_name = ServerName::try_from(domain.as_str()).map_err(|_| "Invalid domain for ServerName")?;
+async fn relay<C>(
+    mut client_stream: C,
+    target: Target,
+    connection: Connection,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let transfers = &shutdown.transfers;
+    let mitm_handler = MitmHandler::new();
 
-        let stream = TcpStream::connect(trim_target_address).await?;
-        let server_stream = TlsConnector::from(Arc::new(connector)).connect(server_name, stream).await?;
-        println!("{}", "TLS handshake with server successful.".green());
+    let (trim_target_address, domain, upstream_tls) = {
+        let trim_target_address = target.address.trim_start_matches("https://").trim_start_matches("http://");
+        let domain = target.address.parse::<Uri>()?.host().ok_or("Invalid target address")?.to_string();
 
-        MaybeTlsStream::Tls(server_stream)
-    } else {
-        println!("{}", format!("Connecting to target (plain): {}", trim_target_address).green());
+        (trim_target_address, domain, target.tls.as_deref())
+    };
 
-        let server_stream = TcpStream::connect(trim_target_address).await?;
-        println!("{}", "Connected to target (plain).".green());
+    let connect_started = Instant::now();
 
-        MaybeTlsStream::Plain(server_stream)
+    let mut server_stream = match connect(trim_target_address, upstream_tls).await {
+        Ok(server_stream) => {
+            transfers.activity.record_upstream(trim_target_address, Ok(connect_started.elapsed()));
+            server_stream
+        }
+        Err(e) => {
+            let message = e.to_string();
+            transfers.activity.record_upstream(trim_target_address, Err(message.clone()));
+            return Err(message.into());
+        }
     };
 
+    transfers.record_upstream_connect(connect_started.elapsed());
+
     let mut client_to_server_buffer = vec![0u8; 4096];
     let mut server_to_client_buffer = vec![0u8; 4096];
     let mut response_buffer = Vec::new();
     let mut headers_parsed = false;
+    let mut awaiting_response: Option<Instant> = None;
+    // Requests still waiting for their response on this keep-alive connection, oldest first.
+    let mut requests: VecDeque<(String, String)> = VecDeque::new();
 
     loop {
         tokio::select! {
             client_read = client_stream.read(&mut client_to_server_buffer) => {
                 let n = client_read?;
                 if n == 0 {
-                    println!("{}", "Client closed the connection.".cyan());
+                    debug!("client closed the connection");
                     break;
                 }
-                println!("{}", format!("Read {} bytes from client", n).cyan());
-                // println!("{}", format!("Client data:\n{}", String::from_utf8_lossy(&client_to_server_buffer[..n])).cyan());
+                trace!(bytes = n, "read from client");
+
+                if let Some(request) = request_line(&client_to_server_buffer[..n]) {
+                    // A new request on the connection gets a new response head.
+                    requests.push_back(request);
+                    headers_parsed = false;
+                }
 
                 let modified_request = mitm_handler.process_request(&client_to_server_buffer[..n], &domain)?;
 
                 server_stream.write_all(&modified_request).await?;
+                transfers.record_received(connection.id, n);
+                awaiting_response.get_or_insert_with(Instant::now);
 
-                println!("{}", format!("Forwarded {} bytes to server.", n).cyan());
+                trace!(bytes = n, "forwarded to server");
             }
 
             server_read = server_stream.read(&mut server_to_client_buffer) => {
                 match server_read {
                     Ok(n) => {
                         if n == 0 {
-                            println!("{}", "Server closed the connection.".green());
+                            debug!("server closed the connection");
                             break;
                         }
-                        println!("{}", format!("Read {} bytes from server", n).green());
-                        // println!("{}", format!("Server data:\n{}", String::from_utf8_lossy(&server_to_client_buffer[..n])).green());
+                        trace!(bytes = n, "read from server");
+
+                        if let Some(sent) = awaiting_response.take() {
+                            transfers.record_upstream_first_byte(sent.elapsed());
+                        }
 
                         response_buffer.extend_from_slice(&server_to_client_buffer[..n]);
 
                         if !headers_parsed && is_end_of_headers(&response_buffer) {
                             headers_parsed = true;
 
+                            if let Some(status) = response_status(&response_buffer) {
+                                transfers.record_response(status);
+                                if let Some((method, path)) = requests.pop_front() {
+                                    transfers.activity.record_request(connection.peer, &method, &path, status);
+                                }
+                            }
+
                             let modified_response = mitm_handler.process_response(&response_buffer, &domain)?;
 
                             client_stream.write_all(&modified_response).await?;
+                            transfers.record_sent(connection.id, modified_response.len());
 
-                            println!("{}", format!("Forwarded {} bytes to client.", modified_response.len()).green());
+                            trace!(bytes = modified_response.len(), "forwarded to client");
 
                             response_buffer.clear();
                         } else if headers_parsed {
                             client_stream.write_all(&server_to_client_buffer[..n]).await?;
-                            println!("{}", format!("Forwarded {} bytes to client.", &server_to_client_buffer[..n].len()).green());
+                            transfers.record_sent(connection.id, n);
+                            trace!(bytes = n, "forwarded to client");
                         }
                     }
                     Err(e) => {
-                        eprintln!("{}", format!("Failed to read from server: {}", e).red());
+                        warn!(error = %e, "failed to read from server");
                         break;
                     }
                 }
             }
+
+            // Only a keep-alive connection with nothing in flight is closed early; the
+            // quiet period lets a response body that is still streaming finish.
+            _ = idle_after(shutdown), if requests.is_empty() => {
+                debug!("closing idle connection for shutdown");
+                break;
+            }
         }
     }
 
     Ok(())
 }
 
+/// Resolves once shutdown has begun and the relay has then seen no traffic for
+/// [`SHUTDOWN_IDLE`]; the relay loop restarts it on every read.
+async fn idle_after(shutdown: &Shutdown) {
+    shutdown.triggered().await;
+    tokio::time::sleep(SHUTDOWN_IDLE).await;
+}
+
+async fn connect(trim_target_address: &str, upstream_tls: Option<&UpstreamConnector>) -> Result<MaybeTlsStream, Box<dyn Error>> {
+    let server_stream = if let Some(upstream_tls) = upstream_tls {
+        debug!(target = trim_target_address, "connecting to target (TLS)");
+
+        let stream = TcpStream::connect(trim_target_address).await?;
+        let server_stream = upstream_tls.connect(stream).await?;
+        debug!("TLS handshake with server successful");
+
+        MaybeTlsStream::Tls(Box::new(server_stream))
+    } else {
+        debug!(target = trim_target_address, "connecting to target (plain)");
+
+        let server_stream = TcpStream::connect(trim_target_address).await?;
+        debug!("connected to target (plain)");
+
+        MaybeTlsStream::Plain(server_stream)
+    };
+
+    Ok(server_stream)
+}
+
+/// Reads the method and target from a `METHOD /path HTTP/1.x` request line, or `None` when
+/// the buffer does not start with one, such as the rest of a request body.
+fn request_line(buffer: &[u8]) -> Option<(String, String)> {
+    let line = buffer.split(|&byte| byte == b'\r').next()?;
+    let mut parts = std::str::from_utf8(line).ok()?.split(' ');
+    let (method, path, version) = (parts.next()?, parts.next()?, parts.next()?);
+    match !method.is_empty() && method.bytes().all(|byte| byte.is_ascii_uppercase()) && version.starts_with("HTTP/1.") {
+        true => Some((method.to_string(), path.to_string())),
+        false => None,
+    }
+}
+
+/// Reads the code from an `HTTP/1.x NNN reason` status line.
+fn response_status(buffer: &[u8]) -> Option<u16> {
+    let line = buffer.split(|&byte| byte == b'\r').next()?;
+    let code = std::str::from_utf8(line).ok()?.split(' ').nth(1)?;
+    code.parse().ok()
+}
+
 fn is_end_of_headers(buffer: &[u8]) -> bool {
     buffer.windows(4).any(|window| window == b"\r\n\r\n")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::acme::challenges::ChallengeType;
+
+    async fn pair() -> (TcpStream, Stream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, Stream::Tcp(server))
+    }
+
+    #[tokio::test]
+    async fn answers_pending_http_challenges() {
+        let challenges = Challenges::default();
+        challenges.publish(ChallengeType::Http01, "example.test", "token1", "token1.thumbprint").unwrap();
+        let (mut client, mut server) = pair().await;
+
+        client.write_all(b"GET /.well-known/acme-challenge/token1 HTTP/1.1\r\nHost: example.test\r\n\r\n").await.unwrap();
+        assert!(answer_http_challenge(&mut server, &challenges).await.unwrap());
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\ntoken1.thumbprint"));
+    }
+
+    #[tokio::test]
+    async fn leaves_other_requests_for_the_upstream() {
+        let challenges = Challenges::default();
+        challenges.publish(ChallengeType::Http01, "example.test", "token1", "token1.thumbprint").unwrap();
+        let request = b"GET /.well-known/acme-challenge/token2 HTTP/1.1\r\n\r\n";
+        let (mut client, mut server) = pair().await;
+
+        client.write_all(request).await.unwrap();
+        assert!(!answer_http_challenge(&mut server, &challenges).await.unwrap());
+
+        // Only peeked, so the request is still there to relay.
+        let mut unread = vec![0u8; request.len()];
+        server.read_exact(&mut unread).await.unwrap();
+        assert_eq!(unread, request);
+    }
+
+    #[test]
+    fn request_line_skips_body_chunks() {
+        assert_eq!(request_line(b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n"), Some(("GET".to_string(), "/a.txt".to_string())));
+        assert_eq!(request_line(b"name=a b HTTP/1.1 c"), None);
+        assert_eq!(request_line(b"--boundary\r\nContent-Disposition: form-data\r\n"), None);
+    }
+}
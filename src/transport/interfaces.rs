@@ -0,0 +1,99 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use if_addrs::{get_if_addrs, Interface};
+
+/// A URL a remote client could paste, with the interface it belongs to.
+pub struct AdvertisedUrl {
+    pub url: String,
+    pub interface: String,
+    pub loopback: bool,
+    pub link_local: bool,
+}
+
+impl AdvertisedUrl {
+    pub fn label(&self) -> String {
+        match (self.loopback, self.link_local) {
+            (true, _) => format!("{}, loopback", self.interface),
+            (_, true) => format!("{}, link-local", self.interface),
+            _ => self.interface.clone(),
+        }
+    }
+}
+
+pub fn interface_addresses(name: &str) -> io::Result<Vec<IpAddr>> {
+    let addresses: Vec<IpAddr> = get_if_addrs()?
+        .into_iter()
+        .filter(|interface| interface.name == name)
+        .map(|interface| interface.ip())
+        .collect();
+
+    if addresses.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no interface named {} with an address", name)));
+    }
+
+    Ok(addresses)
+}
+
+/// Expands a bound address into one URL per interface address a client could reach it on.
+/// Wildcard binds list every matching interface, IPv4 ones included for dual-stack `[::]`,
+/// restricted to `only_interface` when given. Specific binds advertise themselves.
+pub fn advertised_urls(bound: SocketAddr, scheme: &str, dual_stack: bool, only_interface: Option<&str>) -> Vec<AdvertisedUrl> {
+    let interfaces = get_if_addrs().unwrap_or_default();
+
+    if !bound.ip().is_unspecified() {
+        let interface = interfaces.iter().find(|interface| interface.ip() == bound.ip());
+        return vec![advertise(scheme, bound.ip(), bound.port(), interface, interface.map_or("?", |i| i.name.as_str()))];
+    }
+
+    let mut urls: Vec<AdvertisedUrl> = reachable_interfaces(&interfaces, bound.ip(), dual_stack, only_interface)
+        .map(|interface| advertise(scheme, interface.ip(), bound.port(), Some(interface), &interface.name))
+        .collect();
+
+    // Routable addresses first, then link-local, loopback last.
+    urls.sort_by_key(|url| (url.loopback, url.link_local));
+    urls
+}
+
+/// The addresses a bind on `ip` answers on: itself, or every matching interface address for
+/// a wildcard, filtered as in `advertised_urls`.
+pub fn bound_addresses(ip: IpAddr, dual_stack: bool, only_interface: Option<&str>) -> Vec<IpAddr> {
+    if !ip.is_unspecified() {
+        return vec![ip];
+    }
+
+    let interfaces = get_if_addrs().unwrap_or_default();
+    reachable_interfaces(&interfaces, ip, dual_stack, only_interface).map(Interface::ip).collect()
+}
+
+fn reachable_interfaces<'a>(
+    interfaces: &'a [Interface],
+    wildcard: IpAddr,
+    dual_stack: bool,
+    only_interface: Option<&'a str>,
+) -> impl Iterator<Item = &'a Interface> {
+    interfaces
+        .iter()
+        .filter(move |interface| only_interface.is_none_or(|name| interface.name == name))
+        .filter(move |interface| match interface.ip() {
+            IpAddr::V4(_) => wildcard.is_ipv4() || dual_stack,
+            IpAddr::V6(_) => wildcard.is_ipv6(),
+        })
+}
+
+fn advertise(scheme: &str, ip: IpAddr, port: u16, interface: Option<&Interface>, name: &str) -> AdvertisedUrl {
+    let link_local = interface.is_some_and(|interface| interface.is_link_local());
+
+    let host = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        // Link-local IPv6 needs the zone, percent-encoded inside a URL.
+        IpAddr::V6(ip) if link_local => format!("[{}%25{}]", ip, name),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+
+    AdvertisedUrl {
+        url: format!("{}://{}:{}", scheme, host, port),
+        interface: name.to_string(),
+        loopback: ip.is_loopback(),
+        link_local,
+    }
+}
@@ -0,0 +1,325 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::transport::interfaces::interface_addresses;
+use crate::transport::stream::{PeerAddr, Stream};
+
+/// How long to wait after a failed accept, such as EMFILE, before trying again. Without the
+/// pause a listener out of file descriptors would spin.
+pub const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp { host: String, port: Option<u16> },
+    Unix(PathBuf),
+}
+
+/// One `--listen` value: `<address>[,tls|,plain]`, where the address is an IPv4 address or
+/// hostname, a bracketed IPv6 literal, or `unix:/path.sock`, optionally followed by `:port`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ListenerSpec {
+    pub address: ListenAddr,
+    pub tls: Option<bool>,
+}
+
+impl FromStr for ListenerSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, tls) = match value.rsplit_once(',') {
+            Some((address, "tls")) => (address, Some(true)),
+            Some((address, "plain")) => (address, Some(false)),
+            Some((_, option)) => return Err(format!("unknown listener option '{}', expected 'tls' or 'plain'", option)),
+            None => (value, None),
+        };
+
+        Ok(ListenerSpec { address: address.parse()?, tls })
+    }
+}
+
+impl TryFrom<String> for ListenerSpec {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ListenerSpec> for String {
+    fn from(spec: ListenerSpec) -> Self {
+        spec.to_string()
+    }
+}
+
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tls {
+            Some(true) => write!(f, "{},tls", self.address),
+            Some(false) => write!(f, "{},plain", self.address),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix listener needs a socket path".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(|| format!("unterminated IPv6 literal '{}'", address))?;
+            let port = match rest {
+                "" => None,
+                _ => Some(rest.strip_prefix(':').ok_or_else(|| format!("invalid listen address '{}'", address))?),
+            };
+            (host, port)
+        } else if address.matches(':').count() > 1 {
+            (address, None)
+        } else {
+            match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(format!("invalid listen address '{}'", address));
+        }
+
+        let port = port
+            .map(|port| port.parse::<u16>().map_err(|_| format!("invalid port in listen address '{}'", address)))
+            .transpose()?;
+
+        Ok(ListenAddr::Tcp { host: host.to_string(), port })
+    }
+}
+
+impl ListenAddr {
+    /// The address with `port` filled in when none was given, as [`Listener::bind`] uses it.
+    pub fn with_default_port(&self, port: u16) -> ListenAddr {
+        match self {
+            ListenAddr::Tcp { host, port: None } => ListenAddr::Tcp { host: host.clone(), port: Some(port) },
+            address => address.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp { host, port: Some(port) } if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            ListenAddr::Tcp { host, port: Some(port) } => write!(f, "{}:{}", host, port),
+            ListenAddr::Tcp { host, port: None } if host.contains(':') => write!(f, "[{}]", host),
+            ListenAddr::Tcp { host, port: None } => write!(f, "{}", host),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub struct BindOptions {
+    pub default_port: u16,
+    pub dual_stack: bool,
+    pub interface: Option<String>,
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds the address, filling in `default_port` when none was given. IPv6 sockets are
+    /// IPv6-only unless `dual_stack` is set, so `0.0.0.0` and `[::]` can share a port.
+    /// With an `interface`, Linux pins the socket to the device with SO_BINDTODEVICE; other
+    /// platforms bind the interface's own address in place of a wildcard.
+    pub async fn bind(address: &ListenAddr, options: &BindOptions) -> io::Result<Self> {
+        match address {
+            ListenAddr::Tcp { host, port } => {
+                let port = port.unwrap_or(options.default_port);
+                let mut socket_addr = tokio::net::lookup_host((host.as_str(), port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, format!("{} did not resolve", host)))?;
+
+                if let Some(interface) = &options.interface {
+                    if !cfg!(any(target_os = "linux", target_os = "android")) && socket_addr.ip().is_unspecified() {
+                        let ip = interface_addresses(interface)?
+                            .into_iter()
+                            .find(|ip| ip.is_ipv4() == socket_addr.is_ipv4())
+                            .ok_or_else(|| io::Error::new(
+                                io::ErrorKind::AddrNotAvailable,
+                                format!("{} has no address of the listener's IP family", interface),
+                            ))?;
+                        socket_addr.set_ip(ip);
+                    }
+                }
+
+                Ok(Listener::Tcp(bind_tcp(socket_addr, options)?))
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), PeerAddr::Unix))
+            }
+        }
+    }
+
+    /// The bound address, with the actual port when `0` was requested.
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "?".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    pub fn local_port(&self) -> Option<u16> {
+        self.socket_addr().map(|addr| addr.port())
+    }
+
+    pub fn url(&self, scheme: &str) -> String {
+        match self {
+            Listener::Tcp(_) => format!("{}://{}", scheme, self.local_addr()),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("{}+unix://{}", scheme, path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn bind_tcp(socket_addr: SocketAddr, options: &BindOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(socket_addr), Type::STREAM, None)?;
+
+    if socket_addr.is_ipv6() {
+        socket.set_only_v6(!options.dual_stack)?;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(interface) = &options.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&socket_addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display()))),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(host: &str, port: Option<u16>) -> ListenAddr {
+        ListenAddr::Tcp { host: host.to_string(), port }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!("0.0.0.0:8080".parse(), Ok(tcp("0.0.0.0", Some(8080))));
+        assert_eq!("localhost".parse(), Ok(tcp("localhost", None)));
+        assert_eq!("[::1]:8443".parse(), Ok(tcp("::1", Some(8443))));
+        assert_eq!("[::]".parse(), Ok(tcp("::", None)));
+        assert_eq!("fe80::1".parse(), Ok(tcp("fe80::1", None)));
+        assert_eq!("unix:/run/droppa.sock".parse(), Ok(ListenAddr::Unix(PathBuf::from("/run/droppa.sock"))));
+    }
+
+    #[test]
+    fn rejects_bad_addresses() {
+        for address in ["host:", "host:65536", "host:http", "[::1]:", "[::1]8080", "[::1", ":8080", "unix:"] {
+            assert!(address.parse::<ListenAddr>().is_err(), "{} parsed", address);
+        }
+    }
+
+    #[test]
+    fn parses_tls_suffixes() {
+        let spec: ListenerSpec = "[::]:443,tls".parse().unwrap();
+        assert_eq!(spec, ListenerSpec { address: tcp("::", Some(443)), tls: Some(true) });
+        let spec: ListenerSpec = "unix:/tmp/d.sock,plain".parse().unwrap();
+        assert_eq!(spec, ListenerSpec { address: ListenAddr::Unix(PathBuf::from("/tmp/d.sock")), tls: Some(false) });
+        assert_eq!("127.0.0.1:80".parse::<ListenerSpec>().unwrap().tls, None);
+
+        let error = "127.0.0.1:80,quic".parse::<ListenerSpec>().unwrap_err();
+        assert!(error.contains("'quic'"), "{}", error);
+        assert!("127.0.0.1:80,".parse::<ListenerSpec>().is_err());
+        assert!("127.0.0.1:99999,tls".parse::<ListenerSpec>().is_err());
+    }
+
+    #[test]
+    fn displays_what_it_parses() {
+        for value in ["0.0.0.0:8080", "[::1]:8443,tls", "[::]", "host,plain", "unix:/tmp/d.sock"] {
+            assert_eq!(value.parse::<ListenerSpec>().unwrap().to_string(), value);
+        }
+        assert_eq!(tcp("::", None).with_default_port(8000).to_string(), "[::]:8000");
+        assert_eq!(tcp("::", Some(1)).with_default_port(8000).to_string(), "[::]:1");
+    }
+}
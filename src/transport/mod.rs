@@ -1 +1,4 @@
-pub mod compression;
\ No newline at end of file
+pub mod compression;
+pub mod interfaces;
+pub mod listener;
+pub mod stream;
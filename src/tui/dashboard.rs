@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, LineGauge, Paragraph, Row, Sparkline, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use tokio_util::sync::CancellationToken;
+use tracing::Level;
+
+use crate::lifecycle::activity::{ActivitySnapshot, ConnectionEntry};
+use crate::lifecycle::audit::Direction;
+use crate::lifecycle::shutdown::Shutdown;
+use crate::lifecycle::transfers::TransferStats;
+use crate::transport::stream::PeerAddr;
+
+const TICK: Duration = Duration::from_millis(250);
+const HISTORY: usize = 240;
+
+/// Draws the dashboard until `done` is cancelled, which main does once the drain finishes.
+/// Quitting triggers the shutdown; quitting again while draining exits at once.
+pub fn run(shutdown: Shutdown, title: String, done: CancellationToken) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = Dashboard::new(shutdown, title).run(&mut terminal, &done);
+    ratatui::restore();
+    result
+}
+
+struct Dashboard {
+    shutdown: Shutdown,
+    title: String,
+    started: Instant,
+    last_sample: Instant,
+    /// Bytes in and out per connection at the last sample.
+    last_bytes: HashMap<u64, (u64, u64)>,
+    last_total: u64,
+    /// Bytes per second in and out per connection.
+    rates: HashMap<u64, (f64, f64)>,
+    history: VecDeque<u64>,
+    selected: Option<u64>,
+    status: Option<String>,
+}
+
+impl Dashboard {
+    fn new(shutdown: Shutdown, title: String) -> Self {
+        Self {
+            shutdown,
+            title,
+            started: Instant::now(),
+            last_sample: Instant::now(),
+            last_bytes: HashMap::new(),
+            last_total: 0,
+            rates: HashMap::new(),
+            history: VecDeque::with_capacity(HISTORY),
+            selected: None,
+            status: None,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal, done: &CancellationToken) -> io::Result<()> {
+        while !done.is_cancelled() {
+            let snapshot = self.shutdown.transfers.activity.snapshot();
+            let stats = self.shutdown.transfers.stats();
+            self.sample(&snapshot, &stats);
+            terminal.draw(|frame| self.draw(frame, &snapshot, &stats))?;
+
+            if !event::poll(TICK)? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let ctrl_c = key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c');
+                match key.code {
+                    _ if ctrl_c => self.quit(),
+                    KeyCode::Char('q') | KeyCode::Esc => self.quit(),
+                    KeyCode::Up => self.move_selection(&snapshot.connections, -1),
+                    KeyCode::Down => self.move_selection(&snapshot.connections, 1),
+                    KeyCode::Char('k') => self.kill_selected(),
+                    KeyCode::Char('p') => self.toggle_uploads(),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sample(&mut self, snapshot: &ActivitySnapshot, stats: &TransferStats) {
+        let elapsed = self.last_sample.elapsed().as_secs_f64();
+        if elapsed < TICK.as_secs_f64() {
+            return;
+        }
+        self.last_sample = Instant::now();
+
+        let mut bytes = HashMap::new();
+        self.rates.clear();
+        for connection in &snapshot.connections {
+            let (last_in, last_out) = self.last_bytes.get(&connection.id).copied().unwrap_or((0, 0));
+            let rate_in = (connection.bytes_in - last_in) as f64 / elapsed;
+            let rate_out = (connection.bytes_out - last_out) as f64 / elapsed;
+            self.rates.insert(connection.id, (rate_in, rate_out));
+            bytes.insert(connection.id, (connection.bytes_in, connection.bytes_out));
+        }
+        self.last_bytes = bytes;
+
+        let total = stats.bytes_sent + stats.bytes_received;
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(((total - self.last_total) as f64 / elapsed) as u64);
+        self.last_total = total;
+    }
+
+    fn quit(&mut self) {
+        if self.shutdown.is_triggered() {
+            ratatui::restore();
+            println!("DROPPA: Forced exit, {}", self.shutdown.transfers.summary());
+            std::process::exit(130);
+        }
+        self.shutdown.trigger();
+        self.status = Some("Shutting down, waiting for active transfers (q again to force)".to_string());
+    }
+
+    fn move_selection(&mut self, connections: &[ConnectionEntry], step: isize) {
+        if connections.is_empty() {
+            self.selected = None;
+            return;
+        }
+        let index = self.selected_index(connections).map_or(0, |index| {
+            (index as isize + step).clamp(0, connections.len() as isize - 1) as usize
+        });
+        self.selected = Some(connections[index].id);
+    }
+
+    fn selected_index(&self, connections: &[ConnectionEntry]) -> Option<usize> {
+        self.selected.and_then(|id| connections.iter().position(|connection| connection.id == id))
+    }
+
+    fn kill_selected(&mut self) {
+        let activity = &self.shutdown.transfers.activity;
+        self.status = Some(match self.selected {
+            Some(id) if activity.kill(id) => format!("Killed connection #{}", id),
+            Some(id) => format!("Connection #{} already closed", id),
+            None => "Select a connection with the arrow keys first".to_string(),
+        });
+    }
+
+    fn toggle_uploads(&mut self) {
+        let activity = &self.shutdown.transfers.activity;
+        let paused = !activity.uploads_paused();
+        activity.set_uploads_paused(paused);
+        self.status = Some(if paused { "Uploads paused" } else { "Uploads resumed" }.to_string());
+    }
+
+    fn draw(&self, frame: &mut Frame, snapshot: &ActivitySnapshot, stats: &TransferStats) {
+        let [header, top, middle, bottom, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Percentage(35),
+            Constraint::Percentage(25),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        self.draw_header(frame, header, snapshot, stats);
+
+        let [connections, transfers] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(top);
+        self.draw_connections(frame, connections, &snapshot.connections);
+        draw_transfers(frame, transfers, &snapshot.connections);
+
+        let [throughput, clients, upstream] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(25), Constraint::Percentage(25)]).areas(middle);
+        self.draw_throughput(frame, throughput);
+        self.draw_clients(frame, clients, &snapshot.connections);
+        draw_upstream(frame, upstream, snapshot);
+
+        let [requests, errors] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(bottom);
+        draw_requests(frame, requests, snapshot);
+        draw_errors(frame, errors, snapshot);
+
+        let keys = "↑/↓ select  k kill connection  p pause/resume uploads  q quit";
+        let footer_line = match &self.status {
+            Some(status) => Line::from(vec![Span::raw(keys).dark_gray(), Span::raw("  │  "), Span::raw(status.as_str()).yellow()]),
+            None => Line::from(Span::raw(keys).dark_gray()),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect, snapshot: &ActivitySnapshot, stats: &TransferStats) {
+        let mut spans = vec![
+            Span::raw(" DROPPA ").bold().reversed(),
+            Span::raw(format!(" {}  up {}  ", self.title, format_uptime(self.started.elapsed()))),
+            Span::raw(format!(
+                "{} open, {} total  {} down, {} up  sent {}  received {}",
+                stats.active,
+                stats.connections,
+                stats.downloads,
+                stats.uploads,
+                format_bytes(stats.bytes_sent),
+                format_bytes(stats.bytes_received),
+            )),
+        ];
+        if snapshot.uploads_paused {
+            spans.push(Span::raw("  UPLOADS PAUSED").yellow().bold());
+        }
+        if self.shutdown.is_triggered() {
+            spans.push(Span::raw("  DRAINING").red().bold());
+        }
+        frame.render_widget(Paragraph::new(Line::from(spans)), area);
+    }
+
+    fn draw_connections(&self, frame: &mut Frame, area: Rect, connections: &[ConnectionEntry]) {
+        let rows = connections.iter().map(|connection| {
+            let (rate_in, rate_out) = self.rates.get(&connection.id).copied().unwrap_or_default();
+            Row::new(vec![
+                format!("#{}", connection.id),
+                connection.peer.to_string(),
+                connection.kind.to_string(),
+                format_uptime(connection.started.elapsed()),
+                format_bytes(connection.bytes_in),
+                format_bytes(connection.bytes_out),
+                format!("{}/s", format_bytes((rate_in + rate_out) as u64)),
+            ])
+        });
+
+        let table = Table::new(rows, [
+            Constraint::Length(6),
+            Constraint::Fill(1),
+            Constraint::Length(9),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(12),
+        ])
+        .header(Row::new(vec!["id", "peer", "kind", "age", "in", "out", "rate"]).bold())
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(format!(" Connections ({}) ", connections.len())));
+
+        let mut state = TableState::default().with_selected(self.selected_index(connections));
+        frame.render_stateful_widget(table, area, &mut state);
+    }
+
+    fn draw_throughput(&self, frame: &mut Frame, area: Rect) {
+        let current = self.history.back().copied().unwrap_or(0);
+        let peak = self.history.iter().copied().max().unwrap_or(0);
+        let block = Block::bordered().title(format!(" Throughput {}/s (peak {}/s) ", format_bytes(current), format_bytes(peak)));
+
+        // Newest samples on the right, as many as fit.
+        let width = area.width.saturating_sub(2) as usize;
+        let skip = self.history.len().saturating_sub(width);
+        let sparkline = Sparkline::default().block(block).data(self.history.iter().skip(skip).copied()).style(Style::new().cyan());
+        frame.render_widget(sparkline, area);
+    }
+
+    fn draw_clients(&self, frame: &mut Frame, area: Rect, connections: &[ConnectionEntry]) {
+        let mut clients: BTreeMap<String, f64> = BTreeMap::new();
+        for connection in connections {
+            let (rate_in, rate_out) = self.rates.get(&connection.id).copied().unwrap_or_default();
+            *clients.entry(client_name(connection.peer)).or_default() += rate_in + rate_out;
+        }
+
+        let mut clients: Vec<(String, f64)> = clients.into_iter().collect();
+        clients.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let lines: Vec<Line> = clients
+            .iter()
+            .map(|(client, rate)| Line::from(format!("{:<20} {:>10}/s", client, format_bytes(*rate as u64))))
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Per client ")), area);
+    }
+}
+
+fn draw_transfers(frame: &mut Frame, area: Rect, connections: &[ConnectionEntry]) {
+    let block = Block::bordered().title(" Transfers ");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let transfers: Vec<_> = connections
+        .iter()
+        .filter_map(|connection| connection.transfer.as_ref().map(|transfer| (connection.id, transfer)))
+        .take(inner.height as usize)
+        .collect();
+
+    if transfers.is_empty() {
+        frame.render_widget(Paragraph::new("No files moving").dark_gray(), inner);
+        return;
+    }
+
+    let rows = Layout::vertical(vec![Constraint::Length(1); transfers.len()]).split(inner);
+    for ((id, transfer), row) in transfers.into_iter().zip(rows.iter()) {
+        let arrow = match transfer.direction {
+            Direction::Download => "↓",
+            Direction::Upload => "↑",
+        };
+        let (ratio, amount) = match transfer.total {
+            Some(total) if total > 0 => (
+                (transfer.bytes as f64 / total as f64).min(1.0),
+                format!("{}/{}", format_bytes(transfer.bytes), format_bytes(total)),
+            ),
+            _ => (0.0, format_bytes(transfer.bytes)),
+        };
+
+        let gauge = LineGauge::default()
+            .ratio(ratio)
+            .label(format!("#{} {} {} {}", id, arrow, transfer.path, amount))
+            .filled_style(Style::new().green());
+        frame.render_widget(gauge, *row);
+    }
+}
+
+fn draw_upstream(frame: &mut Frame, area: Rect, snapshot: &ActivitySnapshot) {
+    let lines = match &snapshot.upstream {
+        None => vec![Line::from("No upstream contacted").dark_gray()],
+        Some(upstream) => vec![
+            Line::from(upstream.target.clone()),
+            match &upstream.result {
+                Ok(latency) => Line::from(format!("up, connected in {} ms", latency.as_millis())).green(),
+                Err(error) => Line::from(format!("down: {}", error)).red(),
+            },
+            Line::from(format!("checked {} ago", format_uptime(upstream.checked.elapsed()))).dark_gray(),
+        ],
+    };
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Upstream ")), area);
+}
+
+fn draw_requests(frame: &mut Frame, area: Rect, snapshot: &ActivitySnapshot) {
+    let rows = snapshot.requests.iter().take(area.height as usize).map(|request| {
+        let status = Span::raw(request.status.to_string()).style(match request.status {
+            200..=299 => Style::new().green(),
+            300..=399 => Style::new().cyan(),
+            400..=499 => Style::new().yellow(),
+            _ => Style::new().red(),
+        });
+        Row::new(vec![
+            Line::from(format!("{} ago", format_uptime(request.at.elapsed()))),
+            Line::from(request.peer.to_string()),
+            Line::from(request.method.clone()),
+            Line::from(status),
+            Line::from(request.path.clone()),
+        ])
+    });
+
+    let table = Table::new(rows, [
+        Constraint::Length(12),
+        Constraint::Length(22),
+        Constraint::Length(7),
+        Constraint::Length(4),
+        Constraint::Fill(1),
+    ])
+    .block(Block::bordered().title(" Recent requests "));
+    frame.render_widget(table, area);
+}
+
+fn draw_errors(frame: &mut Frame, area: Rect, snapshot: &ActivitySnapshot) {
+    let lines: Vec<Line> = snapshot
+        .errors
+        .iter()
+        .take(area.height as usize)
+        .map(|error| {
+            let level = Span::raw(format!("{:<5} ", error.level));
+            let level = if error.level == Level::ERROR { level.red() } else { level.yellow() };
+            let age = Span::raw(format!("{} ago ", format_uptime(error.at.elapsed()))).dark_gray();
+            Line::from(vec![age, level, Span::raw(error.message.clone())])
+        })
+        .collect();
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Warnings and errors ")), area);
+}
+
+fn client_name(peer: PeerAddr) -> String {
+    match peer {
+        PeerAddr::Tcp(addr) => addr.ip().to_string(),
+        PeerAddr::Unix => "unix".to_string(),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_uptime(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    if seconds < 3600 {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    } else {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+}
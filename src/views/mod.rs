@@ -1 +1,3 @@
-pub mod views;
\ No newline at end of file
+pub mod oneliners;
+#[allow(clippy::module_inception)]
+pub mod views;
@@ -0,0 +1,345 @@
+use std::error::Error;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use url::Url;
+
+use crate::crypto::certs::{sha1_fingerprint, sha256_fingerprint, spki_fingerprint};
+
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'\'').add(b'/').add(b'<').add(b'>')
+    .add(b'?').add(b'`').add(b'{').add(b'}').add(b'\\').add(b'^').add(b'|').add(b'[').add(b']');
+
+/// Where clients should fetch from and what they need to get in.
+pub struct Target<'a> {
+    pub base_url: &'a str,
+    pub auth_token: Option<&'a str>,
+    /// The server presents a self-signed certificate, so clients must skip verification.
+    pub insecure: bool,
+    /// Set when every client gets the same certificate, so those that can pin it do that
+    /// instead of skipping verification.
+    pub pins: Option<&'a CertificatePins>,
+}
+
+/// Fingerprints of the served certificate in the forms clients take them.
+pub struct CertificatePins {
+    /// Base64 SHA-256 of the SubjectPublicKeyInfo, for curl's `--pinnedpubkey`.
+    pub spki_sha256: String,
+    /// Colon-separated SHA-256 of the certificate, for IO::Socket::SSL's `SSL_fingerprint`.
+    pub sha256: String,
+    /// SHA-1 of the certificate, the one hash `GetCertHashString()` offers on every .NET.
+    pub sha1: String,
+}
+
+impl CertificatePins {
+    pub fn of(cert_der: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            spki_sha256: spki_fingerprint(cert_der)?,
+            sha256: sha256_fingerprint(cert_der),
+            sha1: sha1_fingerprint(cert_der),
+        })
+    }
+}
+
+pub struct OneLiner {
+    pub client: &'static str,
+    pub command: String,
+}
+
+impl Target<'_> {
+    pub fn file_url(&self, file_name: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), utf8_percent_encode(file_name, PATH_SEGMENT))
+    }
+
+    fn upload_url(&self) -> String {
+        format!("{}/", self.base_url.trim_end_matches('/'))
+    }
+
+    fn bearer(&self) -> Option<String> {
+        self.auth_token.map(|token| format!("Bearer {}", token))
+    }
+
+    fn pinned(&self) -> Option<&CertificatePins> {
+        self.pins.filter(|_| self.insecure)
+    }
+}
+
+pub fn download_commands(target: &Target, file_name: &str) -> Vec<OneLiner> {
+    let url = target.file_url(file_name);
+    let bearer = target.bearer();
+
+    let mut curl = String::from("curl -fsSL");
+    curl.push_str(&curl_tls(target));
+    if let Some(bearer) = &bearer {
+        curl.push_str(&format!(" -H {}", sh(&format!("Authorization: {}", bearer))));
+    }
+    curl.push_str(&format!(" -o {} {}", sh(file_name), sh(&url)));
+
+    let mut wget = String::from("wget -q");
+    if target.insecure {
+        wget.push_str(" --no-check-certificate");
+    }
+    if let Some(bearer) = &bearer {
+        wget.push_str(&format!(" --header={}", sh(&format!("Authorization: {}", bearer))));
+    }
+    wget.push_str(&format!(" -O {} {}", sh(file_name), sh(&url)));
+
+    let python = format!(
+        "import ssl,urllib.request as u;r=u.Request({},headers={});open({},'wb').write(u.urlopen(r{}).read())",
+        py(&url),
+        py_headers(&bearer),
+        py(file_name),
+        if target.insecure { ",context=ssl._create_unverified_context()" } else { "" },
+    );
+
+    let powershell = format!(
+        "{}Invoke-WebRequest -UseBasicParsing -Uri {}{} -OutFile {}",
+        ps_insecure(target),
+        ps(&url),
+        bearer.as_ref().map(|bearer| format!(" -Headers @{{Authorization={}}}", ps(bearer))).unwrap_or_default(),
+        ps(file_name),
+    );
+
+    let perl_tls = match target.pinned() {
+        // IO::Socket::SSL accepts a certificate whose fingerprint matches even when it does not verify.
+        Some(pins) => format!("verify_SSL=>1,SSL_options=>{{SSL_fingerprint=>{}}}", perl(&format!("sha256${}", pins.sha256))),
+        None if target.insecure => "verify_SSL=>0".to_string(),
+        None => "verify_SSL=>1".to_string(),
+    };
+
+    let perl = format!(
+        "$r=HTTP::Tiny->new({})->mirror({},{}{});die \"$r->{{status}}\\n\" unless $r->{{success}}",
+        perl_tls,
+        perl(&url),
+        perl(file_name),
+        bearer.as_ref().map(|bearer| format!(",{{headers=>{{Authorization=>{}}}}}", perl(bearer))).unwrap_or_default(),
+    );
+
+    let mut commands = vec![
+        OneLiner { client: "curl", command: curl },
+        OneLiner { client: "wget", command: wget },
+        OneLiner { client: "python", command: format!("python3 -c {}", sh(&python)) },
+        OneLiner { client: "powershell", command: powershell },
+        OneLiner { client: "perl", command: format!("perl -MHTTP::Tiny -e {}", sh(&perl)) },
+    ];
+    commands.extend(raw_download(target, file_name, &bearer));
+    commands
+}
+
+/// A bare HTTP/1.0 request through `openssl s_client` or `nc` for hosts with neither an HTTP
+/// client nor a bash built with `/dev/tcp`. The response headers are cut off at the blank line.
+fn raw_download(target: &Target, file_name: &str, bearer: &Option<String>) -> Option<OneLiner> {
+    let url = Url::parse(&target.file_url(file_name)).ok()?;
+    let (host, port) = (url.host_str()?, url.port_or_known_default()?);
+    let authority = format!("{}:{}", host, port);
+
+    let mut request = format!("GET {} HTTP/1.0\\r\\nHost: {}\\r\\n", url.path(), authority);
+    if let Some(bearer) = bearer {
+        request.push_str(&format!("Authorization: {}\\r\\n", bearer));
+    }
+    request.push_str("\\r\\n");
+    let printf = format!("printf {}", sh(&request.replace('%', "%%")));
+    let strip_headers = format!("sed '1,/^\\r$/d' > {}", sh(file_name));
+
+    Some(match url.scheme() {
+        "https" => {
+            let server_name = url.domain().map(|domain| format!(" -servername {}", sh(domain))).unwrap_or_default();
+            OneLiner {
+                client: "openssl",
+                command: format!("{} | openssl s_client -quiet -connect {}{} 2>/dev/null | {}", printf, sh(&authority), server_name, strip_headers),
+            }
+        }
+        _ => OneLiner {
+            client: "nc",
+            command: format!("{} | nc {} {} | {}", printf, sh(host.trim_matches(['[', ']'])), port, strip_headers),
+        },
+    })
+}
+
+/// Upload commands for a local file; `file_name` is usually a placeholder such as `FILE`.
+pub fn upload_commands(target: &Target, file_name: &str) -> Vec<OneLiner> {
+    let url = target.upload_url();
+    let bearer = target.bearer();
+
+    let mut curl = String::from("curl -fsS");
+    curl.push_str(&curl_tls(target));
+    if let Some(bearer) = &bearer {
+        curl.push_str(&format!(" -H {}", sh(&format!("Authorization: {}", bearer))));
+    }
+    curl.push_str(&format!(" -F {} {} -o /dev/null", sh(&format!("file=@{}", file_name)), sh(&url)));
+
+    let mut headers = String::from("{'Content-Type':'multipart/form-data; boundary='+b");
+    if let Some(bearer) = &bearer {
+        headers.push_str(&format!(",'Authorization':{}", py(bearer)));
+    }
+    headers.push('}');
+
+    let python = format!(
+        "import os,ssl,uuid,urllib.request as u;f={};b=uuid.uuid4().hex;\
+         d=('--%s\\r\\nContent-Disposition: form-data; name=\"file\"; filename=\"%s\"\\r\\nContent-Type: application/octet-stream\\r\\n\\r\\n'%(b,os.path.basename(f))).encode()+open(f,'rb').read()+('\\r\\n--%s--\\r\\n'%b).encode();\
+         u.urlopen(u.Request({},data=d,headers={}){})",
+        py(file_name),
+        py(&url),
+        headers,
+        if target.insecure { ",context=ssl._create_unverified_context()" } else { "" },
+    );
+
+    let powershell = format!(
+        "{}$w=New-Object Net.WebClient;{}$w.UploadFile({},(Resolve-Path {}).Path)",
+        ps_insecure(target),
+        bearer.as_ref().map(|bearer| format!("$w.Headers.Add('Authorization',{});", ps(bearer))).unwrap_or_default(),
+        ps(&url),
+        ps(file_name),
+    );
+
+    vec![
+        OneLiner { client: "curl", command: curl },
+        OneLiner { client: "python", command: format!("python3 -c {}", sh(&python)) },
+        OneLiner { client: "powershell", command: powershell },
+    ]
+}
+
+fn curl_tls(target: &Target) -> String {
+    match target.pinned() {
+        // curl checks the pin even with -k, which only skips the chain and name checks.
+        Some(pins) => format!(" -k --pinnedpubkey {}", sh(&format!("sha256//{}", pins.spki_sha256))),
+        None if target.insecure => " -k".to_string(),
+        None => String::new(),
+    }
+}
+
+fn ps_insecure(target: &Target) -> String {
+    match target.pinned() {
+        Some(pins) => format!("[Net.ServicePointManager]::ServerCertificateValidationCallback={{$args[1].GetCertHashString() -eq {}}};", ps(&pins.sha1)),
+        None if target.insecure => "[Net.ServicePointManager]::ServerCertificateValidationCallback={$true};".to_string(),
+        None => String::new(),
+    }
+}
+
+fn py_headers(bearer: &Option<String>) -> String {
+    match bearer {
+        Some(bearer) => format!("{{'Authorization':{}}}", py(bearer)),
+        None => "{}".to_string(),
+    }
+}
+
+/// POSIX shell single-quoted string.
+fn sh(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// PowerShell single-quoted string.
+fn ps(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Python single-quoted string literal.
+fn py(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Perl single-quoted string literal, which does not interpolate.
+fn perl(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "it's a 100% naïve.txt";
+    const SH_FILE: &str = r#"'it'\''s a 100% naïve.txt'"#;
+    const ENCODED: &str = "it%27s%20a%20100%25%20na%C3%AFve.txt";
+
+    fn pins() -> CertificatePins {
+        CertificatePins { spki_sha256: "c3BraQ==".to_string(), sha256: "AA:BB".to_string(), sha1: "A1B2".to_string() }
+    }
+
+    fn command<'a>(commands: &'a [OneLiner], client: &str) -> &'a str {
+        &commands.iter().find(|one_liner| one_liner.client == client).unwrap().command
+    }
+
+    #[test]
+    fn quotes_for_each_language() {
+        let value = r"it's C:\ 100%";
+        assert_eq!(sh(value), r#"'it'\''s C:\ 100%'"#);
+        assert_eq!(ps(value), r#"'it''s C:\ 100%'"#);
+        assert_eq!(py(value), r#"'it\'s C:\\ 100%'"#);
+        assert_eq!(perl(value), r#"'it\'s C:\\ 100%'"#);
+    }
+
+    #[test]
+    fn downloads_over_http_with_a_token() {
+        let target = Target { base_url: "http://127.0.0.1:8080/", auth_token: Some("s3cret"), insecure: false, pins: None };
+        let url = format!("http://127.0.0.1:8080/{}", ENCODED);
+        let commands = download_commands(&target, FILE);
+
+        assert_eq!(command(&commands, "curl"), format!("curl -fsSL -H 'Authorization: Bearer s3cret' -o {} '{}'", SH_FILE, url));
+        assert_eq!(
+            command(&commands, "powershell"),
+            format!("Invoke-WebRequest -UseBasicParsing -Uri '{}' -Headers @{{Authorization='Bearer s3cret'}} -OutFile 'it''s a 100% naïve.txt'", url),
+        );
+        assert_eq!(
+            command(&commands, "nc"),
+            format!(
+                r"printf 'GET /{} HTTP/1.0\r\nHost: 127.0.0.1:8080\r\nAuthorization: Bearer s3cret\r\n\r\n' | nc '127.0.0.1' 8080 | sed '1,/^\r$/d' > {}",
+                ENCODED.replace('%', "%%"),
+                SH_FILE,
+            ),
+        );
+        assert_eq!(
+            command(&commands, "python"),
+            format!(
+                r#"python3 -c 'import ssl,urllib.request as u;r=u.Request('\''{}'\'',headers={{'\''Authorization'\'':'\''Bearer s3cret'\''}});open('\''it\'\''s a 100% naïve.txt'\'','\''wb'\'').write(u.urlopen(r).read())'"#,
+                url,
+            ),
+        );
+    }
+
+    #[test]
+    fn pins_a_self_signed_certificate_without_a_token() {
+        let pins = pins();
+        let target = Target { base_url: "https://files.example:8443", auth_token: None, insecure: true, pins: Some(&pins) };
+        let url = format!("https://files.example:8443/{}", ENCODED);
+        let commands = download_commands(&target, FILE);
+
+        assert_eq!(command(&commands, "curl"), format!("curl -fsSL -k --pinnedpubkey 'sha256//c3BraQ==' -o {} '{}'", SH_FILE, url));
+        assert_eq!(
+            command(&commands, "powershell"),
+            format!(
+                "[Net.ServicePointManager]::ServerCertificateValidationCallback={{$args[1].GetCertHashString() -eq 'A1B2'}};\
+                 Invoke-WebRequest -UseBasicParsing -Uri '{}' -OutFile 'it''s a 100% naïve.txt'",
+                url,
+            ),
+        );
+        assert_eq!(
+            command(&commands, "perl"),
+            format!(
+                r#"perl -MHTTP::Tiny -e '$r=HTTP::Tiny->new(verify_SSL=>1,SSL_options=>{{SSL_fingerprint=>'\''sha256$AA:BB'\''}})->mirror('\''{}'\'','\''it\'\''s a 100% naïve.txt'\'');die "$r->{{status}}\n" unless $r->{{success}}'"#,
+                url,
+            ),
+        );
+        assert_eq!(
+            command(&commands, "openssl"),
+            format!(
+                r"printf 'GET /{} HTTP/1.0\r\nHost: files.example:8443\r\n\r\n' | openssl s_client -quiet -connect 'files.example:8443' -servername 'files.example' 2>/dev/null | sed '1,/^\r$/d' > {}",
+                ENCODED.replace('%', "%%"),
+                SH_FILE,
+            ),
+        );
+    }
+
+    #[test]
+    fn skips_verification_only_when_insecure() {
+        let pins = pins();
+        let verified = Target { base_url: "https://files.example", auth_token: None, insecure: false, pins: Some(&pins) };
+        let commands = download_commands(&verified, "a.txt");
+        assert_eq!(command(&commands, "curl"), "curl -fsSL -o 'a.txt' 'https://files.example/a.txt'");
+        assert_eq!(command(&commands, "powershell"), "Invoke-WebRequest -UseBasicParsing -Uri 'https://files.example/a.txt' -OutFile 'a.txt'");
+        assert!(command(&commands, "perl").contains("HTTP::Tiny->new(verify_SSL=>1)"));
+
+        let insecure = Target { insecure: true, pins: None, ..verified };
+        let commands = download_commands(&insecure, "a.txt");
+        assert_eq!(command(&commands, "curl"), "curl -fsSL -k -o 'a.txt' 'https://files.example/a.txt'");
+        assert!(command(&commands, "powershell").starts_with("[Net.ServicePointManager]::ServerCertificateValidationCallback={$true};"));
+        assert!(command(&commands, "perl").contains("HTTP::Tiny->new(verify_SSL=>0)"));
+    }
+}
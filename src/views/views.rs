@@ -1,9 +1,36 @@
+use ammonia::clean_text;
+use crate::views::oneliners::{download_commands, upload_commands, OneLiner, Target};
+
 static INDEX: &str = include_str!("../static/index.html");
 
-pub fn index_view(files: Vec<String>) -> String {
+pub fn index_view(files: Vec<String>, target: Option<&Target>) -> String {
+    let upload = target
+        .map(|target| oneliners_view(&upload_commands(target, "FILE")))
+        .unwrap_or_default();
+
+    INDEX
+        .replace("<!-- File list will be dynamically inserted here -->", &file_list_view(files, target))
+        .replace("<!-- Upload one-liners will be dynamically inserted here -->", &upload)
+}
+
+/// The `<li>` entries of the download list, also pushed alone when the directory changes.
+pub fn file_list_view(files: Vec<String>, target: Option<&Target>) -> String {
     let mut file_list = String::new();
     for file in files {
-        file_list.push_str(&format!("<li><a href=\"/{file}\">{file}</a></li>\n"));
+        let href = target.map(|target| target.file_url(&file)).unwrap_or_else(|| format!("/{}", file));
+        file_list.push_str(&format!("<li><a href=\"{}\">{}</a>", clean_text(&href), clean_text(&file)));
+        if let Some(target) = target {
+            file_list.push_str(&oneliners_view(&download_commands(target, &file)));
+        }
+        file_list.push_str("</li>\n");
+    }
+    file_list
+}
+
+fn oneliners_view(oneliners: &[OneLiner]) -> String {
+    let mut commands = String::new();
+    for oneliner in oneliners {
+        commands.push_str(&format!("# {}\n{}\n", oneliner.client, clean_text(&oneliner.command)));
     }
-    INDEX.replace("<!-- File list will be dynamically inserted here -->", &file_list)
+    format!("<details><summary>one-liners</summary><pre>{}</pre></details>", commands)
 }